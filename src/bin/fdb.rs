@@ -0,0 +1,149 @@
+use std::io::{BufRead, Write};
+use ferrum::bytecode::opcode::NumeralType;
+use ferrum::vm::debug::{load_program, Debugger, StopReason};
+use ferrum::vm::VM;
+
+const HELP: &str = "\
+commands:
+  break <line>        set a breakpoint on a source line (b)
+  delete <line>       remove the breakpoint from a source line (d)
+  breakpoints         list all breakpoints
+  step                run to the next line, entering calls (s)
+  next                run to the next line of the current function (n)
+  finish              run until the current function returns (f)
+  stepi               execute a single instruction (si)
+  continue            run until the next breakpoint (c)
+  backtrace           print the active calls (bt)
+  locals [frame]      print the locals of a frame, 0 being the innermost (l)
+  print <name> [type] print a local of the innermost frame, e.g. `print x i32` (p)
+  quit                exit the debugger (q)";
+
+fn print_bytes(bytes: &[u8]) -> String {
+    bytes.iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn report(dbg: &Debugger, stop: StopReason) {
+    match stop {
+        StopReason::Finished(exit) => {
+            println!("Process finished with exit code: {}", exit.code());
+            return;
+        },
+        StopReason::Breakpoint(line) => println!("breakpoint at line {}", line),
+        StopReason::Step => (),
+    }
+    if let Some(frame) = dbg.backtrace().first() {
+        println!("{} at {}:{}", frame.function(), frame.pos().0, frame.pos().1);
+    }
+    println!("{:?}", dbg.vm());
+}
+
+/// Executes a single command. Returns `false` if the debugger should exit.
+fn command(dbg: &mut Debugger, line: &str) -> bool {
+    let args: Vec<&str> = line.split_whitespace().collect();
+    let Some((cmd, args)) = args.split_first() else {
+        return true;
+    };
+    let stop = match (*cmd, args) {
+        ("break" | "b", [line]) => {
+            match line.parse() {
+                Ok(line) if dbg.set_breakpoint(line) => println!("breakpoint set on line {}", line),
+                Ok(line) => println!("no code on line {}", line),
+                Err(_) => println!("'{}' is not a line number", line),
+            }
+            return true;
+        },
+        ("delete" | "d", [line]) => {
+            match line.parse() {
+                Ok(line) if dbg.clear_breakpoint(line) => println!("breakpoint on line {} removed", line),
+                _ => println!("there is no breakpoint on line {}", line),
+            }
+            return true;
+        },
+        ("breakpoints", []) => {
+            for line in dbg.breakpoints() {
+                println!("line {}", line);
+            }
+            return true;
+        },
+        ("backtrace" | "bt", []) => {
+            for (i, frame) in dbg.backtrace().iter().enumerate() {
+                println!("#{} {} at {}:{}", i, frame.function(), frame.pos().0, frame.pos().1);
+            }
+            return true;
+        },
+        ("locals" | "l", _) if args.len() <= 1 => {
+            let frame = args.first().and_then(|f| f.parse().ok()).unwrap_or(0);
+            for local in dbg.locals(frame) {
+                println!("{} = [{}]", local.name(), print_bytes(local.value()));
+            }
+            return true;
+        },
+        ("print" | "p", [name, ty @ ..]) if ty.len() <= 1 => {
+            let Some(local) = dbg.local(0, name) else {
+                println!("there is no local '{}' in scope", name);
+                return true;
+            };
+            match ty.first().map(|t| t.parse::<NumeralType>()) {
+                None => println!("{} = [{}]", name, print_bytes(local.value())),
+                Some(Ok(ty)) => match local.as_numeral(ty) {
+                    Some(v) => println!("{}: {:?} = {}", name, ty, v),
+                    None => println!("'{}' has {} bytes, which does not match {:?}", name, local.value().len(), ty),
+                },
+                Some(Err(_)) => println!("'{}' is not a numeral type", ty[0]),
+            }
+            return true;
+        },
+        ("step" | "s", []) => dbg.step_into(),
+        ("next" | "n", []) => dbg.step_over(),
+        ("finish" | "f", []) => dbg.step_out(),
+        ("stepi" | "si", []) => dbg.step_instruction(),
+        ("continue" | "c", []) => dbg.resume(),
+        ("quit" | "q", []) => return false,
+        ("help" | "h", []) => {
+            println!("{}", HELP);
+            return true;
+        },
+        _ => {
+            println!("unknown command '{}', type 'help' for a list of commands", line.trim());
+            return true;
+        },
+    };
+    match stop {
+        Ok(stop) => report(dbg, stop),
+        Err(e) => println!("ERROR: {}", e),
+    }
+    true
+}
+
+/// Usage: `fdb <program.fbc | program.fasm>` debugs a compiled program or an assembly source.
+fn main() -> Result<(), ()> {
+    let Some(path) = std::env::args().nth(1) else {
+        println!("usage: fdb <program.fbc | program.fasm>");
+        return Err(());
+    };
+    let program = load_program(&path).map_err(|e| {
+        println!("ERROR: {}", e);
+    })?;
+    let vm = VM::new(program).map_err(|e| {
+        println!("ERROR: {}", e);
+    })?;
+    let mut dbg = Debugger::new(vm);
+    report(&dbg, StopReason::Step);
+
+    let stdin = std::io::stdin();
+    loop {
+        print!("(fdb) ");
+        std::io::stdout().flush().map_err(|_| ())?;
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) | Err(_) => return Ok(()),
+            Ok(_) => (),
+        }
+        if !command(&mut dbg, &line) {
+            return Ok(());
+        }
+    }
+}
@@ -0,0 +1,9 @@
+use ferrum::vm::debug::dap::serve;
+
+/// Usage: `ferrum-dap` speaks the Debug Adapter Protocol over stdin and stdout. The program to
+/// debug is specified by the `program` attribute of the launch configuration, either as a `.fbc`
+/// file or as assembly source.
+fn main() -> std::io::Result<()> {
+    let stdin = std::io::stdin();
+    serve(stdin.lock(), std::io::stdout().lock())
+}
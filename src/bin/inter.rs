@@ -1,28 +1,123 @@
 use ferrum::bytecode::chunk::Chunk;
 use ferrum::bytecode::opcode::{NumeralType, OpCode};
+use ferrum::bytecode::program::{Program, MAIN_FUNCTION};
+use ferrum::lang::function::FerrumFunctionPtr;
+use ferrum::vm::coverage::Coverage;
+use ferrum::vm::trace::{FoldedStacks, FunctionProfiler, OpcodeHistogram};
 use ferrum::vm::VM;
 
-fn main() -> Result<(), ()> {
+/// Builds the demo program that runs if no bytecode file is specified.
+fn demo() -> Program {
     let mut chunk = Chunk::new(String::from("main"));
-    chunk.write(OpCode::Const(0, 8), 0, 0);
-    chunk.write_value(1.2 as f64);
+    chunk.write_const(1.2 as f64, 0, 0);
     chunk.write(OpCode::Neg(NumeralType::F64), 0, 6);
-    chunk.write(OpCode::Const(8, 8), 1, 0);
-    chunk.write_value(2.0 as f64);
+    chunk.write_const(2.0 as f64, 1, 0);
     chunk.write(OpCode::Mul(NumeralType::F64), 2, 16);
-    chunk.write(OpCode::Ret, 2, 0);
+    chunk.write(OpCode::Cast(NumeralType::F64, NumeralType::I32), 2, 0);
+    chunk.write(OpCode::Ret(4), 2, 0);
 
     println!("chunk: {:#?}", chunk);
+    let mut program = Program::from(chunk);
+    program.set_return_type(&FerrumFunctionPtr::new(String::from(MAIN_FUNCTION), 0), Some(NumeralType::I32));
+    program
+}
+
+/// Usage: `inter [program.fbc]` runs a compiled program, or the demo program if no file is
+/// specified. `inter --emit program.fbc` writes the demo program to a file instead.
+///
+/// With `--profile`, the program runs without the per-cycle trace and an instruction histogram
+/// and a function profile are printed once it finishes. `--folded stacks.txt` additionally
+/// writes the folded call stacks of the run for flamegraph tools. `--lcov coverage.info` records
+/// which lines and branches of the program were executed and writes them as an LCOV tracefile,
+/// which attributes them to the source file passed with `--source main.fe`.
+fn main() -> Result<(), ()> {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let mut profile = false;
+    if let Some(i) = args.iter().position(|a| a == "--profile") {
+        args.remove(i);
+        profile = true;
+    }
+    let folded = flag_value(&mut args, "--folded")?;
+    profile |= folded.is_some();
+    let lcov = flag_value(&mut args, "--lcov")?;
+    let source = flag_value(&mut args, "--source")?;
+    if lcov.is_some() && source.is_none() {
+        println!("ERROR: --lcov requires the path of the source file, pass it with --source");
+        return Err(());
+    }
+
+    let program = match args.as_slice() {
+        [flag, path] if flag == "--emit" => {
+            let bytes = demo().serialize().map_err(|e| {
+                println!("ERROR: {}", e);
+            })?;
+            return std::fs::write(path, bytes).map_err(|e| {
+                println!("ERROR: {}", e);
+            });
+        },
+        [path] => {
+            let bytes = std::fs::read(path).map_err(|e| {
+                println!("ERROR: {}", e);
+            })?;
+            Program::deserialize(&bytes).map_err(|e| {
+                println!("ERROR: {}", e);
+            })?
+        },
+        _ => demo(),
+    };
+
     println!("\nrunning vm...");
 
-    let mut vm = VM::new(chunk);
-    while vm.is_active {
+    let mut vm = VM::new(program).map_err(|e| {
+        println!("ERROR: {}", e);
+    })?;
+    if profile || lcov.is_some() {
+        let mut tracer = (((OpcodeHistogram::new(), FunctionProfiler::new()), FoldedStacks::new()), Coverage::new());
+        let exit = vm.run_traced(&mut tracer).map_err(|e| {
+            println!("ERROR: {}", e);
+        })?;
+        let (((histogram, profiler), stacks), coverage) = tracer;
+        if profile {
+            println!("\n{}\n{}", histogram, profiler);
+        }
+        if let (Some(path), Some(source)) = (lcov, source) {
+            std::fs::write(&path, coverage.lcov(vm.program(), &source)).map_err(|e| {
+                println!("ERROR: {}", e);
+            })?;
+            println!("coverage written to {}", path);
+        }
+        if let Some(path) = folded {
+            std::fs::write(&path, stacks.to_string()).map_err(|e| {
+                println!("ERROR: {}", e);
+            })?;
+            println!("folded stacks written to {}", path);
+        }
+        println!("Process finished with exit code: {}", exit.code());
+        return Ok(());
+    }
+    while vm.is_active() {
         println!("{:?}", vm);
         vm.cycle().map_err(|e| {
             println!("ERROR: {}", e);
             ()
         })?;
     }
-    println!("Process finished with exit code: {}", vm.exit_code);
+    let code = vm.exit_status()
+        .map(|s| s.code())
+        .unwrap_or(0);
+    println!("Process finished with exit code: {}", code);
     Ok(())
 }
+
+/// Removes `flag` and the value following it from the arguments and returns the value.
+fn flag_value(args: &mut Vec<String>, flag: &str) -> Result<Option<String>, ()> {
+    let Some(i) = args.iter().position(|a| a == flag) else {
+        return Ok(None);
+    };
+    args.remove(i);
+    if i >= args.len() {
+        println!("ERROR: {} expects a file path", flag);
+        return Err(());
+    }
+    Ok(Some(args.remove(i)))
+}
@@ -1,5 +1,5 @@
 use ferrum::lang::expr::*;
-use ferrum::lang::expr::Expr::Identifier;
+use ferrum::lang::expr::ExprKind::Identifier;
 
 fn main() -> Result<(), String> {
     let input = r#"
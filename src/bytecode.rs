@@ -1,3 +1,7 @@
 pub mod opcode;
 pub mod chunk;
 pub mod values;
+pub mod program;
+pub mod format;
+pub mod verify;
+pub mod asm;
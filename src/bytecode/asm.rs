@@ -0,0 +1,560 @@
+//! Textual assembly format for bytecode chunks.
+//!
+//! The assembler accepts the output of the disassembler as well as hand-written code. A source
+//! file consists of one statement per line; everything after a `;` is a comment.
+//!
+//! ```text
+//! .chunk main                 ; name of the chunk
+//! .const one f64 1.0          ; named constant, appended to the constant pool
+//! .const bytes 9a9999999999f33f
+//! .local x 0 8 3 12           ; local `x` at frame offset 0 with 8 bytes, live from 3 until 12
+//!     CONST one               ; pushes a named constant, picks CONST or CONSTL
+//! top:                        ; label
+//!     NEG F64 @ 3:4           ; explicit source position (line:char)
+//!     JMPF top
+//! 0012  0003-004  RET 8       ; disassembler rows are accepted verbatim
+//! ```
+//!
+//! Instructions without a source position are attributed to their line in the assembly source.
+//! Jumps take either a raw offset, as printed by the disassembler, or a label. Constants are
+//! written to the pool in the order of their directives, such that the pool is reproduced
+//! exactly when assembling the output of [`disassemble`]. The live range of a `.local` is given
+//! in code offsets; without an end, the local stays in scope until the end of the chunk.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use crate::bytecode::chunk::{Chunk, CodePos, LocalInfo};
+use crate::bytecode::opcode::{NumeralType, OpCode};
+
+/// Error in an assembly source. `line` is the line of the source, starting at 1.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AsmError {
+    pub line: usize,
+    pub msg: String,
+}
+
+impl Display for AsmError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&format!("line {}: {}", self.line, self.msg))
+    }
+}
+
+impl Error for AsmError {}
+
+
+/// Wraps a chunk such that it is formatted as complete assembly source.
+struct Listing<'a>(&'a Chunk);
+
+impl Display for Listing<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&format!(".chunk {}\n", self.0.name()))?;
+        if !self.0.vals().is_empty() {
+            f.write_str(".const bytes ")?;
+            for b in self.0.vals() {
+                f.write_str(&format!("{:02x}", b))?;
+            }
+            f.write_str("\n")?;
+        }
+        for local in self.0.locals() {
+            f.write_str(&format!(".local {} {} {} {}", local.name(), local.offset(), local.size(), local.start()))?;
+            if let Some(end) = local.end() {
+                f.write_str(&format!(" {}", end))?;
+            }
+            f.write_str("\n")?;
+        }
+        self.0.disassemble(0, usize::MAX, f)
+    }
+}
+
+/// Disassembles the entire chunk, including its name and constant pool, into assembly source
+/// that can be read back with [`assemble`].
+pub fn disassemble(chunk: &Chunk) -> String {
+    Listing(chunk).to_string()
+}
+
+
+/// Jump operand of an instruction that has not been resolved yet.
+enum Target {
+    /// Raw offset and, if present, the absolute target the disassembler printed next to it
+    Offset(u16, Option<usize>),
+    Label(String),
+}
+
+/// A single instruction of the assembly source.
+struct Stmt {
+    src_line: usize,
+    op: OpCode,
+    target: Option<Target>,
+    /// Reference to a named constant, resolved once all constants are known
+    constant: Option<(String, bool)>,
+    /// Offset the source claims the instruction is at
+    offset: Option<usize>,
+    pos: CodePos,
+}
+
+/// Assembles the source into a chunk.
+pub fn assemble(src: &str) -> Result<Chunk, AsmError> {
+    let mut name = String::from("main");
+    let mut consts: HashMap<String, (usize, usize)> = HashMap::new();
+    let mut chunk_vals: Vec<Vec<u8>> = Vec::new();
+    let mut labels: HashMap<String, usize> = HashMap::new();
+    let mut stmts: Vec<Stmt> = Vec::new();
+    let mut locals: Vec<LocalInfo> = Vec::new();
+    let mut pool_len = 0;
+
+    // collect constants, labels and instructions. Label positions are stored as statement
+    // indices until the size of every instruction is known.
+    for (i, raw) in src.lines().enumerate() {
+        let src_line = i + 1;
+        let err = |msg: String| AsmError { line: src_line, msg };
+        let line = raw.split(';').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(rest) = line.strip_prefix(".chunk") {
+            name = rest.trim().to_string();
+            if name.is_empty() {
+                return Err(err("expected a chunk name".to_string()));
+            }
+            continue;
+        }
+        if let Some(rest) = line.strip_prefix(".const") {
+            let args: Vec<&str> = rest.split_whitespace().collect();
+            let (cname, ty, val) = match args.as_slice() {
+                [ty, val] => (None, *ty, *val),
+                [cname, ty, val] => (Some(*cname), *ty, *val),
+                _ => return Err(err("expected '.const [name] <type> <value>'".to_string())),
+            };
+            let bits = parse_value(ty, val).map_err(err)?;
+            if let Some(cname) = cname {
+                if consts.insert(cname.to_string(), (pool_len, bits.len())).is_some() {
+                    return Err(err(format!("constant '{}' is defined twice", cname)));
+                }
+            }
+            pool_len += bits.len();
+            chunk_vals.push(bits);
+            continue;
+        }
+        if let Some(rest) = line.strip_prefix(".local") {
+            let args: Vec<&str> = rest.split_whitespace().collect();
+            let (lname, nums) = match args.as_slice() {
+                [lname, nums @ ..] if (3..=4).contains(&nums.len()) && is_ident(lname) => (*lname, nums),
+                _ => return Err(err("expected '.local <name> <offset> <size> <start> [end]'".to_string())),
+            };
+            let nums = nums.iter()
+                .map(|n| parse_num::<usize>(n))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(err)?;
+            locals.push(LocalInfo::new(lname.to_string(), nums[0], nums[1], nums[2], nums.get(3).copied()));
+            continue;
+        }
+        if line.starts_with('.') {
+            return Err(err(format!("unknown directive '{}'", line)));
+        }
+
+        // split off the explicit source position
+        let (line, at) = match line.split_once('@') {
+            Some((l, at)) => (l, Some(parse_at(at.trim()).map_err(err)?)),
+            None => (line, None),
+        };
+        let mut tokens: Vec<&str> = line.split_whitespace().collect();
+
+        // disassembler rows start with the offset and the source position
+        let mut offset = None;
+        let mut pos = None;
+        if tokens.first().is_some_and(|t| t.starts_with(|c: char| c.is_ascii_digit())) {
+            offset = Some(parse_num::<usize>(tokens[0]).map_err(err)?);
+            tokens.remove(0);
+            let Some(p) = tokens.first() else {
+                return Err(err("expected a source position after the offset".to_string()));
+            };
+            pos = Some(parse_row_pos(p, stmts.last().map(|s| s.pos.0)).map_err(err)?);
+            tokens.remove(0);
+        }
+
+        while let Some(label) = tokens.first().and_then(|t| t.strip_suffix(':')) {
+            if !is_ident(label) {
+                return Err(err(format!("'{}' is not a valid label", label)));
+            }
+            if labels.insert(label.to_string(), stmts.len()).is_some() {
+                return Err(err(format!("label '{}' is defined twice", label)));
+            }
+            tokens.remove(0);
+        }
+        let Some((mnemonic, args)) = tokens.split_first() else {
+            if offset.is_some() {
+                return Err(err("expected an instruction".to_string()));
+            }
+            continue;
+        };
+
+        let pos = match (pos, at) {
+            (Some(_), Some(_)) => return Err(err("the source position is given twice".to_string())),
+            (Some(p), None) | (None, Some(p)) => p,
+            (None, None) => (u16::try_from(src_line).unwrap_or(u16::MAX), 0),
+        };
+        let mut stmt = parse_instruction(mnemonic, args).map_err(err)?;
+        stmt.src_line = src_line;
+        stmt.offset = offset;
+        stmt.pos = pos;
+        stmts.push(stmt);
+    }
+
+    // resolve named constants, after which every instruction has its final size
+    for stmt in stmts.iter_mut() {
+        if let Some((cname, long)) = stmt.constant.take() {
+            let Some(&(i, s)) = consts.get(&cname) else {
+                return Err(AsmError { line: stmt.src_line, msg: format!("unknown constant '{}'", cname) });
+            };
+            stmt.op = match (long, u8::try_from(i), u8::try_from(s)) {
+                (false, Ok(i), Ok(s)) => OpCode::Const(i, s),
+                _ => OpCode::ConstLong(i, s),
+            };
+        }
+    }
+
+    let mut offsets = Vec::with_capacity(stmts.len() + 1);
+    let mut offset = 0;
+    for stmt in stmts.iter() {
+        if stmt.offset.is_some_and(|o| o != offset) {
+            return Err(AsmError {
+                line: stmt.src_line,
+                msg: format!("instruction is at offset {}, not {}", offset, stmt.offset.unwrap()),
+            });
+        }
+        offsets.push(offset);
+        offset += stmt.op.size();
+    }
+    offsets.push(offset);
+
+    let mut chunk = Chunk::new(name);
+    for bits in chunk_vals {
+        chunk.write_raw_value(&bits);
+    }
+    for (i, mut stmt) in stmts.into_iter().enumerate() {
+        let err = |msg: String| AsmError { line: stmt.src_line, msg };
+        let end = offsets[i] + stmt.op.size();
+        match stmt.target.take() {
+            Some(Target::Label(label)) => {
+                let Some(&target) = labels.get(&label) else {
+                    return Err(err(format!("unknown label '{}'", label)));
+                };
+                stmt.op = with_jump(stmt.op, end, offsets[target]).map_err(err)?;
+            },
+            Some(Target::Offset(j, Some(target))) => {
+                if jump_target(&stmt.op, end, j) != target {
+                    return Err(err(format!("jump offset {} does not lead to {:04}", j, target)));
+                }
+            },
+            Some(Target::Offset(_, None)) | None => (),
+        }
+        chunk.write(stmt.op, stmt.pos.0, stmt.pos.1);
+    }
+    for local in locals {
+        chunk.add_local(local);
+    }
+    Ok(chunk)
+}
+
+/// Returns the absolute target of a jump instruction ending at `end`.
+fn jump_target(op: &OpCode, end: usize, j: u16) -> usize {
+    match op {
+        OpCode::Loop(_) => end.saturating_sub(j as usize),
+        _ => end + j as usize,
+    }
+}
+
+/// Replaces the offset of the jump instruction ending at `end` such that it leads to `target`.
+fn with_jump(op: OpCode, end: usize, target: usize) -> Result<OpCode, String> {
+    let dist = match op {
+        OpCode::Loop(_) if target <= end => end - target,
+        OpCode::Loop(_) => return Err("LOOP can only jump backwards".to_string()),
+        _ if target >= end => target - end,
+        _ => return Err("jumps can only go forward, use LOOP to jump backwards".to_string()),
+    };
+    let j = u16::try_from(dist).map_err(|_| format!("jump distance {} is out of range", dist))?;
+    Ok(match op {
+        OpCode::Jump(_) => OpCode::Jump(j),
+        OpCode::JumpIfFalse(_) => OpCode::JumpIfFalse(j),
+        OpCode::JumpIfFalseOrPop(_) => OpCode::JumpIfFalseOrPop(j),
+        OpCode::JumpIfTrueOrPop(_) => OpCode::JumpIfTrueOrPop(j),
+        OpCode::Loop(_) => OpCode::Loop(j),
+        op => op,
+    })
+}
+
+/// Parses the mnemonic and operands of an instruction.
+fn parse_instruction(mnemonic: &str, args: &[&str]) -> Result<Stmt, String> {
+    let arity = |n: usize| if args.len() == n {
+        Ok(())
+    } else {
+        Err(format!("{} expects {} operands, found {}", mnemonic, n, args.len()))
+    };
+    let ty = |i: usize| NumeralType::from_str(args[i]);
+
+    let mut stmt = Stmt {
+        src_line: 0,
+        op: OpCode::Not,
+        target: None,
+        constant: None,
+        offset: None,
+        pos: (0, 0),
+    };
+    let typed: Option<fn(NumeralType) -> OpCode> = match mnemonic.to_ascii_uppercase().as_str() {
+        "NEG" => Some(OpCode::Neg),
+        "ADD" => Some(OpCode::Add),
+        "SUB" => Some(OpCode::Sub),
+        "MUL" => Some(OpCode::Mul),
+        "DIV" => Some(OpCode::Div),
+        "REM" => Some(OpCode::Rem),
+        "ADDW" => Some(OpCode::WrappingAdd),
+        "SUBW" => Some(OpCode::WrappingSub),
+        "MULW" => Some(OpCode::WrappingMul),
+        "DIVW" => Some(OpCode::WrappingDiv),
+        "ADDS" => Some(OpCode::SaturatingAdd),
+        "SUBS" => Some(OpCode::SaturatingSub),
+        "MULS" => Some(OpCode::SaturatingMul),
+        "DIVS" => Some(OpCode::SaturatingDiv),
+        "EQ" => Some(OpCode::Eq),
+        "NE" => Some(OpCode::Ne),
+        "LT" => Some(OpCode::Lt),
+        "LE" => Some(OpCode::Le),
+        "GT" => Some(OpCode::Gt),
+        "GE" => Some(OpCode::Ge),
+        "AND" => Some(OpCode::BitAnd),
+        "OR" => Some(OpCode::BitOr),
+        "XOR" => Some(OpCode::BitXor),
+        "INV" => Some(OpCode::BitNot),
+        "SHL" => Some(OpCode::Shl),
+        "SHR" => Some(OpCode::Shr),
+        _ => None,
+    };
+    if let Some(typed) = typed {
+        arity(1)?;
+        stmt.op = typed(ty(0)?);
+        return Ok(stmt);
+    }
+
+    let jump: Option<fn(u16) -> OpCode> = match mnemonic.to_ascii_uppercase().as_str() {
+        "JMP" => Some(OpCode::Jump),
+        "JMPF" => Some(OpCode::JumpIfFalse),
+        "JFOP" => Some(OpCode::JumpIfFalseOrPop),
+        "JTOP" => Some(OpCode::JumpIfTrueOrPop),
+        "LOOP" => Some(OpCode::Loop),
+        _ => None,
+    };
+    if let Some(jump) = jump {
+        stmt.op = jump(0);
+        stmt.target = Some(match args {
+            [label] if is_ident(label) => Target::Label(label.to_string()),
+            [j] => Target::Offset(parse_num(j)?, None),
+            [j, "->", t] => Target::Offset(parse_num(j)?, Some(parse_num(t)?)),
+            _ => return Err(format!("{} expects an offset or a label", mnemonic)),
+        });
+        if let Some(Target::Offset(j, _)) = stmt.target {
+            stmt.op = jump(j);
+        }
+        return Ok(stmt);
+    }
+
+    stmt.op = match mnemonic.to_ascii_uppercase().as_str() {
+        "RET" => {
+            arity(1)?;
+            OpCode::Ret(parse_num(args[0])?)
+        },
+        "CALL" => {
+            arity(2)?;
+            OpCode::Call(parse_num(args[0])?, parse_num(args[1])?)
+        },
+        "CALLN" => {
+            arity(1)?;
+            OpCode::CallNative(parse_num(args[0])?)
+        },
+        m @ ("CONST" | "CONSTL") if args.len() == 1 => {
+            if !is_ident(args[0]) {
+                return Err(format!("'{}' is not a valid constant name", args[0]));
+            }
+            stmt.constant = Some((args[0].to_string(), m == "CONSTL"));
+            OpCode::Not
+        },
+        "CONST" => {
+            arity(2)?;
+            OpCode::Const(parse_num(args[0])?, parse_num(args[1])?)
+        },
+        "CONSTL" => {
+            arity(2)?;
+            OpCode::ConstLong(parse_num(args[0])?, parse_num(args[1])?)
+        },
+        "NOT" => {
+            arity(0)?;
+            OpCode::Not
+        },
+        "CAST" => {
+            arity(2)?;
+            OpCode::Cast(ty(0)?, ty(1)?)
+        },
+        "LOAD" => {
+            arity(2)?;
+            OpCode::LoadLocal(parse_num(args[0])?, parse_num(args[1])?)
+        },
+        "STORE" => {
+            arity(2)?;
+            OpCode::StoreLocal(parse_num(args[0])?, parse_num(args[1])?)
+        },
+        "POP" => {
+            arity(1)?;
+            OpCode::Pop(parse_num(args[0])?)
+        },
+        "DUP" => {
+            arity(1)?;
+            OpCode::Dup(parse_num(args[0])?)
+        },
+        _ => return Err(format!("unknown instruction '{}'", mnemonic)),
+    };
+    Ok(stmt)
+}
+
+/// Parses the value of a `.const` directive into its little endian representation.
+fn parse_value(ty: &str, val: &str) -> Result<Vec<u8>, String> {
+    if ty.eq_ignore_ascii_case("bytes") {
+        if !val.len().is_multiple_of(2) {
+            return Err(format!("'{}' is not a sequence of hex encoded bytes", val));
+        }
+        return (0..val.len())
+            .step_by(2)
+            .map(|i| val.get(i..(i + 2))
+                .and_then(|b| u8::from_str_radix(b, 16).ok())
+                .ok_or_else(|| format!("'{}' is not a sequence of hex encoded bytes", val)))
+            .collect();
+    }
+    Ok(match NumeralType::from_str(ty)? {
+        NumeralType::I8 => parse_num::<i8>(val)?.to_le_bytes().to_vec(),
+        NumeralType::I16 => parse_num::<i16>(val)?.to_le_bytes().to_vec(),
+        NumeralType::I32 => parse_num::<i32>(val)?.to_le_bytes().to_vec(),
+        NumeralType::I64 => parse_num::<i64>(val)?.to_le_bytes().to_vec(),
+        NumeralType::I128 => parse_num::<i128>(val)?.to_le_bytes().to_vec(),
+        NumeralType::U8 => parse_num::<u8>(val)?.to_le_bytes().to_vec(),
+        NumeralType::U16 => parse_num::<u16>(val)?.to_le_bytes().to_vec(),
+        NumeralType::U32 => parse_num::<u32>(val)?.to_le_bytes().to_vec(),
+        NumeralType::U64 => parse_num::<u64>(val)?.to_le_bytes().to_vec(),
+        NumeralType::U128 => parse_num::<u128>(val)?.to_le_bytes().to_vec(),
+        NumeralType::F32 => parse_num::<f32>(val)?.to_le_bytes().to_vec(),
+        NumeralType::F64 => parse_num::<f64>(val)?.to_le_bytes().to_vec(),
+    })
+}
+
+/// Parses a `line:char` source position.
+fn parse_at(s: &str) -> Result<CodePos, String> {
+    let (line, char) = s.split_once(':')
+        .ok_or_else(|| format!("expected a source position 'line:char', found '{}'", s))?;
+    Ok((parse_num(line.trim())?, parse_num(char.trim())?))
+}
+
+/// Parses the source position column of a disassembler row. A line of `|` refers to the line of
+/// the previous instruction.
+fn parse_row_pos(s: &str, prev: Option<u16>) -> Result<CodePos, String> {
+    let (line, char) = s.split_once('-')
+        .ok_or_else(|| format!("expected a source position 'line-char', found '{}'", s))?;
+    let line = match line {
+        "|" => prev.ok_or_else(|| "the first instruction has to specify its line".to_string())?,
+        line => parse_num(line)?,
+    };
+    Ok((line, parse_num(char)?))
+}
+
+fn parse_num<T: FromStr>(s: &str) -> Result<T, String> {
+    s.parse().map_err(|_| format!("'{}' is not a valid {}", s, std::any::type_name::<T>()))
+}
+
+fn is_ident(s: &str) -> bool {
+    s.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::bytecode::asm::{assemble, disassemble};
+    use crate::bytecode::chunk::Chunk;
+    use crate::bytecode::opcode::{NumeralType, OpCode};
+    use crate::bytecode::program::Program;
+    use crate::vm::VM;
+
+    #[test]
+    fn round_trip() {
+        let mut chunk = Chunk::new(String::from("test"));
+        chunk.write_const(1.2f64, 1, 0);
+        let x = chunk.begin_local(String::from("x"), 0, 8);
+        chunk.write(OpCode::Neg(NumeralType::F64), 1, 6);
+        chunk.write_const(2.0f64, 2, 0);
+        chunk.write(OpCode::Lt(NumeralType::F64), 2, 4);
+        let jump = chunk.write(OpCode::JumpIfFalse(0), 2, 4);
+        chunk.write(OpCode::Cast(NumeralType::F64, NumeralType::I32), 3, 0);
+        chunk.begin_local(String::from("y"), 8, 4);
+        chunk.write(OpCode::Dup(4), 3, 2);
+        chunk.write(OpCode::StoreLocal(0, 4), 3, 2);
+        chunk.write_loop(0, 3, 8).unwrap();
+        chunk.patch_jump(&jump).unwrap();
+        chunk.end_local(x);
+        for i in 0..300u16 {
+            chunk.write_value(i);
+        }
+        chunk.write_const(1u16 << 15, 4, 1);
+        chunk.write(OpCode::Call(1, 0), 4, 1);
+        chunk.write(OpCode::Ret(4), 5, 0);
+
+        let src = disassemble(&chunk);
+        let asm = assemble(&src).unwrap();
+        assert_eq!(asm.name(), chunk.name());
+        assert_eq!(asm.code(), chunk.code());
+        assert_eq!(asm.vals(), chunk.vals());
+        assert_eq!(asm.lines(), chunk.lines());
+        assert_eq!(asm.locals(), chunk.locals());
+        assert_eq!(disassemble(&asm), src);
+    }
+
+    #[test]
+    fn factorial() {
+        let src = "
+            .chunk main
+            .const five i32 5
+            .const one i32 1
+                CONST five      ; n
+                CONST one       ; acc
+            top:
+                LOAD 0 4
+                CONST one
+                GT I32
+                JMPF end
+                LOAD 4 4
+                LOAD 0 4
+                MUL I32
+                STORE 4 4
+                LOAD 0 4
+                CONST one
+                SUB I32
+                STORE 0 4
+                LOOP top
+            end:
+                LOAD 4 4 @ 42:7
+                RET 4
+        ";
+        let chunk = assemble(src).unwrap();
+        assert_eq!(chunk.lines().last(), Some(&(23, 0)));
+        assert!(chunk.lines().contains(&(42, 7)));
+
+        let mut vm = VM::new(Program::from(chunk)).unwrap();
+        assert_eq!(vm.run().unwrap().get::<4, i32>(), Some(120));
+    }
+
+    #[test]
+    fn reject_errors() {
+        assert_eq!(assemble("JMP nowhere\nRET 0").unwrap_err().line, 1);
+        assert_eq!(assemble("RET 0\n\nADD X64").unwrap_err().line, 3);
+        assert_eq!(assemble("0001  0001-000  RET 0").unwrap_err().line, 1);
+        assert_eq!(assemble("0000  0001-000  JMP 0 -> 0004\n0003     |-000  RET 0").unwrap_err().line, 1);
+    }
+}
@@ -1,14 +1,15 @@
+use std::collections::HashMap;
 use std::fmt::{Debug, Formatter};
 use std::io::Write;
 use std::ops::Index;
-use crate::bytecode::opcode::OpCode;
+use crate::bytecode::opcode::{OpCode, OpCodeError};
 
 pub trait Value<const N: usize>: Sized {
     fn to_bits(self) -> [u8; N];
     fn from_bits(bits: [u8; N]) -> Self;
 }
 
-type CodePos = (u16, u16);
+pub type CodePos = (u16, u16);
 
 
 pub struct CodeRef {
@@ -16,14 +17,71 @@ pub struct CodeRef {
     size: usize,
 }
 
+impl CodeRef {
+    /// Returns the position of the instruction within the code of its chunk.
+    pub fn pos(&self) -> usize {
+        self.pos
+    }
+}
+
+/// Debug information about a local variable of a chunk. The variable occupies `size` bytes at
+/// `offset` relative to the frame pointer while the instruction pointer is within its live range.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LocalInfo {
+    name: String,
+    offset: usize,
+    size: usize,
+    /// code position of the first instruction at which the variable is live
+    start: usize,
+    /// code position at which the variable goes out of scope, if it lives until the chunk ends
+    end: Option<usize>,
+}
+
+impl LocalInfo {
+    pub fn new(name: String, offset: usize, size: usize, start: usize, end: Option<usize>) -> Self {
+        LocalInfo { name, offset, size, start, end }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the offset of the variable relative to the frame pointer.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    pub fn end(&self) -> Option<usize> {
+        self.end
+    }
+
+    /// Returns `true` if the variable is in scope at code position `ip`.
+    pub fn is_live(&self, ip: usize) -> bool {
+        ip >= self.start && self.end.is_none_or(|end| ip < end)
+    }
+}
+
 pub struct Chunk {
     name: String,
     /// Stores the actual byte code instruction set
     code: Vec<u8>,
     /// Stores constant values
     vals: Vec<u8>,
+    /// Maps the bits of every constant in `vals` to its offset, so that repeated constants share
+    /// a single entry
+    consts: HashMap<Vec<u8>, usize>,
     /// Lines of code for the corresponding instruction
     lines: Vec<CodePos>,
+    /// Debug information about the local variables of the chunk
+    locals: Vec<LocalInfo>,
 }
 
 impl Chunk {
@@ -32,7 +90,22 @@ impl Chunk {
             name,
             code: Vec::with_capacity(512),
             vals: Vec::with_capacity(512),
+            consts: HashMap::new(),
             lines: Vec::with_capacity(512),
+            locals: Vec::new(),
+        }
+    }
+
+    /// Creates a chunk from already encoded instructions and their source positions. `lines`
+    /// must contain one entry per byte of `code`.
+    pub(crate) fn from_raw(name: String, code: Vec<u8>, lines: Vec<CodePos>) -> Self {
+        Chunk {
+            name,
+            code,
+            vals: Vec::new(),
+            consts: HashMap::new(),
+            lines,
+            locals: Vec::new(),
         }
     }
 
@@ -48,21 +121,90 @@ impl Chunk {
         CodeRef { pos: i, size, }
     }
 
+    /// Points the forward jump instruction at `jump` to the current end of the chunk. Jump
+    /// instructions are usually written with a placeholder offset and patched once the code they
+    /// skip has been written.
+    pub fn patch_jump(&mut self, jump: &CodeRef) -> Result<(), OpCodeError> {
+        let dist = self.code.len() - (jump.pos + jump.size);
+        let offset = u16::try_from(dist)
+            .map_err(|_| OpCodeError::JumpOutOfRange(dist))?;
+        self.code[(jump.pos + 1)..(jump.pos + 3)].copy_from_slice(&offset.to_le_bytes());
+        Ok(())
+    }
+
+    /// Writes a `Loop` instruction that jumps back to the instruction at code position `start`.
+    pub fn write_loop(&mut self, start: usize, line: u16, char: u16) -> Result<CodeRef, OpCodeError> {
+        let dist = self.code.len() + OpCode::Loop(0).size() - start;
+        let offset = u16::try_from(dist)
+            .map_err(|_| OpCodeError::JumpOutOfRange(dist))?;
+        Ok(self.write(OpCode::Loop(offset), line, char))
+    }
+
     /// Clears all values in the chunk
     pub fn clear(&mut self) {
         self.code.clear();
         self.vals.clear();
+        self.consts.clear();
+        self.lines.clear();
+        self.locals.clear();
+    }
+
+    /// Records that the local variable `name` comes into scope at the current end of the chunk
+    /// and returns the index of its debug information.
+    pub fn begin_local(&mut self, name: String, offset: usize, size: usize) -> usize {
+        self.add_local(LocalInfo::new(name, offset, size, self.code.len(), None))
+    }
+
+    /// Records that the local variable with debug information index `i` goes out of scope at the
+    /// current end of the chunk.
+    pub fn end_local(&mut self, i: usize) {
+        let end = self.code.len();
+        if let Some(local) = self.locals.get_mut(i) {
+            local.end = Some(end);
+        }
+    }
+
+    /// Adds debug information about a local variable to the chunk and returns its index.
+    pub fn add_local(&mut self, local: LocalInfo) -> usize {
+        self.locals.push(local);
+        self.locals.len() - 1
     }
 
     /// Writes a single data-value entry to the vector of constants for this code chunk. Returns
-    /// the index of the written value.
+    /// the index of the written value. If the exact same value has already been written to the
+    /// chunk, the index of the existing entry is returned instead.
     pub fn write_value<const N: usize, Val: Value<N>>(&mut self, val: Val) -> usize {
-        let i = self.vals.len();
         let bits = val.to_bits();
+        if let Some(i) = self.consts.get(&bits[..]) {
+            return *i;
+        }
+
+        let i = self.vals.len();
         self.vals.write_all(&bits).expect("Failed to write all bits to value vector");
+        self.consts.insert(bits.to_vec(), i);
+        i
+    }
+
+    /// Appends raw bytes to the constants of the chunk without deduplicating them and returns
+    /// their index.
+    pub(crate) fn write_raw_value(&mut self, bits: &[u8]) -> usize {
+        let i = self.vals.len();
+        self.vals.extend_from_slice(bits);
+        self.consts.entry(bits.to_vec()).or_insert(i);
         i
     }
 
+    /// Writes a constant value to the chunk and an instruction that pushes it onto the stack.
+    /// `Const` is used if the constant is addressable with a single byte and `ConstLong`
+    /// otherwise.
+    pub fn write_const<const N: usize, Val: Value<N>>(&mut self, val: Val, line: u16, char: u16) -> CodeRef {
+        let i = self.write_value(val);
+        match (u8::try_from(i), u8::try_from(N)) {
+            (Ok(i), Ok(s)) => self.write(OpCode::Const(i, s), line, char),
+            _ => self.write(OpCode::ConstLong(i, N), line, char),
+        }
+    }
+
     /// Disassembles the code chunk into semi-human readable instruction sets.
     pub fn disassemble(&self, mut i: usize, count: usize, f: &mut Formatter<'_>) -> std::fmt::Result {
         let mut c = 0;
@@ -76,6 +218,16 @@ impl Chunk {
         Ok(())
     }
 
+    /// Removes all constant values from the chunk and returns them.
+    pub fn take_vals(&mut self) -> Vec<u8> {
+        self.consts.clear();
+        std::mem::take(&mut self.vals)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
     pub fn code(&self) -> &[u8] {
         &self.code
     }
@@ -83,6 +235,22 @@ impl Chunk {
     pub fn vals(&self) -> &[u8] {
         &self.vals
     }
+
+    pub fn lines(&self) -> &[CodePos] {
+        &self.lines
+    }
+
+    pub fn locals(&self) -> &[LocalInfo] {
+        &self.locals
+    }
+
+    /// Returns the local variable `name` that is in scope at code position `ip`. If several
+    /// variables of that name are in scope, the one declared last shadows the others.
+    pub fn find_local(&self, name: &str, ip: usize) -> Option<&LocalInfo> {
+        self.locals.iter()
+            .rev()
+            .find(|l| l.name == name && l.is_live(ip))
+    }
 }
 
 impl Debug for Chunk {
@@ -90,3 +258,66 @@ impl Debug for Chunk {
         self.disassemble(0, 10, f)
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use crate::bytecode::chunk::Chunk;
+    use crate::bytecode::opcode::OpCode;
+    use crate::bytecode::program::Program;
+    use crate::vm::VM;
+
+    #[test]
+    fn leb128_operands() {
+        let mut chunk = Chunk::new(String::from("leb128"));
+        let ops = [
+            (OpCode::Pop(127), 2),
+            (OpCode::Pop(128), 3),
+            (OpCode::Dup(16383), 3),
+            (OpCode::Dup(16384), 4),
+            (OpCode::LoadLocal(128, 16384), 6),
+            (OpCode::ConstLong(16383, 127), 4),
+        ];
+        for (op, size) in ops {
+            assert_eq!(op.size(), size);
+            chunk.write(op, 1, 0);
+        }
+        assert_eq!(chunk.code().len(), ops.iter().map(|(_, s)| s).sum::<usize>());
+
+        // decoding and encoding the instructions again has to reproduce the code
+        let mut code = Vec::new();
+        let mut i = 0;
+        while i < chunk.code().len() {
+            let op = OpCode::try_from((i, &chunk)).unwrap();
+            i += op.size();
+            op.write(|b| code.push(b));
+        }
+        assert_eq!(code, chunk.code());
+        assert!(matches!(OpCode::try_from((5, &chunk)), Ok(OpCode::Dup(16383))));
+    }
+
+    #[test]
+    fn wide_and_shared_consts() {
+        let mut chunk = Chunk::new(String::from("main"));
+        for i in 0..70i32 {
+            chunk.write_value(i);
+        }
+        assert_eq!(chunk.vals().len(), 280);
+
+        // constants past byte 255 are reachable through ConstLong
+        chunk.write_const(12_345i64, 1, 0);
+        assert!(matches!(OpCode::try_from((0, &chunk)), Ok(OpCode::ConstLong(280, 8))));
+
+        // repeated literals share their constant
+        let pos = chunk.write_const(7i32, 1, 0).pos();
+        assert!(matches!(OpCode::try_from((pos, &chunk)), Ok(OpCode::Const(28, 4))));
+        let len = chunk.vals().len();
+        chunk.write_const(12_345i64, 1, 0);
+        assert_eq!(chunk.vals().len(), len);
+
+        chunk.write(OpCode::Pop(12), 1, 0);
+        chunk.write(OpCode::Ret(8), 1, 0);
+        let mut vm = VM::new(Program::from(chunk)).unwrap();
+        assert_eq!(vm.run().unwrap().get::<8, i64>(), Some(12_345));
+    }
+}
@@ -0,0 +1,469 @@
+//! Binary on-disk format for compiled programs (`.fbc` files).
+//!
+//! All integers are stored in little endian byte order. A file is laid out as follows:
+//!
+//! ```text
+//! header         magic `FBC\0`, format version (u16), endianness (u8), reserved (u8)
+//! string table   count (u32), then per string: length (u32) and UTF-8 bytes
+//! constant pool  length (u32) and the raw bytes of the shared constant pool
+//! function table count (u32), then per function:
+//!                  chunk name (u32 string index), function id (u32 string index),
+//!                  generic fingerprint (u64), constant base (u32), constant length (u32),
+//!                  return type (u8 numeral type id, or `u8::MAX` if undeclared),
+//!                  code length (u32) and code bytes,
+//!                  line table with one (line: u16, char: u16) entry per code byte,
+//!                  local count (u32), then per local: name (u32 string index), frame offset
+//!                  (u32), size (u32), start of the live range (u32) and end of the live range
+//!                  (u32, or `u32::MAX` if the local lives until the end of the chunk)
+//! native imports count (u32), then per import:
+//!                  path (u32 string index), parameter count (u32) and parameter type ids (u8),
+//!                  return type (u8 numeral type id, or `u8::MAX` if the native returns nothing)
+//! entry point    chunk index (u32), or `u32::MAX` if the program does not set one explicitly
+//! ```
+//!
+//! Functions are stored in the order of their chunk indices, such that `Call` operands stay
+//! valid.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use crate::bytecode::chunk::{Chunk, CodePos, LocalInfo};
+use crate::bytecode::opcode::NumeralType;
+use crate::bytecode::program::{NativeImport, Program};
+use crate::lang::function::FerrumFunctionPtr;
+use crate::vm::native::NativeSignature;
+
+pub const MAGIC: [u8; 4] = *b"FBC\0";
+pub const FORMAT_VERSION: u16 = 5;
+pub const LITTLE_ENDIAN: u8 = 0;
+
+const NO_ENTRY: u32 = u32::MAX;
+/// Marks an undeclared return type of a function or a native that returns nothing
+const NO_RETURN_TYPE: u8 = u8::MAX;
+/// End of the live range of locals that stay in scope until the end of their chunk
+const OPEN_RANGE: u32 = u32::MAX;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum FormatError {
+    /// The input ended in the middle of the specified section
+    UnexpectedEoF(&'static str),
+    BadMagic([u8; 4]),
+    UnsupportedVersion(u16),
+    UnsupportedEndianness(u8),
+    InvalidUtf8(usize),
+    StringIndexOutOfRange(u32),
+    /// The constant base of a function lies outside the constant pool
+    ConstBaseOutOfRange(usize),
+    /// The constants of a function reach past the end of the constant pool
+    ConstRangeOutOfRange(usize, usize),
+    /// A return or parameter type is not a valid numeral type id
+    IllegalNumeralType(u8),
+    /// The function table contains the same function twice
+    DuplicateFunction(FerrumFunctionPtr),
+    EntryOutOfRange(u32),
+    /// The program does not fit into the limits of the format
+    SectionTooLarge(&'static str),
+    /// There are bytes left after the end of the program
+    TrailingBytes(usize),
+}
+
+impl Display for FormatError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnexpectedEoF(s) => f.write_str(&format!("Unexpected end of input in {}", s)),
+            Self::BadMagic(m) => f.write_str(&format!("Not a ferrum bytecode file (magic {:02x?})", m)),
+            Self::UnsupportedVersion(v) => f.write_str(&format!("Unsupported format version {}", v)),
+            Self::UnsupportedEndianness(e) => f.write_str(&format!("Unsupported endianness {}", e)),
+            Self::InvalidUtf8(i) => f.write_str(&format!("String {} is not valid UTF-8", i)),
+            Self::StringIndexOutOfRange(i) => f.write_str(&format!("There is no string with index {}", i)),
+            Self::ConstBaseOutOfRange(b) => f.write_str(&format!("Constant base {} exceeds the constant pool", b)),
+            Self::ConstRangeOutOfRange(b, l) => f.write_str(&format!("Constants {}..{} exceed the constant pool", b, b + l)),
+            Self::IllegalNumeralType(t) => f.write_str(&format!("Illegal numeral type {}", t)),
+            Self::DuplicateFunction(p) => f.write_str(&format!("Function {:?} is defined twice", p)),
+            Self::EntryOutOfRange(e) => f.write_str(&format!("Entry point {} is not a function of the program", e)),
+            Self::SectionTooLarge(s) => f.write_str(&format!("Section {} is too large", s)),
+            Self::TrailingBytes(n) => f.write_str(&format!("{} trailing bytes after the end of the program", n)),
+        }
+    }
+}
+
+impl Error for FormatError {}
+
+
+/// Collects strings for the string table and hands out their indices.
+struct StringTable<'a> {
+    strings: Vec<&'a str>,
+    indices: HashMap<&'a str, u32>,
+}
+
+impl<'a> StringTable<'a> {
+    fn new() -> Self {
+        StringTable {
+            strings: Vec::new(),
+            indices: HashMap::new(),
+        }
+    }
+
+    fn insert(&mut self, s: &'a str) -> u32 {
+        match self.indices.get(s) {
+            Some(i) => *i,
+            None => {
+                let i = self.strings.len() as u32;
+                self.strings.push(s);
+                self.indices.insert(s, i);
+                i
+            }
+        }
+    }
+}
+
+/// Decodes a numeral type id.
+fn numeral_type(t: u8) -> Result<NumeralType, FormatError> {
+    NumeralType::try_from(t).map_err(|_| FormatError::IllegalNumeralType(t))
+}
+
+/// Converts a length into the `u32` used by the format.
+fn len_u32(len: usize, section: &'static str) -> Result<u32, FormatError> {
+    u32::try_from(len).map_err(|_| FormatError::SectionTooLarge(section))
+}
+
+/// Reads primitive values from the input and reports the section that was being read if the
+/// input ends prematurely.
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize, section: &'static str) -> Result<&'a [u8], FormatError> {
+        let end = self.pos.checked_add(n)
+            .filter(|end| *end <= self.data.len())
+            .ok_or(FormatError::UnexpectedEoF(section))?;
+        let out = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(out)
+    }
+
+    fn u8(&mut self, section: &'static str) -> Result<u8, FormatError> {
+        Ok(self.bytes(1, section)?[0])
+    }
+
+    fn u16(&mut self, section: &'static str) -> Result<u16, FormatError> {
+        let b = self.bytes(2, section)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self, section: &'static str) -> Result<u32, FormatError> {
+        let mut bits = [0u8; 4];
+        bits.copy_from_slice(self.bytes(4, section)?);
+        Ok(u32::from_le_bytes(bits))
+    }
+
+    fn u64(&mut self, section: &'static str) -> Result<u64, FormatError> {
+        let mut bits = [0u8; 8];
+        bits.copy_from_slice(self.bytes(8, section)?);
+        Ok(u64::from_le_bytes(bits))
+    }
+
+    /// Reads a `u32` element count. Every element takes up at least `min_size` bytes, which is
+    /// used to reject counts that cannot possibly fit into the remaining input before anything
+    /// is allocated for them.
+    fn count(&mut self, min_size: usize, section: &'static str) -> Result<usize, FormatError> {
+        let n = self.u32(section)? as usize;
+        if n.saturating_mul(min_size) > self.data.len() - self.pos {
+            return Err(FormatError::UnexpectedEoF(section));
+        }
+        Ok(n)
+    }
+}
+
+impl Program {
+    /// Encodes the program in the binary `.fbc` format.
+    pub fn serialize(&self) -> Result<Vec<u8>, FormatError> {
+        // functions in the order of their chunks
+        let mut ptrs: Vec<Option<&FerrumFunctionPtr>> = vec![None; self.chunks().len()];
+        for (ptr, i) in self.functions().iter() {
+            ptrs[*i] = Some(ptr);
+        }
+
+        let mut strings = StringTable::new();
+        let names: Vec<_> = self.chunks().iter()
+            .zip(ptrs.iter())
+            .map(|(chunk, ptr)| {
+                let name = strings.insert(chunk.name());
+                let id = strings.insert(ptr.map(|p| p.function_id()).unwrap_or(chunk.name()));
+                (name, id)
+            })
+            .collect();
+        let locals: Vec<Vec<_>> = self.chunks().iter()
+            .map(|chunk| chunk.locals().iter().map(|l| strings.insert(l.name())).collect())
+            .collect();
+        let paths: Vec<_> = self.natives().iter()
+            .map(|n| strings.insert(n.path()))
+            .collect();
+
+        let mut out = Vec::new();
+        out.extend_from_slice(&MAGIC);
+        out.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        out.push(LITTLE_ENDIAN);
+        out.push(0);
+
+        out.extend_from_slice(&len_u32(strings.strings.len(), "string table")?.to_le_bytes());
+        for s in strings.strings.iter() {
+            out.extend_from_slice(&len_u32(s.len(), "string table")?.to_le_bytes());
+            out.extend_from_slice(s.as_bytes());
+        }
+
+        out.extend_from_slice(&len_u32(self.vals().len(), "constant pool")?.to_le_bytes());
+        out.extend_from_slice(self.vals());
+
+        out.extend_from_slice(&len_u32(self.chunks().len(), "function table")?.to_le_bytes());
+        for (i, chunk) in self.chunks().iter().enumerate() {
+            let (name, id) = names[i];
+            out.extend_from_slice(&name.to_le_bytes());
+            out.extend_from_slice(&id.to_le_bytes());
+            out.extend_from_slice(&ptrs[i].map(|p| p.generic_fingerprint()).unwrap_or(0).to_le_bytes());
+            out.extend_from_slice(&len_u32(self.const_base(i), "function table")?.to_le_bytes());
+            out.extend_from_slice(&len_u32(self.consts(i).len(), "function table")?.to_le_bytes());
+            out.push(self.return_type(i).map(|t| t.into()).unwrap_or(NO_RETURN_TYPE));
+            out.extend_from_slice(&len_u32(chunk.code().len(), "code")?.to_le_bytes());
+            out.extend_from_slice(chunk.code());
+            for (line, char) in chunk.lines().iter() {
+                out.extend_from_slice(&line.to_le_bytes());
+                out.extend_from_slice(&char.to_le_bytes());
+            }
+            out.extend_from_slice(&len_u32(chunk.locals().len(), "local table")?.to_le_bytes());
+            for (local, name) in chunk.locals().iter().zip(locals[i].iter()) {
+                out.extend_from_slice(&name.to_le_bytes());
+                out.extend_from_slice(&len_u32(local.offset(), "local table")?.to_le_bytes());
+                out.extend_from_slice(&len_u32(local.size(), "local table")?.to_le_bytes());
+                out.extend_from_slice(&len_u32(local.start(), "local table")?.to_le_bytes());
+                let end = match local.end() {
+                    Some(end) => len_u32(end, "local table")?,
+                    None => OPEN_RANGE,
+                };
+                out.extend_from_slice(&end.to_le_bytes());
+            }
+        }
+
+        out.extend_from_slice(&len_u32(self.natives().len(), "native imports")?.to_le_bytes());
+        for (native, path) in self.natives().iter().zip(paths) {
+            let params = native.signature().params();
+            out.extend_from_slice(&path.to_le_bytes());
+            out.extend_from_slice(&len_u32(params.len(), "native imports")?.to_le_bytes());
+            out.extend(params.iter().map(|p| Into::<u8>::into(*p)));
+            out.push(native.signature().ret().map(|t| t.into()).unwrap_or(NO_RETURN_TYPE));
+        }
+
+        let entry = match self.explicit_entry() {
+            Some(e) => len_u32(e, "entry point")?,
+            None => NO_ENTRY,
+        };
+        out.extend_from_slice(&entry.to_le_bytes());
+        Ok(out)
+    }
+
+    /// Decodes a program from the binary `.fbc` format. The input is validated strictly, i.e.
+    /// truncated input, unknown versions, dangling indices and trailing bytes are all rejected.
+    pub fn deserialize(data: &[u8]) -> Result<Program, FormatError> {
+        let mut r = Reader { data, pos: 0 };
+
+        let mut magic = [0u8; 4];
+        magic.copy_from_slice(r.bytes(4, "header")?);
+        if magic != MAGIC {
+            return Err(FormatError::BadMagic(magic));
+        }
+        let version = r.u16("header")?;
+        if version != FORMAT_VERSION {
+            return Err(FormatError::UnsupportedVersion(version));
+        }
+        let endianness = r.u8("header")?;
+        if endianness != LITTLE_ENDIAN {
+            return Err(FormatError::UnsupportedEndianness(endianness));
+        }
+        r.u8("header")?;
+
+        let n = r.count(4, "string table")?;
+        let mut strings = Vec::with_capacity(n);
+        for i in 0..n {
+            let len = r.u32("string table")? as usize;
+            let s = std::str::from_utf8(r.bytes(len, "string table")?)
+                .map_err(|_| FormatError::InvalidUtf8(i))?;
+            strings.push(s.to_owned());
+        }
+        let string = |i: u32| strings.get(i as usize)
+            .cloned()
+            .ok_or(FormatError::StringIndexOutOfRange(i));
+
+        let len = r.u32("constant pool")? as usize;
+        let vals = r.bytes(len, "constant pool")?.to_vec();
+
+        let n = r.count(33, "function table")?;
+        let mut chunks = Vec::with_capacity(n);
+        let mut const_ranges = Vec::with_capacity(n);
+        let mut return_types = Vec::with_capacity(n);
+        let mut functions = HashMap::new();
+        for i in 0..n {
+            let name = string(r.u32("function table")?)?;
+            let id = string(r.u32("function table")?)?;
+            let fingerprint = r.u64("function table")?;
+            let base = r.u32("function table")? as usize;
+            if base > vals.len() {
+                return Err(FormatError::ConstBaseOutOfRange(base));
+            }
+            let const_len = r.u32("function table")? as usize;
+            if const_len > vals.len() - base {
+                return Err(FormatError::ConstRangeOutOfRange(base, const_len));
+            }
+            let return_type = match r.u8("function table")? {
+                NO_RETURN_TYPE => None,
+                t => Some(numeral_type(t)?),
+            };
+
+            let len = r.u32("code")? as usize;
+            let code = r.bytes(len, "code")?.to_vec();
+            let table = r.bytes(len.saturating_mul(4), "line table")?;
+            let lines: Vec<CodePos> = table.chunks_exact(4)
+                .map(|b| (u16::from_le_bytes([b[0], b[1]]), u16::from_le_bytes([b[2], b[3]])))
+                .collect();
+            let mut chunk = Chunk::from_raw(name, code, lines);
+
+            let count = r.count(20, "local table")?;
+            for _ in 0..count {
+                let name = string(r.u32("local table")?)?;
+                let offset = r.u32("local table")? as usize;
+                let size = r.u32("local table")? as usize;
+                let start = r.u32("local table")? as usize;
+                let end = match r.u32("local table")? {
+                    OPEN_RANGE => None,
+                    end => Some(end as usize),
+                };
+                chunk.add_local(LocalInfo::new(name, offset, size, start, end));
+            }
+
+            let ptr = FerrumFunctionPtr::new(id, fingerprint);
+            if functions.contains_key(&ptr) {
+                return Err(FormatError::DuplicateFunction(ptr));
+            }
+            functions.insert(ptr, i);
+            chunks.push(chunk);
+            const_ranges.push(base..base + const_len);
+            return_types.push(return_type);
+        }
+
+        let n = r.count(9, "native imports")?;
+        let mut natives = Vec::with_capacity(n);
+        for _ in 0..n {
+            let path = string(r.u32("native imports")?)?;
+            let count = r.u32("native imports")? as usize;
+            let params = r.bytes(count, "native imports")?.iter()
+                .map(|t| numeral_type(*t))
+                .collect::<Result<Vec<_>, _>>()?;
+            let ret = match r.u8("native imports")? {
+                NO_RETURN_TYPE => None,
+                t => Some(numeral_type(t)?),
+            };
+            natives.push(NativeImport::new(path, NativeSignature::new(params, ret)));
+        }
+
+        let entry = match r.u32("entry point")? {
+            NO_ENTRY => None,
+            e if (e as usize) < chunks.len() => Some(e as usize),
+            e => return Err(FormatError::EntryOutOfRange(e)),
+        };
+
+        if r.pos != data.len() {
+            return Err(FormatError::TrailingBytes(data.len() - r.pos));
+        }
+        Ok(Program::from_raw(chunks, functions, vals, const_ranges, entry, return_types, natives))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::bytecode::chunk::Chunk;
+    use crate::bytecode::format::FormatError;
+    use crate::bytecode::opcode::{NumeralType, OpCode};
+    use crate::bytecode::program::Program;
+    use crate::lang::function::FerrumFunctionPtr;
+    use crate::vm::native::NativeSignature;
+
+    fn program() -> Program {
+        let mut main = Chunk::new(String::from("main"));
+        main.write_const(20i32, 1, 4);
+        main.write(OpCode::Call(1, 4), 1, 0);
+        main.write(OpCode::Ret(4), 2, 0);
+
+        let mut double = Chunk::new(String::from("double"));
+        double.begin_local(String::from("x"), 0, 4);
+        double.write(OpCode::LoadLocal(0, 4), 5, 4);
+        double.write_const(2i32, 5, 8);
+        double.write(OpCode::Mul(NumeralType::I32), 5, 6);
+        double.write(OpCode::Ret(4), 6, 0);
+
+        let mut program = Program::new();
+        program.add_function(FerrumFunctionPtr::new(String::from("main"), 0), main);
+        program.add_function(FerrumFunctionPtr::new(String::from("math::double"), 42), double);
+        program.set_return_type(&FerrumFunctionPtr::new(String::from("main"), 0), Some(NumeralType::I32));
+        program.import_native("std::print", NativeSignature::new(vec![NumeralType::I32, NumeralType::F64], None));
+        program.import_native("std::time", NativeSignature::new(Vec::new(), Some(NumeralType::U64)));
+        program
+    }
+
+    #[test]
+    fn round_trip() {
+        let program = program();
+        let bytes = program.serialize().unwrap();
+        let decoded = Program::deserialize(&bytes).unwrap();
+
+        assert_eq!(decoded.vals(), program.vals());
+        assert_eq!(decoded.functions(), program.functions());
+        assert_eq!(decoded.entry_point(), program.entry_point());
+        assert_eq!(decoded.return_type(0), Some(NumeralType::I32));
+        assert_eq!(decoded.return_type(1), None);
+        assert_eq!(decoded.natives(), program.natives());
+        assert_eq!(decoded.consts(0), program.consts(0));
+        assert_eq!(decoded.consts(1), program.consts(1));
+        for (a, b) in decoded.chunks().iter().zip(program.chunks().iter()) {
+            assert_eq!(a.name(), b.name());
+            assert_eq!(a.code(), b.code());
+            assert_eq!(a.lines(), b.lines());
+            assert_eq!(a.locals(), b.locals());
+        }
+        assert_eq!(decoded.serialize().unwrap(), bytes);
+    }
+
+    #[test]
+    fn reject_truncated() {
+        let bytes = program().serialize().unwrap();
+        for len in 0..bytes.len() {
+            assert!(
+                matches!(Program::deserialize(&bytes[..len]), Err(FormatError::UnexpectedEoF(_))),
+                "truncation to {} bytes was accepted", len
+            );
+        }
+    }
+
+    #[test]
+    fn reject_corrupt() {
+        let bytes = program().serialize().unwrap();
+
+        let mut magic = bytes.clone();
+        magic[0] = b'X';
+        assert!(matches!(Program::deserialize(&magic), Err(FormatError::BadMagic(_))));
+
+        let mut version = bytes.clone();
+        version[4] = 99;
+        assert_eq!(Program::deserialize(&version).err(), Some(FormatError::UnsupportedVersion(99)));
+
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert_eq!(Program::deserialize(&trailing).err(), Some(FormatError::TrailingBytes(1)));
+
+        let mut entry = bytes.clone();
+        let n = entry.len();
+        entry[(n - 4)..].copy_from_slice(&7u32.to_le_bytes());
+        assert_eq!(Program::deserialize(&entry).err(), Some(FormatError::EntryOutOfRange(7)));
+    }
+}
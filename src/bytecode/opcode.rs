@@ -1,17 +1,22 @@
 use std::error::Error;
 use std::fmt::{Debug, Display, Formatter, Write};
 use std::io::BufRead;
+use std::str::FromStr;
 use crate::bytecode::chunk::Chunk;
 use crate::bytecode::opcode::OpCodeError::IllegalNumeralType;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum OpCodeError {
     IllegalOpcode(u8),
     IllegalNumeralType(u8),
+    JumpOutOfRange(usize),
+    IllegalOperand(usize),
+    /// An instruction at the specified offset extends past the end of the code
+    UnexpectedEoF(usize),
 }
 
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum NumeralType {
     I8,
     I16,
@@ -47,6 +52,24 @@ impl NumeralType {
             NumeralType::F64 => 8,
         }
     }
+
+    /// Returns the largest magnitudes of the negative and of the positive values of integer
+    /// types, e.g. `(128, 127)` for `I8`, or `None` for float types.
+    pub fn int_bounds(&self) -> Option<(u128, u128)> {
+        match self {
+            NumeralType::I8 => Some((i8::MIN.unsigned_abs() as u128, i8::MAX as u128)),
+            NumeralType::I16 => Some((i16::MIN.unsigned_abs() as u128, i16::MAX as u128)),
+            NumeralType::I32 => Some((i32::MIN.unsigned_abs() as u128, i32::MAX as u128)),
+            NumeralType::I64 => Some((i64::MIN.unsigned_abs() as u128, i64::MAX as u128)),
+            NumeralType::I128 => Some((i128::MIN.unsigned_abs(), i128::MAX as u128)),
+            NumeralType::U8 => Some((0, u8::MAX as u128)),
+            NumeralType::U16 => Some((0, u16::MAX as u128)),
+            NumeralType::U32 => Some((0, u32::MAX as u128)),
+            NumeralType::U64 => Some((0, u64::MAX as u128)),
+            NumeralType::U128 => Some((0, u128::MAX)),
+            NumeralType::F32 | NumeralType::F64 => None,
+        }
+    }
 }
 
 impl TryFrom<u8> for NumeralType {
@@ -73,6 +96,29 @@ impl TryFrom<u8> for NumeralType {
     }
 }
 
+impl FromStr for NumeralType {
+    type Err = String;
+
+    /// Parses the name of a numeral type as it is printed by the disassembler, e.g. `F64`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().as_str() {
+            "I8" => Ok(NumeralType::I8),
+            "I16" => Ok(NumeralType::I16),
+            "I32" => Ok(NumeralType::I32),
+            "I64" => Ok(NumeralType::I64),
+            "I128" => Ok(NumeralType::I128),
+            "U8" => Ok(NumeralType::U8),
+            "U16" => Ok(NumeralType::U16),
+            "U32" => Ok(NumeralType::U32),
+            "U64" => Ok(NumeralType::U64),
+            "U128" => Ok(NumeralType::U128),
+            "F32" => Ok(NumeralType::F32),
+            "F64" => Ok(NumeralType::F64),
+            _ => Err(format!("'{}' is not a valid numeral type", s)),
+        }
+    }
+}
+
 impl Into<u8> for NumeralType {
     fn into(self) -> u8 {
         match self {
@@ -98,6 +144,9 @@ impl Display for OpCodeError {
         match self {
             Self::IllegalOpcode(c) => f.write_str(&format!("There is no opcode with id {}", c)),
             Self::IllegalNumeralType(n) => f.write_str(&format!("There is no numeral type with id {}", n)),
+            Self::UnexpectedEoF(o) => f.write_str(&format!("Instruction at offset {} is truncated", o)),
+            Self::IllegalOperand(o) => f.write_str(&format!("Malformed instruction operand at offset {}", o)),
+            Self::JumpOutOfRange(d) => f.write_str(&format!("Jump distance of {} bytes exceeds the maximum jump range", d)),
         }
     }
 }
@@ -107,19 +156,95 @@ impl Error for OpCodeError {}
 
 
 
+#[derive(Clone, Copy)]
 pub enum OpCode {
-    /// Returns the current stack frame
-    Ret,
+    /// Returns from the current stack frame. The specified number of bytes on top of the stack
+    /// are the return value, which is moved to the start of the frame before the frame is popped.
+    Ret(usize),
+    /// Calls the function with the specified index. The second operand is the number of argument
+    /// bytes on top of the stack, which become the first locals of the new frame.
+    Call(usize, usize),
+    /// Calls the native function with the specified index in the native import table of the
+    /// program. Its arguments are popped off the stack and its return value is pushed.
+    CallNative(usize),
     /// Pushes a constant onto the stack
     Const(u8, u8),
+    /// Pushes a constant onto the stack. In contrast to `Const`, offset and size of the constant
+    /// are encoded as variable length LEB128 operands, so constants beyond the first 256 bytes of
+    /// the constant pool can be reached.
+    ConstLong(usize, usize),
     /// Negates the stop of the stack
     Neg(NumeralType),
 
-    /// Binary operators
+    /// Binary operators. Integer operations trap on overflow and division by zero.
     Add(NumeralType),
     Sub(NumeralType),
     Mul(NumeralType),
     Div(NumeralType),
+    Rem(NumeralType),
+
+    /// Integer operators that wrap around at the boundary of the type
+    WrappingAdd(NumeralType),
+    WrappingSub(NumeralType),
+    WrappingMul(NumeralType),
+    WrappingDiv(NumeralType),
+
+    /// Integer operators that saturate at the numeric bounds of the type
+    SaturatingAdd(NumeralType),
+    SaturatingSub(NumeralType),
+    SaturatingMul(NumeralType),
+    SaturatingDiv(NumeralType),
+
+    /// Comparison operators. These push a boolean (a single byte that is either `0` or `1`).
+    Eq(NumeralType),
+    Ne(NumeralType),
+    Lt(NumeralType),
+    Le(NumeralType),
+    Gt(NumeralType),
+    Ge(NumeralType),
+    /// Inverts the boolean on top of the stack
+    Not,
+
+    /// Unconditionally jumps forward by the specified number of bytes. Jump offsets are relative
+    /// to the end of the jump instruction.
+    Jump(u16),
+    /// Pops the boolean on top of the stack and jumps forward if it is `false`
+    JumpIfFalse(u16),
+    /// Jumps forward if the boolean on top of the stack is `false` and leaves it on the stack.
+    /// Otherwise, the boolean is popped. This is used to short-circuit `&&`.
+    JumpIfFalseOrPop(u16),
+    /// Jumps forward if the boolean on top of the stack is `true` and leaves it on the stack.
+    /// Otherwise, the boolean is popped. This is used to short-circuit `||`.
+    JumpIfTrueOrPop(u16),
+    /// Unconditionally jumps backwards by the specified number of bytes
+    Loop(u16),
+
+    /// Bitwise operators for integer types
+    BitAnd(NumeralType),
+    BitOr(NumeralType),
+    BitXor(NumeralType),
+    BitNot(NumeralType),
+    /// Shifts the integer below the top of the stack by the amount on top of the stack. Both
+    /// operands are of the same type and shifting by the bit width of the type or more traps.
+    Shl(NumeralType),
+    /// Right shift. This is an arithmetic shift for signed types and a logical shift for unsigned
+    /// types.
+    Shr(NumeralType),
+
+    /// Converts the value on top of the stack from the first into the second numeral type. This
+    /// follows the semantics of Rust's `as` operator, i.e. integers are truncated or extended,
+    /// floats are saturated when converted into integers and integers are rounded to the nearest
+    /// float.
+    Cast(NumeralType, NumeralType),
+
+    /// Pushes a copy of the `size` bytes at `offset` relative to the frame pointer
+    LoadLocal(usize, usize),
+    /// Pops `size` bytes off the stack and writes them to `offset` relative to the frame pointer
+    StoreLocal(usize, usize),
+    /// Pops the specified number of bytes off the stack
+    Pop(usize),
+    /// Pushes a copy of the specified number of bytes on top of the stack
+    Dup(usize),
 }
 
 impl OpCode {
@@ -137,14 +262,26 @@ impl OpCode {
         f.write_str(&format!("{:04}  {}-{char:03}  ", offset, line_format, char=lines[offset].1))?;
 
         match self {
-            Self::Ret => {
-                f.write_str("RET\n")?;
-                Ok(offset + 1)
+            Self::Ret(s) => {
+                f.write_str(&format!("RET   {s:>16}\n"))?;
+                Ok(offset + self.size())
+            },
+            Self::Call(i, a) => {
+                f.write_str(&format!("CALL  {i:>16}{a:>16}\n"))?;
+                Ok(offset + self.size())
+            },
+            Self::CallNative(i) => {
+                f.write_str(&format!("CALLN {i:>16}\n"))?;
+                Ok(offset + self.size())
             },
             Self::Const(i, s) => {
                 f.write_str(&format!("CONST {i:>16}{s:>16}\n"))?;
                 Ok(offset + 3)
             },
+            Self::ConstLong(i, s) => {
+                f.write_str(&format!("CONSTL{i:>16}{s:>16}\n"))?;
+                Ok(offset + self.size())
+            },
             Self::Neg(n) => {
                 f.write_str(&format!("NEG   {:?}\n", n))?;
                 Ok(offset + 2)
@@ -166,17 +303,165 @@ impl OpCode {
                 f.write_str(&format!("DIV   {:?}\n", n))?;
                 Ok(offset + 2)
             }
+            OpCode::Rem(n) => {
+                f.write_str(&format!("REM   {:?}\n", n))?;
+                Ok(offset + 2)
+            }
+            OpCode::WrappingAdd(n) => {
+                f.write_str(&format!("ADDW  {:?}\n", n))?;
+                Ok(offset + 2)
+            }
+            OpCode::WrappingSub(n) => {
+                f.write_str(&format!("SUBW  {:?}\n", n))?;
+                Ok(offset + 2)
+            }
+            OpCode::WrappingMul(n) => {
+                f.write_str(&format!("MULW  {:?}\n", n))?;
+                Ok(offset + 2)
+            }
+            OpCode::WrappingDiv(n) => {
+                f.write_str(&format!("DIVW  {:?}\n", n))?;
+                Ok(offset + 2)
+            }
+            OpCode::SaturatingAdd(n) => {
+                f.write_str(&format!("ADDS  {:?}\n", n))?;
+                Ok(offset + 2)
+            }
+            OpCode::SaturatingSub(n) => {
+                f.write_str(&format!("SUBS  {:?}\n", n))?;
+                Ok(offset + 2)
+            }
+            OpCode::SaturatingMul(n) => {
+                f.write_str(&format!("MULS  {:?}\n", n))?;
+                Ok(offset + 2)
+            }
+            OpCode::SaturatingDiv(n) => {
+                f.write_str(&format!("DIVS  {:?}\n", n))?;
+                Ok(offset + 2)
+            }
+
+            OpCode::Eq(n) => {
+                f.write_str(&format!("EQ    {:?}\n", n))?;
+                Ok(offset + 2)
+            }
+            OpCode::Ne(n) => {
+                f.write_str(&format!("NE    {:?}\n", n))?;
+                Ok(offset + 2)
+            }
+            OpCode::Lt(n) => {
+                f.write_str(&format!("LT    {:?}\n", n))?;
+                Ok(offset + 2)
+            }
+            OpCode::Le(n) => {
+                f.write_str(&format!("LE    {:?}\n", n))?;
+                Ok(offset + 2)
+            }
+            OpCode::Gt(n) => {
+                f.write_str(&format!("GT    {:?}\n", n))?;
+                Ok(offset + 2)
+            }
+            OpCode::Ge(n) => {
+                f.write_str(&format!("GE    {:?}\n", n))?;
+                Ok(offset + 2)
+            }
+            OpCode::Not => {
+                f.write_str("NOT\n")?;
+                Ok(offset + 1)
+            }
+            OpCode::Jump(j) => {
+                f.write_str(&format!("JMP   {j:>16} -> {:04}\n", offset + 3 + *j as usize))?;
+                Ok(offset + 3)
+            }
+            OpCode::JumpIfFalse(j) => {
+                f.write_str(&format!("JMPF  {j:>16} -> {:04}\n", offset + 3 + *j as usize))?;
+                Ok(offset + 3)
+            }
+            OpCode::JumpIfFalseOrPop(j) => {
+                f.write_str(&format!("JFOP  {j:>16} -> {:04}\n", offset + 3 + *j as usize))?;
+                Ok(offset + 3)
+            }
+            OpCode::JumpIfTrueOrPop(j) => {
+                f.write_str(&format!("JTOP  {j:>16} -> {:04}\n", offset + 3 + *j as usize))?;
+                Ok(offset + 3)
+            }
+            OpCode::Loop(j) => {
+                f.write_str(&format!("LOOP  {j:>16} -> {:04}\n", (offset + 3).saturating_sub(*j as usize)))?;
+                Ok(offset + 3)
+            }
+
+            OpCode::BitAnd(n) => {
+                f.write_str(&format!("AND   {:?}\n", n))?;
+                Ok(offset + 2)
+            }
+            OpCode::BitOr(n) => {
+                f.write_str(&format!("OR    {:?}\n", n))?;
+                Ok(offset + 2)
+            }
+            OpCode::BitXor(n) => {
+                f.write_str(&format!("XOR   {:?}\n", n))?;
+                Ok(offset + 2)
+            }
+            OpCode::BitNot(n) => {
+                f.write_str(&format!("INV   {:?}\n", n))?;
+                Ok(offset + 2)
+            }
+            OpCode::Shl(n) => {
+                f.write_str(&format!("SHL   {:?}\n", n))?;
+                Ok(offset + 2)
+            }
+            OpCode::Shr(n) => {
+                f.write_str(&format!("SHR   {:?}\n", n))?;
+                Ok(offset + 2)
+            }
+            OpCode::Cast(from, to) => {
+                f.write_str(&format!("CAST  {:?} {:?}\n", from, to))?;
+                Ok(offset + 3)
+            }
+
+            OpCode::LoadLocal(o, s) => {
+                f.write_str(&format!("LOAD  {o:>16}{s:>16}\n"))?;
+                Ok(offset + self.size())
+            }
+            OpCode::StoreLocal(o, s) => {
+                f.write_str(&format!("STORE {o:>16}{s:>16}\n"))?;
+                Ok(offset + self.size())
+            }
+            OpCode::Pop(s) => {
+                f.write_str(&format!("POP   {s:>16}\n"))?;
+                Ok(offset + self.size())
+            }
+            OpCode::Dup(s) => {
+                f.write_str(&format!("DUP   {s:>16}\n"))?;
+                Ok(offset + self.size())
+            }
         }
     }
 
     pub fn write<F: FnMut(u8)>(self, mut writer: F) {
         match self {
-            Self::Ret => writer(0),
+            Self::Ret(s) => {
+                writer(0);
+                write_leb128(s, &mut writer);
+            },
+            Self::Call(i, a) => {
+                writer(40);
+                write_leb128(i, &mut writer);
+                write_leb128(a, &mut writer);
+            },
+            Self::CallNative(i) => {
+                writer(41);
+                write_leb128(i, &mut writer);
+            },
             Self::Const(i, s) => {
                 writer(1);
                 writer(i);
                 writer(s);
             },
+            Self::ConstLong(i, s) => {
+                writer(35);
+                write_leb128(i, &mut writer);
+                write_leb128(s, &mut writer);
+            },
             Self::Neg(num) => {
                 writer(2);
                 writer(num.into());
@@ -198,19 +483,258 @@ impl OpCode {
                 writer(6);
                 writer(num.into());
             }
+            OpCode::Rem(num) => {
+                writer(7);
+                writer(num.into());
+            }
+            OpCode::WrappingAdd(num) => {
+                writer(8);
+                writer(num.into());
+            }
+            OpCode::WrappingSub(num) => {
+                writer(9);
+                writer(num.into());
+            }
+            OpCode::WrappingMul(num) => {
+                writer(10);
+                writer(num.into());
+            }
+            OpCode::WrappingDiv(num) => {
+                writer(11);
+                writer(num.into());
+            }
+            OpCode::SaturatingAdd(num) => {
+                writer(12);
+                writer(num.into());
+            }
+            OpCode::SaturatingSub(num) => {
+                writer(13);
+                writer(num.into());
+            }
+            OpCode::SaturatingMul(num) => {
+                writer(14);
+                writer(num.into());
+            }
+            OpCode::SaturatingDiv(num) => {
+                writer(15);
+                writer(num.into());
+            }
+
+            OpCode::Eq(num) => {
+                writer(16);
+                writer(num.into());
+            }
+            OpCode::Ne(num) => {
+                writer(17);
+                writer(num.into());
+            }
+            OpCode::Lt(num) => {
+                writer(18);
+                writer(num.into());
+            }
+            OpCode::Le(num) => {
+                writer(19);
+                writer(num.into());
+            }
+            OpCode::Gt(num) => {
+                writer(20);
+                writer(num.into());
+            }
+            OpCode::Ge(num) => {
+                writer(21);
+                writer(num.into());
+            }
+            OpCode::Not => writer(22),
+            OpCode::Jump(j) => {
+                writer(23);
+                j.to_le_bytes().into_iter().for_each(&mut writer);
+            }
+            OpCode::JumpIfFalse(j) => {
+                writer(24);
+                j.to_le_bytes().into_iter().for_each(&mut writer);
+            }
+            OpCode::JumpIfFalseOrPop(j) => {
+                writer(25);
+                j.to_le_bytes().into_iter().for_each(&mut writer);
+            }
+            OpCode::JumpIfTrueOrPop(j) => {
+                writer(26);
+                j.to_le_bytes().into_iter().for_each(&mut writer);
+            }
+            OpCode::Loop(j) => {
+                writer(27);
+                j.to_le_bytes().into_iter().for_each(&mut writer);
+            }
+
+            OpCode::BitAnd(num) => {
+                writer(28);
+                writer(num.into());
+            }
+            OpCode::BitOr(num) => {
+                writer(29);
+                writer(num.into());
+            }
+            OpCode::BitXor(num) => {
+                writer(30);
+                writer(num.into());
+            }
+            OpCode::BitNot(num) => {
+                writer(31);
+                writer(num.into());
+            }
+            OpCode::Shl(num) => {
+                writer(32);
+                writer(num.into());
+            }
+            OpCode::Shr(num) => {
+                writer(33);
+                writer(num.into());
+            }
+            OpCode::Cast(from, to) => {
+                writer(34);
+                writer(from.into());
+                writer(to.into());
+            }
+
+            OpCode::LoadLocal(o, s) => {
+                writer(36);
+                write_leb128(o, &mut writer);
+                write_leb128(s, &mut writer);
+            }
+            OpCode::StoreLocal(o, s) => {
+                writer(37);
+                write_leb128(o, &mut writer);
+                write_leb128(s, &mut writer);
+            }
+            OpCode::Pop(s) => {
+                writer(38);
+                write_leb128(s, &mut writer);
+            }
+            OpCode::Dup(s) => {
+                writer(39);
+                write_leb128(s, &mut writer);
+            }
         };
     }
 
     /// Returns the size of the opcode within program memory in bytes
     pub fn size(&self) -> usize {
         match self {
-            OpCode::Ret => 1,
+            OpCode::Ret(s) => 1 + leb128_size(*s),
+            OpCode::Call(i, a) => 1 + leb128_size(*i) + leb128_size(*a),
+            OpCode::CallNative(i) => 1 + leb128_size(*i),
             OpCode::Const(_, _) => 3,
+            OpCode::ConstLong(i, s) => 1 + leb128_size(*i) + leb128_size(*s),
             OpCode::Neg(_) => 2,
             OpCode::Add(_) => 2,
             OpCode::Sub(_) => 2,
             OpCode::Mul(_) => 2,
             OpCode::Div(_) => 2,
+            OpCode::Rem(_) => 2,
+            OpCode::WrappingAdd(_) => 2,
+            OpCode::WrappingSub(_) => 2,
+            OpCode::WrappingMul(_) => 2,
+            OpCode::WrappingDiv(_) => 2,
+            OpCode::SaturatingAdd(_) => 2,
+            OpCode::SaturatingSub(_) => 2,
+            OpCode::SaturatingMul(_) => 2,
+            OpCode::SaturatingDiv(_) => 2,
+            OpCode::Eq(_) => 2,
+            OpCode::Ne(_) => 2,
+            OpCode::Lt(_) => 2,
+            OpCode::Le(_) => 2,
+            OpCode::Gt(_) => 2,
+            OpCode::Ge(_) => 2,
+            OpCode::Not => 1,
+            OpCode::Jump(_) => 3,
+            OpCode::JumpIfFalse(_) => 3,
+            OpCode::JumpIfFalseOrPop(_) => 3,
+            OpCode::JumpIfTrueOrPop(_) => 3,
+            OpCode::Loop(_) => 3,
+            OpCode::BitAnd(_) => 2,
+            OpCode::BitOr(_) => 2,
+            OpCode::BitXor(_) => 2,
+            OpCode::BitNot(_) => 2,
+            OpCode::Shl(_) => 2,
+            OpCode::Shr(_) => 2,
+            OpCode::Cast(_, _) => 3,
+            OpCode::LoadLocal(o, s) => 1 + leb128_size(*o) + leb128_size(*s),
+            OpCode::StoreLocal(o, s) => 1 + leb128_size(*o) + leb128_size(*s),
+            OpCode::Pop(s) => 1 + leb128_size(*s),
+            OpCode::Dup(s) => 1 + leb128_size(*s),
+        }
+    }
+
+    /// Returns the amount of fuel the VM consumes to execute the instruction. The cost only
+    /// depends on the instruction and its operands, such that a program always consumes the same
+    /// amount of fuel. Instructions that copy data cost an additional unit per 8 bytes copied.
+    pub fn cost(&self) -> u64 {
+        let copy = |s: usize| (s / 8) as u64;
+        match self {
+            OpCode::Ret(s) => 4 + copy(*s),
+            OpCode::Call(_, _) => 8,
+            OpCode::CallNative(_) => 8,
+            OpCode::Const(_, s) => 1 + copy(*s as usize),
+            OpCode::ConstLong(_, s) => 1 + copy(*s),
+            OpCode::Mul(_)
+            | OpCode::WrappingMul(_)
+            | OpCode::SaturatingMul(_) => 2,
+            OpCode::Div(_)
+            | OpCode::Rem(_)
+            | OpCode::WrappingDiv(_)
+            | OpCode::SaturatingDiv(_) => 4,
+            OpCode::LoadLocal(_, s) => 1 + copy(*s),
+            OpCode::StoreLocal(_, s) => 1 + copy(*s),
+            OpCode::Dup(s) => 1 + copy(*s),
+            _ => 1,
+        }
+    }
+
+    /// Returns the mnemonic of the instruction as it is written in assembly source.
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            OpCode::Ret(_) => "RET",
+            OpCode::Call(_, _) => "CALL",
+            OpCode::CallNative(_) => "CALLN",
+            OpCode::Const(_, _) => "CONST",
+            OpCode::ConstLong(_, _) => "CONSTL",
+            OpCode::Neg(_) => "NEG",
+            OpCode::Add(_) => "ADD",
+            OpCode::Sub(_) => "SUB",
+            OpCode::Mul(_) => "MUL",
+            OpCode::Div(_) => "DIV",
+            OpCode::Rem(_) => "REM",
+            OpCode::WrappingAdd(_) => "ADDW",
+            OpCode::WrappingSub(_) => "SUBW",
+            OpCode::WrappingMul(_) => "MULW",
+            OpCode::WrappingDiv(_) => "DIVW",
+            OpCode::SaturatingAdd(_) => "ADDS",
+            OpCode::SaturatingSub(_) => "SUBS",
+            OpCode::SaturatingMul(_) => "MULS",
+            OpCode::SaturatingDiv(_) => "DIVS",
+            OpCode::Eq(_) => "EQ",
+            OpCode::Ne(_) => "NE",
+            OpCode::Lt(_) => "LT",
+            OpCode::Le(_) => "LE",
+            OpCode::Gt(_) => "GT",
+            OpCode::Ge(_) => "GE",
+            OpCode::Not => "NOT",
+            OpCode::Jump(_) => "JMP",
+            OpCode::JumpIfFalse(_) => "JMPF",
+            OpCode::JumpIfFalseOrPop(_) => "JFOP",
+            OpCode::JumpIfTrueOrPop(_) => "JTOP",
+            OpCode::Loop(_) => "LOOP",
+            OpCode::BitAnd(_) => "AND",
+            OpCode::BitOr(_) => "OR",
+            OpCode::BitXor(_) => "XOR",
+            OpCode::BitNot(_) => "INV",
+            OpCode::Shl(_) => "SHL",
+            OpCode::Shr(_) => "SHR",
+            OpCode::Cast(_, _) => "CAST",
+            OpCode::LoadLocal(_, _) => "LOAD",
+            OpCode::StoreLocal(_, _) => "STORE",
+            OpCode::Pop(_) => "POP",
+            OpCode::Dup(_) => "DUP",
         }
     }
 }
@@ -222,19 +746,128 @@ impl Debug for OpCode {
     }
 }
 
+/// Writes an unsigned LEB128 operand.
+fn write_leb128<F: FnMut(u8)>(mut value: usize, writer: &mut F) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            writer(byte);
+            return;
+        }
+        writer(byte | 0x80);
+    }
+}
+
+/// Returns the number of bytes needed to encode `value` as an unsigned LEB128 operand.
+fn leb128_size(mut value: usize) -> usize {
+    let mut size = 1;
+    while value >= 0x80 {
+        value >>= 7;
+        size += 1;
+    }
+    size
+}
+
+/// Returns the byte at `offset` in the code of the chunk.
+fn read_u8(chunk: &Chunk, offset: usize) -> Result<u8, OpCodeError> {
+    chunk.code().get(offset)
+        .copied()
+        .ok_or(OpCodeError::UnexpectedEoF(offset))
+}
+
+/// Reads an unsigned LEB128 operand from the chunk and returns its value together with its
+/// encoded length in bytes.
+fn read_leb128(chunk: &Chunk, offset: usize) -> Result<(usize, usize), OpCodeError> {
+    let mut value = 0usize;
+    let mut len = 0usize;
+    loop {
+        let byte = read_u8(chunk, offset + len)?;
+        let bits = (byte & 0x7f) as usize;
+        let shift = 7 * len as u32;
+        if shift >= usize::BITS || (bits << shift) >> shift != bits {
+            return Err(OpCodeError::IllegalOperand(offset));
+        }
+        value |= bits << shift;
+        len += 1;
+        if byte & 0x80 == 0 {
+            return Ok((value, len));
+        }
+    }
+}
+
+/// Reads a little endian `u16` operand from the chunk.
+fn read_u16(chunk: &Chunk, offset: usize) -> Result<u16, OpCodeError> {
+    Ok(u16::from_le_bytes([read_u8(chunk, offset)?, read_u8(chunk, offset + 1)?]))
+}
+
 impl TryFrom<(usize, &Chunk)> for OpCode {
     type Error = OpCodeError;
 
     /// Decode instruction
     fn try_from((offset, chunk): (usize, &Chunk)) -> Result<Self, Self::Error> {
-        match chunk.code()[offset] {
-            0 => Ok(Self::Ret),
-            1 => Ok(Self::Const(chunk.code()[offset + 1], chunk.code()[offset + 2])),
-            2 => Ok(Self::Neg(NumeralType::try_from(chunk.code()[offset + 1])?)),
-            3 => Ok(Self::Add(NumeralType::try_from(chunk.code()[offset + 1])?)),
-            4 => Ok(Self::Sub(NumeralType::try_from(chunk.code()[offset + 1])?)),
-            5 => Ok(Self::Mul(NumeralType::try_from(chunk.code()[offset + 1])?)),
-            6 => Ok(Self::Div(NumeralType::try_from(chunk.code()[offset + 1])?)),
+        match read_u8(chunk, offset)? {
+            0 => Ok(Self::Ret(read_leb128(chunk, offset + 1)?.0)),
+            1 => Ok(Self::Const(read_u8(chunk, offset + 1)?, read_u8(chunk, offset + 2)?)),
+            2 => Ok(Self::Neg(NumeralType::try_from(read_u8(chunk, offset + 1)?)?)),
+            3 => Ok(Self::Add(NumeralType::try_from(read_u8(chunk, offset + 1)?)?)),
+            4 => Ok(Self::Sub(NumeralType::try_from(read_u8(chunk, offset + 1)?)?)),
+            5 => Ok(Self::Mul(NumeralType::try_from(read_u8(chunk, offset + 1)?)?)),
+            6 => Ok(Self::Div(NumeralType::try_from(read_u8(chunk, offset + 1)?)?)),
+            7 => Ok(Self::Rem(NumeralType::try_from(read_u8(chunk, offset + 1)?)?)),
+            8 => Ok(Self::WrappingAdd(NumeralType::try_from(read_u8(chunk, offset + 1)?)?)),
+            9 => Ok(Self::WrappingSub(NumeralType::try_from(read_u8(chunk, offset + 1)?)?)),
+            10 => Ok(Self::WrappingMul(NumeralType::try_from(read_u8(chunk, offset + 1)?)?)),
+            11 => Ok(Self::WrappingDiv(NumeralType::try_from(read_u8(chunk, offset + 1)?)?)),
+            12 => Ok(Self::SaturatingAdd(NumeralType::try_from(read_u8(chunk, offset + 1)?)?)),
+            13 => Ok(Self::SaturatingSub(NumeralType::try_from(read_u8(chunk, offset + 1)?)?)),
+            14 => Ok(Self::SaturatingMul(NumeralType::try_from(read_u8(chunk, offset + 1)?)?)),
+            15 => Ok(Self::SaturatingDiv(NumeralType::try_from(read_u8(chunk, offset + 1)?)?)),
+            16 => Ok(Self::Eq(NumeralType::try_from(read_u8(chunk, offset + 1)?)?)),
+            17 => Ok(Self::Ne(NumeralType::try_from(read_u8(chunk, offset + 1)?)?)),
+            18 => Ok(Self::Lt(NumeralType::try_from(read_u8(chunk, offset + 1)?)?)),
+            19 => Ok(Self::Le(NumeralType::try_from(read_u8(chunk, offset + 1)?)?)),
+            20 => Ok(Self::Gt(NumeralType::try_from(read_u8(chunk, offset + 1)?)?)),
+            21 => Ok(Self::Ge(NumeralType::try_from(read_u8(chunk, offset + 1)?)?)),
+            22 => Ok(Self::Not),
+            23 => Ok(Self::Jump(read_u16(chunk, offset + 1)?)),
+            24 => Ok(Self::JumpIfFalse(read_u16(chunk, offset + 1)?)),
+            25 => Ok(Self::JumpIfFalseOrPop(read_u16(chunk, offset + 1)?)),
+            26 => Ok(Self::JumpIfTrueOrPop(read_u16(chunk, offset + 1)?)),
+            27 => Ok(Self::Loop(read_u16(chunk, offset + 1)?)),
+            28 => Ok(Self::BitAnd(NumeralType::try_from(read_u8(chunk, offset + 1)?)?)),
+            29 => Ok(Self::BitOr(NumeralType::try_from(read_u8(chunk, offset + 1)?)?)),
+            30 => Ok(Self::BitXor(NumeralType::try_from(read_u8(chunk, offset + 1)?)?)),
+            31 => Ok(Self::BitNot(NumeralType::try_from(read_u8(chunk, offset + 1)?)?)),
+            32 => Ok(Self::Shl(NumeralType::try_from(read_u8(chunk, offset + 1)?)?)),
+            33 => Ok(Self::Shr(NumeralType::try_from(read_u8(chunk, offset + 1)?)?)),
+            34 => Ok(Self::Cast(
+                NumeralType::try_from(read_u8(chunk, offset + 1)?)?,
+                NumeralType::try_from(read_u8(chunk, offset + 2)?)?,
+            )),
+            35 => {
+                let (i, len) = read_leb128(chunk, offset + 1)?;
+                let (s, _) = read_leb128(chunk, offset + 1 + len)?;
+                Ok(Self::ConstLong(i, s))
+            },
+            36 => {
+                let (o, len) = read_leb128(chunk, offset + 1)?;
+                let (s, _) = read_leb128(chunk, offset + 1 + len)?;
+                Ok(Self::LoadLocal(o, s))
+            },
+            37 => {
+                let (o, len) = read_leb128(chunk, offset + 1)?;
+                let (s, _) = read_leb128(chunk, offset + 1 + len)?;
+                Ok(Self::StoreLocal(o, s))
+            },
+            38 => Ok(Self::Pop(read_leb128(chunk, offset + 1)?.0)),
+            39 => Ok(Self::Dup(read_leb128(chunk, offset + 1)?.0)),
+            40 => {
+                let (i, len) = read_leb128(chunk, offset + 1)?;
+                let (a, _) = read_leb128(chunk, offset + 1 + len)?;
+                Ok(Self::Call(i, a))
+            },
+            41 => Ok(Self::CallNative(read_leb128(chunk, offset + 1)?.0)),
             v => Err(OpCodeError::IllegalOpcode(v))
         }
     }
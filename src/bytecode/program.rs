@@ -0,0 +1,323 @@
+use std::collections::HashMap;
+use std::ops::Range;
+use crate::bytecode::chunk::Chunk;
+use crate::bytecode::opcode::NumeralType;
+use crate::lang::function::FerrumFunctionPtr;
+use crate::vm::native::NativeSignature;
+
+/// Name of the function that is used as the entry point, if none has been set explicitly.
+pub const MAIN_FUNCTION: &str = "main";
+
+/// A native function that is imported by a program. `CallNative` operands are indices into the
+/// import table of the program.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NativeImport {
+    path: String,
+    signature: NativeSignature,
+}
+
+impl NativeImport {
+    pub fn new(path: String, signature: NativeSignature) -> Self {
+        NativeImport { path, signature }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn signature(&self) -> &NativeSignature {
+        &self.signature
+    }
+}
+
+/// A compiled program. Every function of the program is stored in its own chunk and `Call`
+/// instructions address functions through the index of their chunk.
+pub struct Program {
+    /// program memory, one chunk per function
+    chunks: Vec<Chunk>,
+    /// Maps each function to the index of its chunk
+    functions: HashMap<FerrumFunctionPtr, usize>,
+    /// Constant pool shared by all chunks of the program
+    vals: Vec<u8>,
+    /// Range of the constants of each chunk within the shared constant pool
+    const_ranges: Vec<Range<usize>>,
+    /// Maps each distinct constant block in the pool to its offset
+    const_blocks: HashMap<Vec<u8>, usize>,
+    /// Chunk index of the entry point, if set explicitly
+    entry: Option<usize>,
+    /// Numeral type of the value returned by each function, if declared
+    return_types: Vec<Option<NumeralType>>,
+    /// Native functions called by the program
+    natives: Vec<NativeImport>,
+}
+
+impl Program {
+    pub fn new() -> Self {
+        Program {
+            chunks: Vec::new(),
+            functions: HashMap::new(),
+            vals: Vec::new(),
+            const_ranges: Vec::new(),
+            const_blocks: HashMap::new(),
+            entry: None,
+            return_types: Vec::new(),
+            natives: Vec::new(),
+        }
+    }
+
+    /// Adds the compiled code of a function to the program and returns the index of its chunk.
+    /// The constants of the chunk are moved to the shared constant pool of the program, where
+    /// chunks with exactly the same constants share them. If a function with the same pointer already
+    /// exists, it is replaced and constants no other chunk uses are dropped from the pool.
+    pub fn add_function(&mut self, ptr: FerrumFunctionPtr, mut chunk: Chunk) -> usize {
+        let vals = chunk.take_vals();
+
+        match self.functions.get(&ptr) {
+            Some(&i) => {
+                self.chunks[i] = chunk;
+                self.const_ranges[i] = 0..0;
+                self.compact_consts();
+                self.const_ranges[i] = self.intern_consts(&vals);
+                i
+            },
+            None => {
+                let i = self.chunks.len();
+                self.chunks.push(chunk);
+                let range = self.intern_consts(&vals);
+                self.const_ranges.push(range);
+                self.return_types.push(None);
+                self.functions.insert(ptr, i);
+                i
+            }
+        }
+    }
+
+    /// Returns the range of the constants `vals` within the shared constant pool. Only whole
+    /// blocks are shared: if another chunk has exactly the same constants, its block is reused,
+    /// otherwise `vals` is appended, even if the pool contains them as part of a larger block.
+    /// `Const` operands are offsets into the block of a chunk, so individual constants cannot be
+    /// shared without rewriting the code of the chunk.
+    fn intern_consts(&mut self, vals: &[u8]) -> Range<usize> {
+        if vals.is_empty() {
+            return 0..0;
+        }
+        let base = match self.const_blocks.get(vals) {
+            Some(&base) => base,
+            None => {
+                let base = self.vals.len();
+                self.vals.extend_from_slice(vals);
+                self.const_blocks.insert(vals.to_vec(), base);
+                base
+            },
+        };
+        base..(base + vals.len())
+    }
+
+    /// Rebuilds the shared constant pool from the constants that are still used by a chunk.
+    fn compact_consts(&mut self) {
+        let vals = std::mem::take(&mut self.vals);
+        self.const_blocks.clear();
+        for i in 0..self.const_ranges.len() {
+            let range = self.const_ranges[i].clone();
+            self.const_ranges[i] = self.intern_consts(&vals[range]);
+        }
+    }
+
+    /// Assembles a program from its raw parts. `const_ranges` and `return_types` must contain
+    /// one entry per chunk and every range must lie within `vals`.
+    pub(crate) fn from_raw(
+        chunks: Vec<Chunk>,
+        functions: HashMap<FerrumFunctionPtr, usize>,
+        vals: Vec<u8>,
+        const_ranges: Vec<Range<usize>>,
+        entry: Option<usize>,
+        return_types: Vec<Option<NumeralType>>,
+        natives: Vec<NativeImport>,
+    ) -> Self {
+        let const_blocks = const_ranges.iter()
+            .filter(|range| !range.is_empty())
+            .map(|range| (vals[range.clone()].to_vec(), range.start))
+            .collect();
+        Program {
+            chunks,
+            functions,
+            vals,
+            const_ranges,
+            const_blocks,
+            entry,
+            return_types,
+            natives,
+        }
+    }
+
+    /// Adds a native function to the import table of the program and returns its index. If the
+    /// native has already been imported, the index of the existing import is returned.
+    pub fn import_native(&mut self, path: &str, signature: NativeSignature) -> usize {
+        match self.natives.iter().position(|n| n.path == path) {
+            Some(i) => i,
+            None => {
+                self.natives.push(NativeImport::new(path.to_owned(), signature));
+                self.natives.len() - 1
+            },
+        }
+    }
+
+    /// Returns the native import table of the program.
+    pub fn natives(&self) -> &[NativeImport] {
+        &self.natives
+    }
+
+    /// Returns the offset of the constants of chunk `i` within the shared constant pool.
+    pub fn const_base(&self, i: usize) -> usize {
+        self.const_ranges[i].start
+    }
+
+    /// Returns the explicitly set entry point, if any.
+    pub(crate) fn explicit_entry(&self) -> Option<usize> {
+        self.entry
+    }
+
+    /// Returns the chunk index of the specified function.
+    pub fn find_function(&self, ptr: &FerrumFunctionPtr) -> Option<usize> {
+        self.functions.get(ptr).copied()
+    }
+
+    /// Sets the function at which execution of the program starts. Returns `false` if the
+    /// function is not part of the program.
+    pub fn set_entry(&mut self, ptr: &FerrumFunctionPtr) -> bool {
+        match self.find_function(ptr) {
+            Some(i) => {
+                self.entry = Some(i);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Declares the numeral type of the value returned by the function. The verifier checks that
+    /// every `Ret` of the function matches the size of the type, and the VM uses it to type the
+    /// value returned by the entry point. Returns `false` if the function is not part of the
+    /// program.
+    pub fn set_return_type(&mut self, ptr: &FerrumFunctionPtr, ty: Option<NumeralType>) -> bool {
+        match self.find_function(ptr) {
+            Some(i) => {
+                self.return_types[i] = ty;
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Returns the declared return type of the function with chunk index `i`.
+    pub fn return_type(&self, i: usize) -> Option<NumeralType> {
+        self.return_types.get(i).copied().flatten()
+    }
+
+    /// Returns the chunk index of the entry point. Unless an entry point has been set
+    /// explicitly, this is the function named `main`. If there are several functions named
+    /// `main`, e.g. with different generics, the one that was added first is used.
+    pub fn entry_point(&self) -> Option<usize> {
+        self.entry.or_else(|| {
+            self.functions.iter()
+                .filter(|(ptr, _)| ptr.function_id() == MAIN_FUNCTION)
+                .map(|(_, i)| *i)
+                .min()
+        })
+    }
+
+    /// Returns the function table of the program.
+    pub fn functions(&self) -> &HashMap<FerrumFunctionPtr, usize> {
+        &self.functions
+    }
+
+    pub fn chunk(&self, i: usize) -> Option<&Chunk> {
+        self.chunks.get(i)
+    }
+
+    pub fn chunks(&self) -> &[Chunk] {
+        &self.chunks
+    }
+
+    /// Returns the constants of the chunk with index `i`. `Const` operands of the chunk are
+    /// offsets into this slice, which ends where the constants of the chunk end.
+    pub fn consts(&self, i: usize) -> &[u8] {
+        &self.vals[self.const_ranges[i].clone()]
+    }
+
+    /// Returns the shared constant pool of the program.
+    pub fn vals(&self) -> &[u8] {
+        &self.vals
+    }
+}
+
+impl Default for Program {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl From<Chunk> for Program {
+    /// Creates a program that consists of a single `main` function.
+    fn from(chunk: Chunk) -> Self {
+        let mut program = Program::new();
+        program.add_function(FerrumFunctionPtr::new(String::from(MAIN_FUNCTION), 0), chunk);
+        program
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::bytecode::chunk::Chunk;
+    use crate::bytecode::opcode::OpCode;
+    use crate::bytecode::program::Program;
+    use crate::lang::function::FerrumFunctionPtr;
+    use crate::vm::VM;
+
+    /// Creates a chunk that returns the constant `val`.
+    fn constant(name: &str, val: i64) -> Chunk {
+        let mut chunk = Chunk::new(String::from(name));
+        chunk.write_const(val, 1, 0);
+        chunk.write(OpCode::Ret(8), 1, 0);
+        chunk
+    }
+
+    #[test]
+    fn shared_consts() {
+        let mut program = Program::new();
+        program.add_function(FerrumFunctionPtr::new(String::from("main"), 0), constant("main", 1));
+        program.add_function(FerrumFunctionPtr::new(String::from("one"), 0), constant("one", 1));
+        let two = program.add_function(FerrumFunctionPtr::new(String::from("two"), 0), constant("two", 2));
+        assert_eq!(program.vals().len(), 16);
+        assert_eq!(program.const_base(0), program.const_base(1));
+
+        // replacing a function drops the constants nobody else uses
+        for val in 3..10 {
+            let i = program.add_function(FerrumFunctionPtr::new(String::from("two"), 0), constant("two", val));
+            assert_eq!(i, two);
+            assert_eq!(program.vals().len(), 16);
+            assert_eq!(program.consts(two), val.to_le_bytes());
+        }
+        program.add_function(FerrumFunctionPtr::new(String::from("main"), 0), constant("main", 42));
+        assert_eq!(program.vals().len(), 24);
+        assert_eq!(program.consts(1), 1i64.to_le_bytes());
+
+        let mut vm = VM::new(program).unwrap();
+        assert_eq!(vm.run().unwrap().get::<8, i64>(), Some(42));
+    }
+
+    #[test]
+    fn entry_point() {
+        let mut program = Program::new();
+        program.add_function(FerrumFunctionPtr::new(String::from("f"), 0), constant("f", 1));
+        for fingerprint in [7, 3, 5, 1] {
+            program.add_function(FerrumFunctionPtr::new(String::from("main"), fingerprint), constant("main", 2));
+        }
+        assert_eq!(program.entry_point(), Some(1));
+
+        assert!(program.set_entry(&FerrumFunctionPtr::new(String::from("f"), 0)));
+        assert_eq!(program.entry_point(), Some(0));
+        assert!(!program.set_entry(&FerrumFunctionPtr::new(String::from("g"), 0)));
+        assert_eq!(Program::new().entry_point(), None);
+    }
+}
@@ -0,0 +1,418 @@
+//! Static verification of bytecode.
+//!
+//! The verifier checks everything the VM relies on without checking it at runtime: every
+//! instruction decodes, operands are in bounds, constants lie within the constant pool, jumps
+//! land on instruction boundaries and the stack depth is consistent along every path through
+//! the code. Programs that pass verification can be executed without the VM indexing out of
+//! bounds of its program memory or reading data that does not belong to the current frame.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use crate::bytecode::chunk::Chunk;
+use crate::bytecode::opcode::{OpCode, OpCodeError};
+use crate::bytecode::program::Program;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum VerifyErrorKind {
+    /// The instruction could not be decoded
+    IllegalInstruction(OpCodeError),
+    /// A constant does not lie within the constant pool of the chunk
+    ConstOutOfRange(usize, usize),
+    /// A jump target lies outside of the code of the chunk
+    JumpOutOfBounds(usize),
+    /// A jump target lies within an instruction instead of at its start
+    JumpIntoInstruction(usize),
+    /// The instruction requires more bytes on the stack than the current frame holds
+    StackUnderflow(usize, usize),
+    /// Two paths reach the instruction with different stack depths
+    StackMismatch(usize, usize),
+    /// A local access reaches past the data of the current frame
+    LocalOutOfFrame(usize, usize),
+    /// A call references a function that does not exist
+    UnknownFunction(usize),
+    /// A native call references an entry that does not exist in the native import table
+    UnknownNative(usize),
+    /// The function is called with different argument sizes
+    InconsistentArguments(usize, usize),
+    /// The function returns values of different sizes
+    InconsistentReturnSize(usize, usize),
+    /// Execution can run past the last instruction of the chunk
+    MissingReturn,
+    /// The operand sizes of the instruction overflow the stack depth
+    SizeOverflow,
+}
+
+/// Verification error. `function` is the index of the chunk in its program and `offset` the
+/// position of the offending instruction.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VerifyError {
+    pub function: usize,
+    pub offset: usize,
+    pub kind: VerifyErrorKind,
+}
+
+impl Display for VerifyErrorKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::IllegalInstruction(e) => f.write_str(&format!("{}", e)),
+            Self::ConstOutOfRange(i, s) => f.write_str(&format!("Constant {}..{} lies outside of the constant pool", i, i + s)),
+            Self::JumpOutOfBounds(t) => f.write_str(&format!("Jump target {} lies outside of the code", t)),
+            Self::JumpIntoInstruction(t) => f.write_str(&format!("Jump target {} is not the start of an instruction", t)),
+            Self::StackUnderflow(req, depth) => f.write_str(&format!("Instruction requires {} bytes on the stack, but the frame only holds {}", req, depth)),
+            Self::StackMismatch(a, b) => f.write_str(&format!("Instruction is reached with stack depths {} and {}", a, b)),
+            Self::LocalOutOfFrame(o, s) => f.write_str(&format!("Local {}..{} lies outside of the frame", o, o + s)),
+            Self::UnknownFunction(i) => f.write_str(&format!("There is no function with index {}", i)),
+            Self::UnknownNative(i) => f.write_str(&format!("There is no native import with index {}", i)),
+            Self::InconsistentArguments(a, b) => f.write_str(&format!("Function is called with {} and {} argument bytes", a, b)),
+            Self::InconsistentReturnSize(a, b) => f.write_str(&format!("Function returns {} and {} bytes", a, b)),
+            Self::MissingReturn => f.write_str("Execution runs past the end of the chunk"),
+            Self::SizeOverflow => f.write_str("Operand sizes of the instruction overflow the stack depth"),
+        }
+    }
+}
+
+impl Display for VerifyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&format!("function {} at {:04}: {}", self.function, self.offset, self.kind))
+    }
+}
+
+impl Error for VerifyError {}
+
+
+/// Decoded instructions of a single chunk.
+struct Decoded {
+    /// instructions in the order of their offsets
+    ops: Vec<(usize, OpCode)>,
+    /// maps the offset of each instruction to its index in `ops`
+    starts: HashMap<usize, usize>,
+}
+
+/// Decodes all instructions of a chunk.
+fn decode(function: usize, chunk: &Chunk) -> Result<Decoded, VerifyError> {
+    let mut ops = Vec::new();
+    let mut starts = HashMap::new();
+    let mut offset = 0;
+    while offset < chunk.code().len() {
+        let op = OpCode::try_from((offset, chunk))
+            .map_err(|e| VerifyError { function, offset, kind: VerifyErrorKind::IllegalInstruction(e) })?;
+        starts.insert(offset, ops.len());
+        let size = op.size();
+        ops.push((offset, op));
+        offset += size;
+    }
+    Ok(Decoded { ops, starts })
+}
+
+/// Returns the `(arguments, return value)` sizes in bytes that the instruction pops and pushes,
+/// excluding calls and native calls, or `None` if the sizes overflow.
+fn stack_effect(op: &OpCode) -> Option<(usize, usize)> {
+    let effect = match op {
+        OpCode::Const(_, s) => (0, *s as usize),
+        OpCode::ConstLong(_, s) => (0, *s),
+        OpCode::Neg(n) | OpCode::BitNot(n) => (n.size(), n.size()),
+        OpCode::Add(n) | OpCode::Sub(n) | OpCode::Mul(n) | OpCode::Div(n) | OpCode::Rem(n)
+        | OpCode::WrappingAdd(n) | OpCode::WrappingSub(n) | OpCode::WrappingMul(n)
+        | OpCode::WrappingDiv(n) | OpCode::SaturatingAdd(n) | OpCode::SaturatingSub(n)
+        | OpCode::SaturatingMul(n) | OpCode::SaturatingDiv(n) | OpCode::BitAnd(n)
+        | OpCode::BitOr(n) | OpCode::BitXor(n) | OpCode::Shl(n) | OpCode::Shr(n) => (2 * n.size(), n.size()),
+        OpCode::Eq(n) | OpCode::Ne(n) | OpCode::Lt(n) | OpCode::Le(n) | OpCode::Gt(n)
+        | OpCode::Ge(n) => (2 * n.size(), 1),
+        OpCode::Not => (1, 1),
+        OpCode::Cast(from, to) => (from.size(), to.size()),
+        OpCode::Jump(_) | OpCode::Loop(_) => (0, 0),
+        OpCode::JumpIfFalse(_) => (1, 0),
+        // the jumping branch keeps the boolean and is handled separately
+        OpCode::JumpIfFalseOrPop(_) | OpCode::JumpIfTrueOrPop(_) => (1, 0),
+        OpCode::LoadLocal(_, s) => (0, *s),
+        OpCode::StoreLocal(_, s) => (*s, 0),
+        OpCode::Pop(s) => (*s, 0),
+        OpCode::Dup(s) => (*s, s.checked_mul(2)?),
+        OpCode::Ret(s) => (*s, 0),
+        OpCode::Call(_, a) => (*a, 0),
+        OpCode::CallNative(_) => (0, 0),
+    };
+    Some(effect)
+}
+
+/// Returns the jump target of jump instructions.
+fn jump_target(offset: usize, op: &OpCode) -> Option<isize> {
+    let end = (offset + op.size()) as isize;
+    match op {
+        OpCode::Jump(j) | OpCode::JumpIfFalse(j) | OpCode::JumpIfFalseOrPop(j)
+        | OpCode::JumpIfTrueOrPop(j) => Some(end + *j as isize),
+        OpCode::Loop(j) => Some(end - *j as isize),
+        _ => None,
+    }
+}
+
+/// Verifies a single chunk that is not part of a program. The chunk is treated as the only
+/// function of a program, i.e. it may only call itself and it is entered without arguments.
+pub fn verify(chunk: &Chunk) -> Result<(), VerifyError> {
+    verify_chunks(&[chunk], &[chunk.vals()], 0, vec![None], &[])
+}
+
+/// Verifies every function of a program.
+pub fn verify_program(program: &Program) -> Result<(), VerifyError> {
+    let chunks: Vec<_> = program.chunks().iter().collect();
+    let consts: Vec<_> = (0..chunks.len()).map(|i| program.consts(i)).collect();
+    let rets = (0..chunks.len())
+        .map(|i| program.return_type(i).map(|t| t.size()))
+        .collect();
+    let natives: Vec<_> = program.natives().iter()
+        .map(|n| (n.signature().arg_size(), n.signature().ret_size()))
+        .collect();
+    verify_chunks(&chunks, &consts, program.entry_point().unwrap_or(0), rets, &natives)
+}
+
+/// Verifies the chunks of a program. `rets` contains the return value size of every function
+/// whose return type is declared and `natives` the argument and return value sizes of every
+/// native import.
+fn verify_chunks(
+    chunks: &[&Chunk],
+    consts: &[&[u8]],
+    entry: usize,
+    mut rets: Vec<Option<usize>>,
+    natives: &[(usize, usize)],
+) -> Result<(), VerifyError> {
+    let decoded = chunks.iter()
+        .enumerate()
+        .map(|(i, chunk)| decode(i, chunk))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // the calling convention is not part of the chunks, so argument sizes and undeclared return
+    // value sizes are inferred from the `Call` and `Ret` instructions
+    let mut args: Vec<Option<usize>> = vec![None; chunks.len()];
+    if entry < chunks.len() {
+        args[entry] = Some(0);
+    }
+    for (function, d) in decoded.iter().enumerate() {
+        for (offset, op) in d.ops.iter() {
+            let err = |kind| VerifyError { function, offset: *offset, kind };
+            match op {
+                OpCode::Call(f, a) => {
+                    let slot = args.get_mut(*f)
+                        .ok_or_else(|| err(VerifyErrorKind::UnknownFunction(*f)))?;
+                    match slot {
+                        Some(prev) if *prev != *a => {
+                            return Err(err(VerifyErrorKind::InconsistentArguments(*prev, *a)));
+                        },
+                        _ => *slot = Some(*a),
+                    }
+                },
+                OpCode::Ret(s) => match rets[function] {
+                    Some(prev) if prev != *s => {
+                        return Err(err(VerifyErrorKind::InconsistentReturnSize(prev, *s)));
+                    },
+                    _ => rets[function] = Some(*s),
+                },
+                _ => (),
+            }
+        }
+    }
+
+    for (function, d) in decoded.iter().enumerate() {
+        verify_stack(function, d, chunks[function].code().len(), consts[function],
+                     args[function].unwrap_or(0), &rets, natives)?;
+    }
+    Ok(())
+}
+
+/// Checks operands and jump targets of every instruction and the stack depth along every path
+/// through the chunk. Depths are measured in bytes relative to the frame pointer.
+fn verify_stack(
+    function: usize,
+    d: &Decoded,
+    len: usize,
+    consts: &[u8],
+    args: usize,
+    rets: &[Option<usize>],
+    natives: &[(usize, usize)],
+) -> Result<(), VerifyError> {
+    if d.ops.is_empty() {
+        return Err(VerifyError { function, offset: 0, kind: VerifyErrorKind::MissingReturn });
+    }
+
+    let mut depths: Vec<Option<usize>> = vec![None; d.ops.len()];
+    let mut work = vec![(0usize, args)];
+
+    while let Some((i, depth)) = work.pop() {
+        let (offset, op) = &d.ops[i];
+        let err = |kind| VerifyError { function, offset: *offset, kind };
+
+        match depths[i] {
+            Some(prev) if prev == depth => continue,
+            Some(prev) => return Err(err(VerifyErrorKind::StackMismatch(prev, depth))),
+            None => depths[i] = Some(depth),
+        }
+
+        // operands
+        match op {
+            OpCode::Const(c, s) if *c as usize + *s as usize > consts.len() => {
+                return Err(err(VerifyErrorKind::ConstOutOfRange(*c as usize, *s as usize)));
+            },
+            OpCode::ConstLong(c, s) if c.checked_add(*s).is_none_or(|end| end > consts.len()) => {
+                return Err(err(VerifyErrorKind::ConstOutOfRange(*c, *s)));
+            },
+            OpCode::LoadLocal(o, s) if o.checked_add(*s).is_none_or(|end| end > depth) => {
+                return Err(err(VerifyErrorKind::LocalOutOfFrame(*o, *s)));
+            },
+            // the stored value is popped before it is written, so the local has to end below it
+            OpCode::StoreLocal(o, s) if o.checked_add(*s).and_then(|end| end.checked_add(*s)).is_none_or(|end| end > depth) => {
+                return Err(err(VerifyErrorKind::LocalOutOfFrame(*o, *s)));
+            },
+            _ => (),
+        }
+
+        // stack effect
+        let (pops, pushes) = match op {
+            OpCode::CallNative(n) => *natives.get(*n)
+                .ok_or(err(VerifyErrorKind::UnknownNative(*n)))?,
+            _ => stack_effect(op).ok_or(err(VerifyErrorKind::SizeOverflow))?,
+        };
+        if pops > depth {
+            return Err(err(VerifyErrorKind::StackUnderflow(pops, depth)));
+        }
+        let pushes = match op {
+            OpCode::Call(f, _) => rets.get(*f)
+                .ok_or(err(VerifyErrorKind::UnknownFunction(*f)))?
+                .unwrap_or(0),
+            _ => pushes,
+        };
+        let next = (depth - pops).checked_add(pushes)
+            .ok_or(err(VerifyErrorKind::SizeOverflow))?;
+
+        // successors
+        let mut successors = Vec::with_capacity(2);
+        if let Some(target) = jump_target(*offset, op) {
+            if target < 0 || target as usize >= len {
+                return Err(err(VerifyErrorKind::JumpOutOfBounds(target.max(0) as usize)));
+            }
+            let j = *d.starts.get(&(target as usize))
+                .ok_or(err(VerifyErrorKind::JumpIntoInstruction(target as usize)))?;
+            let keep = matches!(op, OpCode::JumpIfFalseOrPop(_) | OpCode::JumpIfTrueOrPop(_));
+            successors.push((j, if keep { depth } else { next }));
+        }
+        match op {
+            OpCode::Ret(_) | OpCode::Jump(_) | OpCode::Loop(_) => (),
+            _ if i + 1 < d.ops.len() => successors.push((i + 1, next)),
+            _ => return Err(err(VerifyErrorKind::MissingReturn)),
+        }
+        work.extend(successors);
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::bytecode::chunk::Chunk;
+    use crate::bytecode::opcode::{NumeralType, OpCode};
+    use crate::bytecode::program::Program;
+    use crate::bytecode::verify::{verify, verify_program, VerifyErrorKind};
+    use crate::lang::function::FerrumFunctionPtr;
+
+    fn kind(chunk: &Chunk) -> Option<VerifyErrorKind> {
+        verify(chunk).err().map(|e| e.kind)
+    }
+
+    #[test]
+    fn accept_branches() {
+        let mut chunk = Chunk::new(String::from("main"));
+        chunk.write_const(1u8, 0, 0);
+        let j = chunk.write(OpCode::JumpIfFalse(0), 0, 0);
+        chunk.write_const(2i32, 0, 0);
+        let e = chunk.write(OpCode::Jump(0), 0, 0);
+        chunk.patch_jump(&j).unwrap();
+        chunk.write_const(3i32, 0, 0);
+        chunk.patch_jump(&e).unwrap();
+        chunk.write(OpCode::Ret(4), 0, 0);
+        assert_eq!(kind(&chunk), None);
+    }
+
+    #[test]
+    fn reject_malformed() {
+        let mut chunk = Chunk::new(String::from("main"));
+        chunk.write(OpCode::Add(NumeralType::I32), 0, 0);
+        chunk.write(OpCode::Ret(4), 0, 0);
+        assert_eq!(kind(&chunk), Some(VerifyErrorKind::StackUnderflow(8, 0)));
+
+        let mut chunk = Chunk::new(String::from("main"));
+        chunk.write_const(1i32, 0, 0);
+        chunk.write(OpCode::Const(2, 4), 0, 0);
+        chunk.write(OpCode::Ret(4), 0, 0);
+        assert_eq!(kind(&chunk), Some(VerifyErrorKind::ConstOutOfRange(2, 4)));
+
+        let mut chunk = Chunk::new(String::from("main"));
+        chunk.write(OpCode::Jump(1), 0, 0);
+        chunk.write(OpCode::Ret(0), 0, 0);
+        assert_eq!(kind(&chunk), Some(VerifyErrorKind::JumpIntoInstruction(4)));
+
+        let mut chunk = Chunk::new(String::from("main"));
+        chunk.write_const(1u8, 0, 0);
+        let j = chunk.write(OpCode::JumpIfFalse(0), 0, 0);
+        chunk.write_const(2i32, 0, 0);
+        chunk.patch_jump(&j).unwrap();
+        chunk.write(OpCode::Ret(0), 0, 0);
+        assert_eq!(kind(&chunk), Some(VerifyErrorKind::StackMismatch(4, 0)));
+
+        let mut chunk = Chunk::new(String::from("main"));
+        chunk.write_const(1i32, 0, 0);
+        assert_eq!(kind(&chunk), Some(VerifyErrorKind::MissingReturn));
+    }
+
+    #[test]
+    fn reject_huge_operands() {
+        let huge = 1usize << 63;
+        let cases = [
+            (OpCode::LoadLocal(huge, huge), VerifyErrorKind::LocalOutOfFrame(huge, huge)),
+            (OpCode::LoadLocal(1, usize::MAX), VerifyErrorKind::LocalOutOfFrame(1, usize::MAX)),
+            (OpCode::StoreLocal(1, huge), VerifyErrorKind::LocalOutOfFrame(1, huge)),
+            (OpCode::StoreLocal(0, huge), VerifyErrorKind::LocalOutOfFrame(0, huge)),
+            (OpCode::Pop(usize::MAX), VerifyErrorKind::StackUnderflow(usize::MAX, 4)),
+            (OpCode::Dup(huge), VerifyErrorKind::SizeOverflow),
+        ];
+        for (op, expected) in cases {
+            let mut chunk = Chunk::new(String::from("main"));
+            chunk.write_const(1i32, 0, 0);
+            chunk.write(op, 0, 0);
+            chunk.write(OpCode::Ret(0), 0, 0);
+            assert_eq!(kind(&chunk), Some(expected), "{:?}", op);
+        }
+
+        // pushes that fit on their own but overflow together with the current depth
+        let mut chunk = Chunk::new(String::from("main"));
+        chunk.write_const(1i32, 0, 0);
+        chunk.write(OpCode::Dup(usize::MAX / 2), 0, 0);
+        chunk.write(OpCode::Ret(0), 0, 0);
+        assert_eq!(kind(&chunk), Some(VerifyErrorKind::StackUnderflow(usize::MAX / 2, 4)));
+    }
+
+    #[test]
+    fn reject_foreign_consts() {
+        let mut main = Chunk::new(String::from("main"));
+        main.write(OpCode::Const(0, 4), 0, 0);
+        main.write(OpCode::Ret(4), 0, 0);
+        let mut other = Chunk::new(String::from("other"));
+        other.write_const(7i32, 0, 0);
+        other.write(OpCode::Ret(4), 0, 0);
+
+        // the constant exists in the shared pool, but it belongs to the other chunk
+        let mut program = Program::new();
+        program.add_function(FerrumFunctionPtr::new(String::from("main"), 0), main);
+        program.add_function(FerrumFunctionPtr::new(String::from("other"), 0), other);
+        let err = verify_program(&program).unwrap_err();
+        assert_eq!((err.function, err.kind), (0, VerifyErrorKind::ConstOutOfRange(0, 4)));
+    }
+
+    #[test]
+    fn reject_truncated() {
+        let mut chunk = Chunk::new(String::from("main"));
+        chunk.write(OpCode::Call(300, 0), 0, 0);
+        let code = chunk.code()[..2].to_vec();
+        let lines = chunk.lines()[..2].to_vec();
+        let chunk = Chunk::from_raw(String::from("main"), code, lines);
+        assert!(matches!(kind(&chunk), Some(VerifyErrorKind::IllegalInstruction(_))));
+    }
+}
@@ -4,8 +4,9 @@ mod lifetime;
 mod r#struct;
 mod r#enum;
 mod types;
-mod function;
+pub mod function;
+pub mod span;
 mod variable;
 mod tuple;
-mod compiler;
-mod error;
+pub mod compiler;
+pub mod error;
@@ -1,26 +1,381 @@
 use std::collections::hash_map::Entry;
 use std::collections::HashMap;
-use crate::bytecode::chunk::Chunk;
-use crate::lang::error::CompileResult;
-use crate::lang::variable::{DataLoc, FerrumVariable, VarLoc};
+use crate::bytecode::chunk::{Chunk, CodePos};
+use crate::bytecode::opcode::{NumeralType, OpCode};
+use crate::bytecode::program::Program;
+use crate::lang::error::{CompileError, CompileResult};
+use crate::lang::expr::{int_value, Block, Expr, ExprKind, NumType, Stat, StatKind, Trail};
+use crate::lang::lifetime::LifeTime;
+use crate::lang::span::{LineIndex, Span, Spanned};
+use crate::lang::types::FerrumType;
+use crate::lang::variable::{DataLoc, DataSource, FerrumVariable, VarLoc};
+use crate::vm::native::{NativeRegistry, NativeSignature};
+use crate::vm::VMError;
 
-pub struct FerrumCompiler {
+pub struct FerrumCompiler<'a> {
     chunk: Chunk,
     scopes: Vec<StackScope>,
+    /// line index of the source code the compiled function is defined in
+    lines: LineIndex,
+    /// native functions that calls are resolved against
+    natives: &'a NativeRegistry,
+    /// program the compiled function belongs to, which holds the native import table
+    program: &'a mut Program,
 }
 
 struct StackScope {
     vars: HashMap<String, Vec<FerrumVariable>>,
+    /// Numeral types of the variables that are visible in the scope
+    types: HashMap<String, NumeralType>,
+    /// Offset of the first byte of the scope relative to the frame pointer
+    base: usize,
     sp: usize,
+    /// Indices of the debug information of the variables defined in the scope
+    locals: Vec<usize>,
 }
 
-impl FerrumCompiler {
-    pub fn find_global(&self, name: &str) -> Option<&FerrumVariable> {
-        todo!()
+impl<'a> FerrumCompiler<'a> {
+    /// Creates a compiler for the function `name` of `program`. `source` is the source code the
+    /// spans of the compiled AST refer to, and calls are resolved against `natives`.
+    pub fn new(name: String, source: &str, natives: &'a NativeRegistry, program: &'a mut Program) -> Self {
+        FerrumCompiler {
+            chunk: Chunk::new(name),
+            scopes: vec![StackScope::new(0)],
+            lines: LineIndex::new(source),
+            natives,
+            program,
+        }
     }
 
-    pub fn find_global_mut(&mut self, name: &str) -> Option<&mut FerrumVariable> {
-        todo!()
+    /// Finishes the compilation and returns the chunk of the function.
+    pub fn finish(self) -> Chunk {
+        self.chunk
+    }
+
+    /// Returns the chunk the compiler writes to.
+    pub fn chunk(&self) -> &Chunk {
+        &self.chunk
+    }
+
+    /// Returns the line and char position of the span in the source code, as recorded in the
+    /// line table of the chunk.
+    pub fn pos(&self, span: Span) -> CodePos {
+        self.lines.span_pos(span)
+    }
+
+    /// Converts a compile error into a `VMError` that points at the line and char of the source
+    /// code that caused it. Errors without a span have no position.
+    pub fn report(&self, err: CompileError) -> VMError {
+        let pos = err.span().map(|s| self.pos(s));
+        VMError::CompileError(pos, format!("{:?}", err.inner()))
+    }
+
+    /// Writes an instruction attributed to the source code in `span`.
+    fn write(&mut self, op: OpCode, span: Span) {
+        let (line, char) = self.pos(span);
+        self.chunk.write(op, line, char);
+    }
+
+    /// Opens a new stack scope. Data of the new scope is placed right after the data of the
+    /// enclosing scope.
+    pub fn push_scope(&mut self) {
+        let sp = self.scopes.last().map(|s| s.sp).unwrap_or(0);
+        self.scopes.push(StackScope::new(sp));
+    }
+
+    /// Closes the innermost stack scope and writes an instruction that pops its data off the
+    /// stack. The scope of the function itself is never popped, its data is dropped by `Ret`.
+    pub fn pop_scope(&mut self, span: Span) {
+        if self.scopes.len() < 2 {
+            return;
+        }
+        if let Some(scope) = self.scopes.pop() {
+            for i in scope.locals.iter() {
+                self.chunk.end_local(*i);
+            }
+            let size = scope.sp - scope.base;
+            if size > 0 {
+                self.write(OpCode::Pop(size), span);
+            }
+        }
+    }
+
+    /// Binds the value on top of the stack to a new local variable in the innermost scope. The
+    /// value is expected to be located right after the data of the scope, such that it can
+    /// stay where it is. The location of the variable is recorded as debug information in the
+    /// chunk.
+    pub fn define_local(&mut self, name: String, ty: NumeralType, mutable: bool) {
+        let lvl = self.scopes.len();
+        let scope = self.scopes.last_mut()
+            .expect("the scope of the function is never popped");
+        let loc = scope.alloc_data_loc(ty.size());
+        scope.locals.push(self.chunk.begin_local(name.clone(), loc.loc, loc.size));
+        scope.types.insert(name.clone(), ty);
+        scope.add_var(FerrumVariable::new(
+            name,
+            lvl,
+            FerrumType::Elementary(ty.size() as u8),
+            DataSource::Location(loc),
+            mutable,
+            LifeTime::Scoped(lvl, lvl),
+            true,
+        ));
+    }
+
+    /// Returns the numeral type of the local variable `name`.
+    fn local_type(&self, name: &str) -> Option<NumeralType> {
+        self.scopes.iter().rev().find_map(|s| s.types.get(name).copied())
+    }
+
+    /// Writes an instruction that pushes a copy of the local variable `name` onto the stack.
+    pub fn load_local(&mut self, name: &str, span: Span) -> CompileResult<()> {
+        let (loc, size) = self.local_data_loc(name).map_err(|e| e.at(span))?;
+        self.write(OpCode::LoadLocal(loc, size), span);
+        Ok(())
+    }
+
+    /// Writes an instruction that pops the value on top of the stack into the local variable
+    /// `name`.
+    pub fn store_local(&mut self, name: &str, span: Span) -> CompileResult<()> {
+        let var = self.find_var(name, self.scopes.len())
+            .ok_or_else(|| self.unknown_var(name).at(span))?;
+        if !var.is_mutable() {
+            return Err(CompileError::DataNotMutable(var.create_ref()).at(span));
+        }
+        let (loc, size) = self.local_data_loc(name).map_err(|e| e.at(span))?;
+        self.write(OpCode::StoreLocal(loc, size), span);
+        Ok(())
+    }
+
+    /// Returns the frame offset and size of the stack data owned by the local variable `name`.
+    fn local_data_loc(&self, name: &str) -> CompileResult<(usize, usize)> {
+        let var = self.find_var(name, self.scopes.len())
+            .ok_or_else(|| self.unknown_var(name))?;
+        match &var.loc {
+            Some(DataSource::Location(data)) if data.is_stack => Ok((data.loc, data.size)),
+            _ => Err(CompileError::IllegalDataSource(String::from("stack"))),
+        }
+    }
+
+    /// Resolves the path of a called function to a native function of the registry, adds the
+    /// native to the import table of the program and writes an instruction that calls it. The
+    /// arguments of the call are expected on top of the stack. Returns the signature of the
+    /// native, such that the caller can check the arguments and type the returned value. The
+    /// call is attributed to the source code in `span`, an unknown function to the path itself.
+    pub fn call_native(&mut self, path: &Trail, span: Span) -> CompileResult<NativeSignature> {
+        let name = path.path();
+        let signature = self.natives.signature(&name)
+            .ok_or_else(|| CompileError::UnknownFunction(name.clone()).at(path.span()))?
+            .clone();
+        let i = self.program.import_native(&name, signature.clone());
+        self.write(OpCode::CallNative(i), span);
+        Ok(signature)
+    }
+
+    /// Writes the instructions of a function body followed by the `Ret` of its value and returns
+    /// the type of the value. Variables defined by the body live in the scope of the function.
+    pub fn fn_body(&mut self, body: &Block) -> CompileResult<Option<NumeralType>> {
+        for stat in body.content() {
+            self.stat(stat)?;
+        }
+        let ty = match body.return_value() {
+            Some(val) => self.value(val)?,
+            None => None,
+        };
+        self.write(OpCode::Ret(ty.map(|t| t.size()).unwrap_or(0)), body.span());
+        for i in self.scopes[0].locals.iter() {
+            self.chunk.end_local(*i);
+        }
+        Ok(ty)
+    }
+
+    /// Writes the instructions of a statement. Statements leave the stack as they found it,
+    /// except for `let`, which leaves the value of the new variable where it is.
+    pub fn stat(&mut self, stat: &Stat) -> CompileResult<()> {
+        let span = stat.span();
+        match stat.kind() {
+            StatKind::Define(name, val, mutable) => {
+                let ty = self.expr(val)?;
+                self.define_local(name.clone(), ty, *mutable);
+            },
+            StatKind::ExprStat(expr) => match expr.kind() {
+                ExprKind::Assign(var, val) => self.assign(var, val, span)?,
+                _ => {
+                    if let Some(ty) = self.value(expr)? {
+                        self.write(OpCode::Pop(ty.size()), span);
+                    }
+                },
+            },
+            StatKind::Block(block) => {
+                self.push_scope();
+                for stat in block.content() {
+                    self.stat(stat)?;
+                }
+                if let Some(ty) = block.return_value().map(|v| self.value(v)).transpose()?.flatten() {
+                    self.write(OpCode::Pop(ty.size()), span);
+                }
+                self.pop_scope(span);
+            },
+            _ => return Err(CompileError::Unsupported("Statement").at(span)),
+        }
+        Ok(())
+    }
+
+    /// Writes the instructions that store the value of `val` in the local variable `var`.
+    fn assign(&mut self, var: &Expr, val: &Expr, span: Span) -> CompileResult<()> {
+        let ExprKind::Path(path) = var.kind() else {
+            return Err(CompileError::Unsupported("Assignment target").at(var.span()));
+        };
+        let name = path.path();
+        let expected = self.local_type(&name)
+            .ok_or_else(|| self.unknown_var(&name).at(var.span()))?;
+        let ty = self.expr(val)?;
+        if ty != expected {
+            return Err(CompileError::OperandTypeMismatch(ty, expected).at(val.span()));
+        }
+        self.store_local(&name, span)
+    }
+
+    /// Writes the instructions of an expression that may not have a value, like a call of a
+    /// function without return value. Returns the type of the value, if any.
+    fn value(&mut self, expr: &Expr) -> CompileResult<Option<NumeralType>> {
+        match expr.kind() {
+            ExprKind::Call(callee, args) => self.call(callee, args, expr.span()),
+            _ => self.expr(expr).map(Some),
+        }
+    }
+
+    /// Writes the instructions of a call of a native function. The arguments are pushed from
+    /// left to right and checked against the signature of the native. Returns the type of the
+    /// returned value, if any.
+    fn call(&mut self, callee: &Expr, args: &[Expr], span: Span) -> CompileResult<Option<NumeralType>> {
+        let ExprKind::Path(path) = callee.kind() else {
+            return Err(CompileError::Unsupported("Callee").at(callee.span()));
+        };
+        let mut types = Vec::with_capacity(args.len());
+        for arg in args {
+            types.push(self.expr(arg)?);
+        }
+        let signature = self.call_native(path, span)?;
+        if types.len() != signature.params().len() {
+            return Err(CompileError::ArgumentCount(path.path(), signature.params().len(), types.len()).at(span));
+        }
+        for ((ty, param), arg) in types.iter().zip(signature.params()).zip(args) {
+            if ty != param {
+                return Err(CompileError::OperandTypeMismatch(*ty, *param).at(arg.span()));
+            }
+        }
+        Ok(signature.ret())
+    }
+
+    /// Writes the instructions that evaluate the numeric expression `expr` and leave its value
+    /// on top of the stack. Returns the type of the value. Integer literals are lowered through
+    /// `int_lit`, paths load local variables and both operands of arithmetic operators have to
+    /// be of the same type.
+    pub fn expr(&mut self, expr: &Expr) -> CompileResult<NumeralType> {
+        let span = expr.span();
+        match expr.kind() {
+            ExprKind::NumLit(lit, ty) => self.int_lit(lit, ty, false, span),
+            ExprKind::Negate(a) => match a.kind() {
+                ExprKind::NumLit(lit, ty) => self.int_lit(lit, ty, true, span),
+                _ => {
+                    let ty = self.expr(a)?;
+                    self.write(OpCode::Neg(ty), span);
+                    Ok(ty)
+                },
+            },
+            ExprKind::Path(path) => {
+                let name = path.path();
+                self.load_local(&name, span)?;
+                self.local_type(&name).ok_or_else(|| self.unknown_var(&name).at(span))
+            },
+            ExprKind::Call(callee, args) => self.call(callee, args, span)?
+                .ok_or_else(|| CompileError::MissingValue.at(span)),
+            ExprKind::Add(a, b) => self.binary_op(a, b, OpCode::Add, span),
+            ExprKind::Sub(a, b) => self.binary_op(a, b, OpCode::Sub, span),
+            ExprKind::Mul(a, b) => self.binary_op(a, b, OpCode::Mul, span),
+            ExprKind::Div(a, b) => self.binary_op(a, b, OpCode::Div, span),
+            ExprKind::Mod(a, b) => self.binary_op(a, b, OpCode::Rem, span),
+            _ => Err(CompileError::Unsupported("Expression").at(span)),
+        }
+    }
+
+    /// Writes the instructions of both operands followed by the operator `op`.
+    fn binary_op(
+        &mut self,
+        a: &Expr,
+        b: &Expr,
+        op: fn(NumeralType) -> OpCode,
+        span: Span,
+    ) -> CompileResult<NumeralType> {
+        let ty = self.expr(a)?;
+        let other = self.expr(b)?;
+        if ty != other {
+            return Err(CompileError::OperandTypeMismatch(other, ty).at(b.span()));
+        }
+        self.write(op(ty), span);
+        Ok(ty)
+    }
+
+    /// Writes an instruction that pushes the integer literal `lit` with the type suffix `ty`.
+    /// Unsuffixed literals are `i32`, a float suffix turns the literal into a float. `negative` is
+    /// set if the literal is negated right away, which makes e.g. `-128i8` a valid literal.
+    /// Returns the type of the pushed value.
+    pub fn int_lit(&mut self, lit: &str, ty: &NumType, negative: bool, span: Span) -> CompileResult<NumeralType> {
+        let numeral = ty.numeral_type().unwrap_or(NumeralType::I32);
+        let name = match ty {
+            NumType::None => "i32",
+            ty => ty.name(),
+        };
+        let out_of_range = || CompileError::LiteralOutOfRange(lit.to_owned(), name).at(span);
+        let value = int_value(lit).ok_or_else(out_of_range)?;
+        let (neg_max, pos_max) = numeral.int_bounds().unwrap_or((u128::MAX, u128::MAX));
+        if value > if negative { neg_max } else { pos_max } {
+            return Err(out_of_range());
+        }
+
+        // the two's complement of the magnitude, which is in range for every signed type
+        let signed = match negative {
+            true => (value as i128).wrapping_neg(),
+            false => value as i128,
+        };
+        let float = match negative {
+            true => -(value as f64),
+            false => value as f64,
+        };
+        let (line, char) = self.pos(span);
+        match numeral {
+            NumeralType::I8 => self.chunk.write_const(signed as i8, line, char),
+            NumeralType::I16 => self.chunk.write_const(signed as i16, line, char),
+            NumeralType::I32 => self.chunk.write_const(signed as i32, line, char),
+            NumeralType::I64 => self.chunk.write_const(signed as i64, line, char),
+            NumeralType::I128 => self.chunk.write_const(signed, line, char),
+            NumeralType::U8 => self.chunk.write_const(value as u8, line, char),
+            NumeralType::U16 => self.chunk.write_const(value as u16, line, char),
+            NumeralType::U32 => self.chunk.write_const(value as u32, line, char),
+            NumeralType::U64 => self.chunk.write_const(value as u64, line, char),
+            NumeralType::U128 => self.chunk.write_const(value, line, char),
+            NumeralType::F32 => self.chunk.write_const(float as f32, line, char),
+            NumeralType::F64 => self.chunk.write_const(float, line, char),
+        };
+        Ok(numeral)
+    }
+
+    fn unknown_var(&self, name: &str) -> CompileError {
+        CompileError::UnknownVariable(VarLoc {
+            stack_frame: self.scopes.len(),
+            name: name.to_owned(),
+        })
+    }
+
+    pub fn find_global(&self, _name: &str) -> Option<&FerrumVariable> {
+        // globals are not supported yet
+        None
+    }
+
+    pub fn find_global_mut(&mut self, _name: &str) -> Option<&mut FerrumVariable> {
+        // globals are not supported yet
+        None
     }
 
     pub fn find_var(&self, name: &str, lvl: usize) -> Option<&FerrumVariable> {
@@ -82,6 +437,16 @@ impl FerrumCompiler {
 }
 
 impl StackScope {
+    fn new(base: usize) -> Self {
+        StackScope {
+            vars: HashMap::new(),
+            types: HashMap::new(),
+            base,
+            sp: base,
+            locals: Vec::new(),
+        }
+    }
+
     pub fn alloc_data_loc(&mut self, size: usize) -> DataLoc {
         let loc = DataLoc {
             loc: self.sp,
@@ -130,3 +495,145 @@ impl StackScope {
         }
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use crate::bytecode::opcode::{NumeralType, OpCode};
+    use crate::bytecode::program::Program;
+    use crate::lang::compiler::FerrumCompiler;
+    use crate::lang::error::CompileError;
+    use crate::lang::expr::{parser, NumType};
+    use crate::lang::function::FerrumFunctionPtr;
+    use crate::lang::span::Span;
+    use crate::vm::native::{NativeRegistry, NativeSignature, NativeValue};
+    use crate::vm::{VMError, VM};
+
+    /// Compiles the function in `source` into the `main` function of a program.
+    fn compile(source: &str, natives: &NativeRegistry) -> Result<Program, VMError> {
+        let function = parser::function(source).unwrap();
+        let mut program = Program::new();
+        let mut compiler = FerrumCompiler::new(String::from("main"), source, natives, &mut program);
+        compiler.fn_body(function.body()).map_err(|e| compiler.report(e))?;
+        let chunk = compiler.finish();
+        program.add_function(FerrumFunctionPtr::new(String::from("main"), 0), chunk);
+        Ok(program)
+    }
+
+    /// Returns the position and message of a compile error.
+    fn compile_error(source: &str, natives: &NativeRegistry) -> (Option<(u16, u16)>, String) {
+        match compile(source, natives) {
+            Err(VMError::CompileError(pos, msg)) => (pos, msg),
+            Err(e) => panic!("unexpected error {:?}", e),
+            Ok(_) => panic!("expected {:?} to fail", source),
+        }
+    }
+
+    #[test]
+    fn resolve_natives() {
+        let printed = Rc::new(Cell::new(0));
+        let out = printed.clone();
+        let mut natives = NativeRegistry::new();
+        natives.register("std::print", NativeSignature::new(vec![NumeralType::I32], None), move |args| {
+            if let [NativeValue::I32(v)] = args {
+                out.set(*v);
+            }
+            Ok(None)
+        });
+        natives.register("math::abs", NativeSignature::new(vec![NumeralType::I32], Some(NumeralType::I32)), |args| {
+            match args {
+                [NativeValue::I32(v)] => Ok(Some(NativeValue::I32(v.abs()))),
+                _ => Err(String::from("expected an i32")),
+            }
+        });
+
+
+        let errors = [
+            ("fn main() {\n    std::exit(1);\n}", Some((2, 4)), "Function `std::exit` not found"),
+            ("fn main() {\n    std::print(1u8);\n}", Some((2, 15)), "Operand of type U8 where I32 was expected"),
+            ("fn main() {\n    std::print(1, 2);\n}", Some((2, 4)), "Function `std::print` takes 1 arguments but 2 were given"),
+            ("fn main() {\n    let x = std::print(1);\n}", Some((2, 12)), "Expression does not have a value"),
+        ];
+        for (source, pos, msg) in errors {
+            assert_eq!(compile_error(source, &natives), (pos, String::from(msg)));
+        }
+
+        let mut program = Program::new();
+        let compiler = FerrumCompiler::new(String::from("main"), "", &natives, &mut program);
+        assert!(matches!(compiler.report(CompileError::IllegalBorrowState), VMError::CompileError(None, _)));
+
+        let source = "fn main() -> i32 {\n    std::print(40 + 2);\n    math::abs(-7) + 1\n}";
+        let program = compile(source, &natives).unwrap();
+        assert_eq!(program.natives().len(), 2);
+        let mut vm = VM::with_natives(program, natives).unwrap();
+        assert_eq!(vm.run().unwrap().get::<4, i32>(), Some(8));
+        assert_eq!(printed.get(), 42);
+    }
+
+    #[test]
+    fn int_literals() {
+        let natives = NativeRegistry::new();
+        let mut program = Program::new();
+        let mut compiler = FerrumCompiler::new(String::from("main"), "", &natives, &mut program);
+        let span = Span::new(0, 0);
+        assert_eq!(compiler.int_lit("0b1010", &NumType::None, false, span).unwrap(), NumeralType::I32);
+        compiler.int_lit("0o17", &NumType::None, false, span).unwrap();
+        compiler.chunk.write(OpCode::Add(NumeralType::I32), 1, 0);
+        compiler.int_lit("0x80", &NumType::I8, true, span).unwrap();
+        compiler.chunk.write(OpCode::Cast(NumeralType::I8, NumeralType::I32), 1, 0);
+        compiler.chunk.write(OpCode::Add(NumeralType::I32), 1, 0);
+        compiler.chunk.write(OpCode::Ret(4), 1, 0);
+
+        for (lit, ty, negative) in [("300", NumType::U8, false), ("128", NumType::I8, false), ("1", NumType::U32, true)] {
+            match compiler.int_lit(lit, &ty, negative, span).map_err(|e| e.to_string()) {
+                Err(e) => assert_eq!(e, format!("Literal `{}` is out of range for {} at 0..0", lit, ty.name())),
+                Ok(_) => panic!("expected {}{} to be out of range", lit, ty.name()),
+            }
+        }
+        assert!(compiler.int_lit("0x1_0000_0000_0000_0000_0000_0000_0000_0000", &NumType::U128, false, span).is_err());
+
+        let chunk = compiler.finish();
+        program.add_function(FerrumFunctionPtr::new(String::from("main"), 0), chunk);
+        let mut vm = VM::new(program).unwrap();
+        assert_eq!(vm.run().unwrap().get::<4, i32>(), Some(10 + 15 - 128));
+    }
+
+    #[test]
+    fn lower_int_literals() {
+        let natives = NativeRegistry::new();
+        let source = "fn main() -> i32 { 0x7f_i8 * -1i8 - -128i8 + 2 / 1i8 }";
+        assert_eq!(compile_error(source, &natives), (Some((1, 49)), String::from("Operand of type I8 where I32 was expected")));
+
+        let source = "fn main() -> i8 { (0x7f_i8 * -1i8 - -128i8) + 2i8 % 0b11_i8 }";
+        let mut vm = VM::new(compile(source, &natives).unwrap()).unwrap();
+        assert_eq!(vm.run().unwrap().get::<1, i8>(), Some(-127 - -128 + 2));
+    }
+
+    #[test]
+    fn lower_locals() {
+        let natives = NativeRegistry::new();
+        let source = "fn main() -> i32 {
+    let mut x = 40;
+    {
+        let y = 2;
+        x = x + y;
+    }
+    let z = 1u8;
+    x
+}";
+        let program = compile(source, &natives).unwrap();
+        let locals: Vec<_> = program.chunk(0).unwrap().locals().iter()
+            .map(|l| (l.name(), l.offset(), l.size(), l.end().is_some()))
+            .collect();
+        assert_eq!(locals, [("x", 0, 4, true), ("y", 4, 4, true), ("z", 4, 1, true)]);
+        let mut vm = VM::new(program).unwrap();
+        assert_eq!(vm.run().unwrap().get::<4, i32>(), Some(42));
+
+        let source = "fn main() {\n    let x = 1;\n    x = 2;\n}";
+        let (pos, msg) = compile_error(source, &natives);
+        assert_eq!(pos, Some((3, 4)));
+        assert!(msg.starts_with("Variable"), "{}", msg);
+    }
+}
@@ -1,5 +1,7 @@
 use std::error::Error;
 use std::fmt::{Debug, Display, format, Formatter, Write};
+use crate::bytecode::opcode::NumeralType;
+use crate::lang::span::Span;
 use crate::lang::types::FerrumType;
 use crate::lang::variable::VarLoc;
 
@@ -15,10 +17,51 @@ pub enum CompileError {
     VariableNotInitialized(VarLoc),
     ModifiedBorrowedData(VarLoc),
     UnknownVariable(VarLoc),
+    UnknownFunction(String),
+    /// Integer literal that does not fit into its type
+    LiteralOutOfRange(String, &'static str),
+    /// Operands of an operator with different types, the found and the expected one
+    OperandTypeMismatch(NumeralType, NumeralType),
+    /// Call of the function with the expected and the given number of arguments
+    ArgumentCount(String, usize, usize),
+    /// Expression without a value, e.g. a call of a function without return value, where a value
+    /// is expected
+    MissingValue,
+    /// Kind of source code that cannot be compiled yet, e.g. `Statement`
+    Unsupported(&'static str),
+    /// Error caused by the source code in the span
+    At(Span, Box<CompileError>),
 }
 
 pub type CompileResult<T> = Result<T, CompileError>;
 
+impl CompileError {
+    /// Attributes the error to the source code in `span`. Errors that already have a span keep
+    /// their original, more precise one.
+    pub fn at(self, span: Span) -> Self {
+        match self {
+            CompileError::At(_, _) => self,
+            e => CompileError::At(span, Box::new(e)),
+        }
+    }
+
+    /// Returns the span of the source code that caused the error, if it is known.
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            CompileError::At(span, _) => Some(*span),
+            _ => None,
+        }
+    }
+
+    /// Returns the error without its span.
+    pub fn inner(&self) -> &CompileError {
+        match self {
+            CompileError::At(_, e) => e.inner(),
+            e => e,
+        }
+    }
+}
+
 impl Debug for CompileError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -55,13 +98,34 @@ impl Debug for CompileError {
             CompileError::UnknownVariable(v) => {
                 f.write_str(&format!("Variable {v:?} not found in current scope"))
             }
+            CompileError::UnknownFunction(p) => {
+                f.write_str(&format!("Function `{p}` not found"))
+            }
+            CompileError::LiteralOutOfRange(lit, ty) => {
+                f.write_str(&format!("Literal `{lit}` is out of range for {ty}"))
+            }
+            CompileError::OperandTypeMismatch(got, exp) => {
+                f.write_str(&format!("Operand of type {got:?} where {exp:?} was expected"))
+            }
+            CompileError::ArgumentCount(p, exp, got) => {
+                f.write_str(&format!("Function `{p}` takes {exp} arguments but {got} were given"))
+            }
+            CompileError::MissingValue => {
+                f.write_str("Expression does not have a value")
+            }
+            CompileError::Unsupported(what) => {
+                f.write_str(&format!("{what} is not supported yet"))
+            }
+            CompileError::At(span, e) => {
+                f.write_str(&format!("{e:?} at {}..{}", span.start, span.end))
+            }
         }
     }
 }
 
 impl Display for CompileError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        todo!()
+        Debug::fmt(self, f)
     }
 }
 
@@ -1,4 +1,6 @@
 use std::str::FromStr;
+use crate::bytecode::opcode::NumeralType;
+use crate::lang::span::{Span, Spanned};
 
 #[derive(Debug)]
 pub enum NumType {
@@ -38,6 +40,37 @@ impl NumType {
             NumType::None => "",
         }
     }
+
+    /// Returns the bytecode type of values with this suffix, or `None` for unsuffixed literals.
+    pub fn numeral_type(&self) -> Option<NumeralType> {
+        match self {
+            NumType::I8 => Some(NumeralType::I8),
+            NumType::I16 => Some(NumeralType::I16),
+            NumType::I32 => Some(NumeralType::I32),
+            NumType::I64 => Some(NumeralType::I64),
+            NumType::I128 => Some(NumeralType::I128),
+            NumType::U8 => Some(NumeralType::U8),
+            NumType::U16 => Some(NumeralType::U16),
+            NumType::U32 => Some(NumeralType::U32),
+            NumType::U64 => Some(NumeralType::U64),
+            NumType::U128 => Some(NumeralType::U128),
+            NumType::F32 => Some(NumeralType::F32),
+            NumType::F64 => Some(NumeralType::F64),
+            NumType::None => None,
+        }
+    }
+}
+
+/// Returns the value of an integer literal as written in the source, e.g. `1_000`, `0xFF`, `0o17`
+/// or `0b1010`. Returns `None` if the value does not even fit into a `u128`.
+pub fn int_value(lit: &str) -> Option<u128> {
+    let (digits, radix) = match lit.get(..2) {
+        Some("0x") => (&lit[2..], 16),
+        Some("0o") => (&lit[2..], 8),
+        Some("0b") => (&lit[2..], 2),
+        _ => (lit, 10),
+    };
+    u128::from_str_radix(&digits.replace('_', ""), radix).ok()
 }
 
 impl TryFrom<&str> for NumType {
@@ -70,32 +103,42 @@ impl TryFrom<&str> for NumType {
 
 #[derive(Debug)]
 struct EnumMember {
+    /// lines of the doc comment in front of the member
+    docs: Vec<String>,
     name: String,
     args: Vec<Type>,
+    span: Span,
 }
 
 #[derive(Debug)]
 pub struct Enum {
+    docs: Vec<String>,
     name: String,
-    m: Vec<EnumMember>
+    m: Vec<EnumMember>,
+    span: Span,
 }
 
 #[derive(Debug)]
 struct StructMember {
+    docs: Vec<String>,
     name: String,
     ty: Type,
+    span: Span,
 }
 
 #[derive(Debug)]
 pub struct Struct {
+    docs: Vec<String>,
     name: String,
     m: Vec<StructMember>,
+    span: Span,
 }
 
 #[derive(Debug)]
 pub struct Function {
     header: FnHeader,
     body: Block,
+    span: Span,
 }
 
 #[derive(Debug)]
@@ -103,13 +146,16 @@ struct FnArg {
     name: String,
     ty: Type,
     md: bool,
+    span: Span,
 }
 
 #[derive(Debug)]
 pub struct FnHeader {
+    docs: Vec<String>,
     name: String,
     args: Vec<FnArg>,
     ret_val: Option<Type>,
+    span: Span,
 }
 
 #[derive(Debug)]
@@ -120,29 +166,111 @@ pub struct Impl {
     ty: Type,
     /// implemented functions
     fns: Vec<Function>,
+    span: Span,
 }
 
 #[derive(Debug)]
 pub struct Trait {
+    docs: Vec<String>,
     /// name of the trait
     name: String,
     /// declared function headers
     fns: Vec<FnHeader>,
+    span: Span,
+}
+
+/// Source file with its items. `docs` holds the inner doc comment (`//!`) at the top of the file.
+#[derive(Debug)]
+pub struct Module {
+    docs: Vec<String>,
+    items: Vec<Item>,
+    span: Span,
+}
+
+#[derive(Debug)]
+pub enum Item {
+    Enum(Enum),
+    Struct(Struct),
+    Trait(Trait),
+    Function(Box<Function>),
+}
+
+impl Module {
+    pub fn docs(&self) -> &[String] {
+        &self.docs
+    }
+
+    pub fn items(&self) -> &[Item] {
+        &self.items
+    }
+}
+
+impl Item {
+    /// Returns the lines of the doc comment in front of the item, without the leading `///`.
+    pub fn docs(&self) -> &[String] {
+        match self {
+            Item::Enum(e) => &e.docs,
+            Item::Struct(s) => &s.docs,
+            Item::Trait(t) => &t.docs,
+            Item::Function(f) => &f.header.docs,
+        }
+    }
+}
+
+impl Function {
+    pub fn header(&self) -> &FnHeader {
+        &self.header
+    }
+
+    pub fn body(&self) -> &Block {
+        &self.body
+    }
 }
 
 #[derive(Debug)]
 pub struct Block {
     content: Vec<Stat>,
     return_value: Option<Expr>,
+    span: Span,
+}
+
+impl Block {
+    pub fn content(&self) -> &[Stat] {
+        &self.content
+    }
+
+    /// Returns the expression the block evaluates to, i.e. its last expression without a `;`.
+    pub fn return_value(&self) -> Option<&Expr> {
+        self.return_value.as_ref()
+    }
 }
 
 #[derive(Debug)]
 pub struct Return {
     val: Expr,
+    span: Span,
+}
+
+#[derive(Debug)]
+pub struct If {
+    kind: IfKind,
+    span: Span,
+}
+
+impl If {
+    pub fn kind(&self) -> &IfKind {
+        &self.kind
+    }
+
+    /// Wraps an `else` block, which spans the same source as the block itself.
+    fn else_block(block: Block) -> Self {
+        let span = block.span;
+        If { kind: IfKind::Else(block), span }
+    }
 }
 
 #[derive(Debug)]
-pub enum If {
+pub enum IfKind {
     If(Expr, Block, Option<Box<If>>),
     Else(Block),
 }
@@ -150,23 +278,49 @@ pub enum If {
 #[derive(Debug)]
 pub struct Trail {
     head: String,
-    trail: Vec<String>
+    trail: Vec<String>,
+    span: Span,
+}
+
+impl Trail {
+    /// Creates a path that does not originate from source code and therefore has an empty span.
+    pub fn new(head: String, trail: Vec<String>) -> Self {
+        Trail { head, trail, span: Span::default() }
+    }
+
+    /// Returns the full path with its segments separated by `::`, e.g. `std::print`.
+    pub fn path(&self) -> String {
+        let mut path = self.head.clone();
+        for seg in self.trail.iter() {
+            path.push_str("::");
+            path.push_str(seg);
+        }
+        path
+    }
 }
 
 #[derive(Debug)]
 pub struct Match<T> {
     expr: Expr,
     cases: Vec<MatchBranch<T>>,
+    span: Span,
 }
 
 #[derive(Debug)]
 struct MatchBranch<T> {
     case: MatchCase,
     block: T,
+    span: Span,
 }
 
 #[derive(Debug)]
-enum MatchCase {
+struct MatchCase {
+    kind: MatchCaseKind,
+    span: Span,
+}
+
+#[derive(Debug)]
+enum MatchCaseKind {
     /// Matches against literals / constants
     Literal(Expr),
     /// Match branch with multiple cases
@@ -181,11 +335,26 @@ enum MatchCase {
 }
 
 #[derive(Debug)]
-pub enum Expr {
+pub struct Expr {
+    kind: ExprKind,
+    span: Span,
+}
+
+impl Expr {
+    pub fn kind(&self) -> &ExprKind {
+        &self.kind
+    }
+}
+
+#[derive(Debug)]
+pub enum ExprKind {
+    /// String literal with its escapes resolved
     Literal(String),
+    ByteStrLit(Vec<u8>),
     NumLit(String, NumType),
     FloatLit(String, NumType),
     CharLit(char),
+    ByteLit(u8),
     BoolLit(bool),
 
     Path(Trail),
@@ -251,7 +420,19 @@ pub enum Expr {
 }
 
 #[derive(Debug)]
-pub enum Stat {
+pub struct Stat {
+    kind: StatKind,
+    span: Span,
+}
+
+impl Stat {
+    pub fn kind(&self) -> &StatKind {
+        &self.kind
+    }
+}
+
+#[derive(Debug)]
+pub enum StatKind {
     Define(String, Box<Expr>, bool),
     ExprStat(Expr),
     Return(Return),
@@ -266,7 +447,19 @@ pub enum Stat {
 }
 
 #[derive(Debug)]
-pub enum Type {
+pub struct Type {
+    kind: TypeKind,
+    span: Span,
+}
+
+impl Type {
+    pub fn kind(&self) -> &TypeKind {
+        &self.kind
+    }
+}
+
+#[derive(Debug)]
+pub enum TypeKind {
     None,
     Plain(Trail),
     Ref(Box<Type>),
@@ -276,243 +469,612 @@ pub enum Type {
     Tuple(Vec<Type>),
 }
 
+/// Implements `Spanned` for AST nodes that store their span in a `span` field.
+macro_rules! impl_spanned {
+    ($($node:ty),*) => {
+        $(
+            impl Spanned for $node {
+                fn span(&self) -> Span {
+                    self.span
+                }
+            }
+        )*
+    };
+}
+
+impl_spanned!(
+    EnumMember, Enum, StructMember, Struct, Function, FnArg, FnHeader, Impl, Trait, Module, Block,
+    Return, If, Trail, MatchCase, Expr, Stat, Type
+);
+
+impl<T> Spanned for Match<T> {
+    fn span(&self) -> Span {
+        self.span
+    }
+}
+
+impl<T> Spanned for MatchBranch<T> {
+    fn span(&self) -> Span {
+        self.span
+    }
+}
+
 
 peg::parser!(pub grammar parser() for str {
 
+    pub rule module() -> Module
+        = __ start:position!() docs:(d:inner_doc_comment() __ {d})* items:(i:item() __ {i})* end:position!() _ { Module { docs, items, span: Span::new(start, end) } }
+
+    rule item() -> Item
+        = e:enum_def() { Item::Enum(e) }
+        / s:struct_def() { Item::Struct(s) }
+        / t:tr() { Item::Trait(t) }
+        / f:function() { Item::Function(Box::new(f)) }
+
     pub rule enum_def() -> Enum
-        = _ "enum" _ name:identifier() _ "{" _ m:((_ mem:enum_member() _ {mem}) ** ",") _ "," _ "}" _ { Enum { name, m } }
-        / _ "enum" _ name:identifier() _ "{" _ m:((_ mem:enum_member() _ {mem}) ** ",") _ "}" _ { Enum { name, m } }
+        = __ docs:doc_comments() start:position!() "enum" _ name:identifier() _ "{" m:((__ mem:enum_member() _ {mem}) ** ",") _ "," _ "}" end:position!() __ { Enum { docs, name, m, span: Span::new(start, end) } }
+        / __ docs:doc_comments() start:position!() "enum" _ name:identifier() _ "{" m:((__ mem:enum_member() _ {mem}) ** ",") _ "}" end:position!() __ { Enum { docs, name, m, span: Span::new(start, end) } }
 
     rule enum_member() -> EnumMember
-        = name:identifier() _ "(" _ args:((_ t:ty() _ {t}) ** ",") _ ")" { EnumMember{ name, args } }
-        / name:identifier() { EnumMember{ name, args: Vec::new() } }
+        = docs:doc_comments() start:position!() name:identifier() _ "(" _ args:((_ t:ty() _ {t}) ** ",") _ ")" end:position!() { EnumMember{ docs, name, args, span: Span::new(start, end) } }
+        / docs:doc_comments() start:position!() name:identifier() end:position!() { EnumMember{ docs, name, args: Vec::new(), span: Span::new(start, end) } }
 
     pub rule struct_def() -> Struct
-        = _ "struct" _ name:identifier() _ "{" _ m:((_ field:identifier() _ ":" _ ty:ty() _ { StructMember { name: field, ty } }) ** ",") _ "," _ "}" _ { Struct { name, m } }
-        / _ "struct" _ name:identifier() _ "{" _ m:((_ field:identifier() _ ":" _ ty:ty() _ { StructMember { name: field, ty } }) ** ",") _ "}" _ { Struct { name, m } }
+        = __ docs:doc_comments() start:position!() "struct" _ name:identifier() _ "{" m:((__ field:struct_member() _ {field}) ** ",") _ "," _ "}" end:position!() __ { Struct { docs, name, m, span: Span::new(start, end) } }
+        / __ docs:doc_comments() start:position!() "struct" _ name:identifier() _ "{" m:((__ field:struct_member() _ {field}) ** ",") _ "}" end:position!() __ { Struct { docs, name, m, span: Span::new(start, end) } }
+
+    rule struct_member() -> StructMember
+        = docs:doc_comments() start:position!() name:identifier() _ ":" _ ty:ty() end:position!() { StructMember { docs, name, ty, span: Span::new(start, end) } }
 
     pub rule tr() -> Trait
-        = _ "trait" _ name:identifier() _ "{" _ fns:((_ header:fn_header() _ ";" _ {header}) ** _) "}" _ { Trait { name, fns } }
+        = __ docs:doc_comments() start:position!() "trait" _ name:identifier() _ "{" fns:(__ header:fn_header() _ ";" {header})* _ "}" end:position!() __ { Trait { docs, name, fns, span: Span::new(start, end) } }
 
     pub rule function() -> Function
-        = _ header:fn_header() body:fn_block() { Function { header, body } }
+        = __ header:fn_header() body:fn_block() { Function { span: header.span.join(body.span), header, body } }
 
     rule fn_header() -> FnHeader
-        = "fn" _ name:identifier() _ "(" args:((_ arg:fn_arg() _ { arg }) ** ",") ")" _ "->" _ ret_val:ty() { FnHeader{ name, args, ret_val:Some(ret_val) } }
-        / "fn" _ name:identifier() _ "(" args:((_ arg:fn_arg() _ { arg }) ** ",") ")" { FnHeader{ name, args, ret_val: None } }
+        = docs:doc_comments() start:position!() "fn" _ name:identifier() _ "(" args:((_ arg:fn_arg() _ { arg }) ** ",") ")" _ "->" _ ret_val:ty() end:position!() { FnHeader{ docs, name, args, ret_val:Some(ret_val), span: Span::new(start, end) } }
+        / docs:doc_comments() start:position!() "fn" _ name:identifier() _ "(" args:((_ arg:fn_arg() _ { arg }) ** ",") ")" end:position!() { FnHeader{ docs, name, args, ret_val: None, span: Span::new(start, end) } }
 
     rule fn_arg() -> FnArg
-        = "mut" _ name:identifier() _ ":" _ t:ty() { FnArg { name, ty: t, md: true } }
-        / name:identifier() _ ":" _ t:ty() { FnArg { name, ty: t, md: false } }
+        = start:position!() "mut" _ name:identifier() _ ":" _ t:ty() end:position!() { FnArg { name, ty: t, md: true, span: Span::new(start, end) } }
+        / start:position!() name:identifier() _ ":" _ t:ty() end:position!() { FnArg { name, ty: t, md: false, span: Span::new(start, end) } }
 
     rule expression() -> Expr = precedence!{
-        a:@ _ "&=" _ b:(@) { Expr::AssignAnd(Box::new(a), Box::new(b)) }
-        a:@ _ "|=" _ b:(@) { Expr::AssignOr(Box::new(a), Box::new(b)) }
-        a:@ _ "^=" _ b:(@) { Expr::AssignXor(Box::new(a), Box::new(b)) }
-        a:@ _ "<<=" _ b:(@) { Expr::AssignLShift(Box::new(a), Box::new(b)) }
-        a:@ _ ">>=" _ b:(@) { Expr::AssignRShift(Box::new(a), Box::new(b)) }
+        start:position!() kind:@ end:position!() { Expr { kind, span: Span::new(start, end) } }
+        --
+        a:@ _ "&=" _ b:(@) { ExprKind::AssignAnd(Box::new(a), Box::new(b)) }
+        a:@ _ "|=" _ b:(@) { ExprKind::AssignOr(Box::new(a), Box::new(b)) }
+        a:@ _ "^=" _ b:(@) { ExprKind::AssignXor(Box::new(a), Box::new(b)) }
+        a:@ _ "<<=" _ b:(@) { ExprKind::AssignLShift(Box::new(a), Box::new(b)) }
+        a:@ _ ">>=" _ b:(@) { ExprKind::AssignRShift(Box::new(a), Box::new(b)) }
         --
-        a:@ _ "=" _ b:(@) { Expr::Assign(Box::new(a), Box::new(b)) }
-        a:@ _ "+=" _ b:(@) { Expr::AssignAdd(Box::new(a), Box::new(b)) }
-        a:@ _ "-=" _ b:(@) { Expr::AssignSub(Box::new(a), Box::new(b)) }
-        a:@ _ "*=" _ b:(@) { Expr::AssignMul(Box::new(a), Box::new(b)) }
-        a:@ _ "/=" _ b:(@) { Expr::AssignDiv(Box::new(a), Box::new(b)) }
-        a:@ _ "%=" _ b:(@) { Expr::AssignMod(Box::new(a), Box::new(b)) }
+        a:@ _ "=" _ b:(@) { ExprKind::Assign(Box::new(a), Box::new(b)) }
+        a:@ _ "+=" _ b:(@) { ExprKind::AssignAdd(Box::new(a), Box::new(b)) }
+        a:@ _ "-=" _ b:(@) { ExprKind::AssignSub(Box::new(a), Box::new(b)) }
+        a:@ _ "*=" _ b:(@) { ExprKind::AssignMul(Box::new(a), Box::new(b)) }
+        a:@ _ "/=" _ b:(@) { ExprKind::AssignDiv(Box::new(a), Box::new(b)) }
+        a:@ _ "%=" _ b:(@) { ExprKind::AssignMod(Box::new(a), Box::new(b)) }
         --
-        a:@ _ "||" _ b:(@) { Expr::LOr(Box::new(a), Box::new(b)) }
+        a:(@) _ "||" _ b:@ { ExprKind::LOr(Box::new(a), Box::new(b)) }
         --
-        a:@ _ "&&" _ b:(@) { Expr::LAnd(Box::new(a), Box::new(b)) }
+        a:(@) _ "&&" _ b:@ { ExprKind::LAnd(Box::new(a), Box::new(b)) }
         --
-        a:@ _ "==" _ b:(@) { Expr::Eq(Box::new(a), Box::new(b)) }
-        a:@ _ "!=" _ b:(@) { Expr::Ne(Box::new(a), Box::new(b)) }
-        a:@ _ "<"  _ b:(@) { Expr::Lt(Box::new(a), Box::new(b)) }
-        a:@ _ ">"  _ b:(@) { Expr::Gt(Box::new(a), Box::new(b)) }
-        a:@ _ "<=" _ b:(@) { Expr::Le(Box::new(a), Box::new(b)) }
-        a:@ _ ">=" _ b:(@) { Expr::Ge(Box::new(a), Box::new(b)) }
+        a:(@) _ "==" _ b:@ { ExprKind::Eq(Box::new(a), Box::new(b)) }
+        a:(@) _ "!=" _ b:@ { ExprKind::Ne(Box::new(a), Box::new(b)) }
+        a:(@) _ "<"  _ b:@ { ExprKind::Lt(Box::new(a), Box::new(b)) }
+        a:(@) _ ">"  _ b:@ { ExprKind::Gt(Box::new(a), Box::new(b)) }
+        a:(@) _ "<=" _ b:@ { ExprKind::Le(Box::new(a), Box::new(b)) }
+        a:(@) _ ">=" _ b:@ { ExprKind::Ge(Box::new(a), Box::new(b)) }
         --
-        a:@ _ "|" _ b:(@) { Expr::Or(Box::new(a), Box::new(b)) }
+        a:(@) _ "|" _ b:@ { ExprKind::Or(Box::new(a), Box::new(b)) }
         --
-        a:@ _ "^" _ b:(@) { Expr::Xor(Box::new(a), Box::new(b)) }
+        a:(@) _ "^" _ b:@ { ExprKind::Xor(Box::new(a), Box::new(b)) }
         --
-        a:@ _ "&" _ b:(@) { Expr::And(Box::new(a), Box::new(b)) }
+        a:(@) _ "&" _ b:@ { ExprKind::And(Box::new(a), Box::new(b)) }
         --
-        a:@ _ "<<" _ b:(@) { Expr::LShift(Box::new(a), Box::new(b)) }
-        a:@ _ ">>" _ b:(@) { Expr::RShift(Box::new(a), Box::new(b)) }
+        a:(@) _ "<<" _ b:@ { ExprKind::LShift(Box::new(a), Box::new(b)) }
+        a:(@) _ ">>" _ b:@ { ExprKind::RShift(Box::new(a), Box::new(b)) }
         --
-        a:@ _ "+" _ b:(@) { Expr::Add(Box::new(a), Box::new(b)) }
-        a:@ _ "-" _ b:(@) { Expr::Sub(Box::new(a), Box::new(b)) }
+        a:(@) _ "+" _ b:@ { ExprKind::Add(Box::new(a), Box::new(b)) }
+        a:(@) _ "-" _ b:@ { ExprKind::Sub(Box::new(a), Box::new(b)) }
         --
-        a:@ _ "*" _ b:(@) { Expr::Mul(Box::new(a), Box::new(b)) }
-        a:@ _ "/" _ b:(@) { Expr::Div(Box::new(a), Box::new(b)) }
-        a:@ _ "%" _ b:(@) { Expr::Mod(Box::new(a), Box::new(b)) }
+        a:(@) _ "*" _ b:@ { ExprKind::Mul(Box::new(a), Box::new(b)) }
+        a:(@) _ "/" _ b:@ { ExprKind::Div(Box::new(a), Box::new(b)) }
+        a:(@) _ "%" _ b:@ { ExprKind::Mod(Box::new(a), Box::new(b)) }
         --
-        a:@ _ "as" _ ty:ty() { Expr::Cast(Box::new(a), ty) }
+        a:@ _ "as" _ ty:ty() { ExprKind::Cast(Box::new(a), ty) }
         --
-        "-" _ a:@ { Expr::Negate(Box::new(a)) }
-        "*" _ a:@ { Expr::Deref(Box::new(a)) }
-        "!" _ a:@ { Expr::Not(Box::new(a)) }
-        "~" _ a:@ { Expr::Inv(Box::new(a)) }
-        "&" _ a:@ { Expr::Ref(Box::new(a)) }
-        "&" _ "mut" _ a:@ { Expr::RefMut(Box::new(a)) }
+        "-" _ a:@ { ExprKind::Negate(Box::new(a)) }
+        "*" _ a:@ { ExprKind::Deref(Box::new(a)) }
+        "!" _ a:@ { ExprKind::Not(Box::new(a)) }
+        "~" _ a:@ { ExprKind::Inv(Box::new(a)) }
+        "&" _ a:@ { ExprKind::Ref(Box::new(a)) }
+        "&" _ "mut" _ a:@ { ExprKind::RefMut(Box::new(a)) }
         --
-        a:@ _ "?" { Expr::Unwrap(Box::new(a)) }
+        a:@ _ "?" { ExprKind::Unwrap(Box::new(a)) }
         --
         n:num_lit() { n }
         --
-        a:@ _ ".." _ b:(@) { Expr::Range(Box::new(a), Box::new(b)) }
-        a:@ _ "[" _ index:expression() "]" { Expr::Index(Box::new(a), Box::new(index)) }
+        a:@ _ ".." _ b:(@) { ExprKind::Range(Box::new(a), Box::new(b)) }
+        a:@ _ "[" _ index:expression() "]" { ExprKind::Index(Box::new(a), Box::new(index)) }
         --
-        a:@ _ "." _ name:identifier() { Expr::DotOp(Box::new(a), name) }
-        i:@ _ "(" args:((_ e:expression() _ {e}) ** ",") ")" { Expr::Call(Box::new(i), args) }
-        "(" _ e:expression() _ ")" { e }
-        "(" _ content:((_ e:expression() _ {e}) ++ ",") ")" { Expr::Tuple(content) }
-        "(" _ content:((_ e:expression() _ {e}) ++ ",") "," _ ")" { Expr::Tuple(content) }
-        "[" cont:((_ e:expression() _ {e}) ** ",") "]" { Expr::ArrayExplicit(cont) }
-        "[" _ val:expression() _ ";" _ len:expression() _ "]" { Expr::ArrayInit(Box::new(val), Box::new(len)) }
+        a:@ _ "." _ name:identifier() { ExprKind::DotOp(Box::new(a), name) }
+        i:@ _ "(" args:((_ e:expression() _ {e}) ** ",") ")" { ExprKind::Call(Box::new(i), args) }
+        "(" _ e:expression() _ ")" { e.kind }
+        "(" _ content:((_ e:expression() _ {e}) ++ ",") ")" { ExprKind::Tuple(content) }
+        "(" _ content:((_ e:expression() _ {e}) ++ ",") "," _ ")" { ExprKind::Tuple(content) }
+        "[" cont:((_ e:expression() _ {e}) ** ",") "]" { ExprKind::ArrayExplicit(cont) }
+        "[" _ val:expression() _ ";" _ len:expression() _ "]" { ExprKind::ArrayInit(Box::new(val), Box::new(len)) }
         s:struct_init() { s }
-        i:if_expr() { Expr::If(Box::new(i)) }
-        m:match_expr() { Expr::Match(Box::new(m)) }
-        e:exp_block() { Expr::Block(Box::new(e)) }
+        i:if_expr() { ExprKind::If(Box::new(i)) }
+        m:match_expr() { ExprKind::Match(Box::new(m)) }
+        e:exp_block() { ExprKind::Block(Box::new(e)) }
         b:bool_lit() { b }
-        p:path() { Expr::Path(p) }
         l:str_lit() { l }
         l:char_lit() { l }
+        p:path() { ExprKind::Path(p) }
     }
 
     rule match_expr() -> Match<Expr>
-        = "match" _ arg:expression() _ "{" _ branches:((_ case:match_case() _ "=>" _ expr:expression() _ {MatchBranch { case, block:expr }}) ** ",") _ "," _ "}" { Match{expr:arg, cases:branches} }
-        / "match" _ arg:expression() _ "{" _ branches:((_ case:match_case() _ "=>" _ expr:expression() _ {MatchBranch { case, block:expr }}) ** ",") _ "}" { Match{expr:arg, cases:branches} }
-        / "match" _ arg:expression() _ "{" _ branches:((_ case:match_case() _ "=>" _ expr:expression() _ {MatchBranch { case, block:expr }}) ** _) _ "}" { Match{expr:arg, cases:branches} }
+        = start:position!() "match" _ arg:expression() _ "{" _ branches:((_ b:match_expr_branch() _ {b}) ** ",") _ "," _ "}" end:position!() { Match{expr:arg, cases:branches, span: Span::new(start, end)} }
+        / start:position!() "match" _ arg:expression() _ "{" _ branches:((_ b:match_expr_branch() _ {b}) ** ",") _ "}" end:position!() { Match{expr:arg, cases:branches, span: Span::new(start, end)} }
+        / start:position!() "match" _ arg:expression() _ "{" _ branches:((_ b:match_expr_branch() _ {b}) ** _) _ "}" end:position!() { Match{expr:arg, cases:branches, span: Span::new(start, end)} }
+
+    rule match_expr_branch() -> MatchBranch<Expr>
+        = case:match_case() _ "=>" _ expr:expression() { MatchBranch { span: case.span.join(expr.span), case, block:expr } }
 
     rule match_stat() -> Match<Stat>
-        = "match" _ arg:expression() _ "{" _ branches:((_ case:match_case() _ "=>" _ expr:stat() _ {MatchBranch { case, block:expr }}) ** ",") _ "," _ "}" { Match{expr:arg, cases:branches} }
-        / "match" _ arg:expression() _ "{" _ branches:((_ case:match_case() _ "=>" _ expr:stat() _ {MatchBranch { case, block:expr }}) ** ",") _ "}" { Match{expr:arg, cases:branches} }
-        / "match" _ arg:expression() _ "{" _ branches:((_ case:match_case() _ "=>" _ expr:stat() _ {MatchBranch { case, block:expr }}) ** _) _ "}" { Match{expr:arg, cases:branches} }
+        = start:position!() "match" _ arg:expression() _ "{" _ branches:((_ b:match_stat_branch() _ {b}) ** ",") _ "," _ "}" end:position!() { Match{expr:arg, cases:branches, span: Span::new(start, end)} }
+        / start:position!() "match" _ arg:expression() _ "{" _ branches:((_ b:match_stat_branch() _ {b}) ** ",") _ "}" end:position!() { Match{expr:arg, cases:branches, span: Span::new(start, end)} }
+        / start:position!() "match" _ arg:expression() _ "{" _ branches:((_ b:match_stat_branch() _ {b}) ** _) _ "}" end:position!() { Match{expr:arg, cases:branches, span: Span::new(start, end)} }
+
+    rule match_stat_branch() -> MatchBranch<Stat>
+        = case:match_case() _ "=>" _ stat:stat() { MatchBranch { span: case.span.join(stat.span), case, block:stat } }
 
     rule match_case() -> MatchCase = precedence!{
-        t:ty() _ "(" _ params:((_ c:match_case() _ {c}) ** ",") _ ")" { MatchCase::Data( t, params ) }
-        "(" _ params:((_ c:match_case() _ {c}) ** ",") _ ")" { MatchCase::Tuple( params ) }
-        start:expression() _ ".." _ end:expression() { MatchCase::Range(start, end) }
-        first:@ _ "|" _ cases:((_ c:match_case() _ {c}) ++ "|") { MatchCase::Multi(Box::new(first), cases) }
-        name:identifier() { MatchCase::Param(name) }
-        a:expression() { MatchCase::Literal(a) }
+        start:position!() kind:@ end:position!() { MatchCase { kind, span: Span::new(start, end) } }
+        --
+        t:ty() _ "(" _ params:((_ c:match_case() _ {c}) ** ",") _ ")" { MatchCaseKind::Data( t, params ) }
+        "(" _ params:((_ c:match_case() _ {c}) ** ",") _ ")" { MatchCaseKind::Tuple( params ) }
+        start:expression() _ ".." _ end:expression() { MatchCaseKind::Range(start, end) }
+        first:@ _ "|" _ cases:((_ c:match_case() _ {c}) ++ "|") { MatchCaseKind::Multi(Box::new(first), cases) }
+        name:identifier() { MatchCaseKind::Param(name) }
+        a:expression() { MatchCaseKind::Literal(a) }
     }
 
-    rule struct_init() -> Expr
-        = t:ty() _ "{" _ args:((_ par:struct_parameter() _ {par}) ** ",") _ "," _ "}" { Expr::StructInit( t, args ) }
-        / t:ty() _ "{" _ args:((_ par:struct_parameter() _ {par}) ** ",") _ "}" { Expr::StructInit( t, args ) }
+    rule struct_init() -> ExprKind
+        = t:ty() _ "{" _ args:((_ par:struct_parameter() _ {par}) ** ",") _ "," _ "}" { ExprKind::StructInit( t, args ) }
+        / t:ty() _ "{" _ args:((_ par:struct_parameter() _ {par}) ** ",") _ "}" { ExprKind::StructInit( t, args ) }
 
     rule struct_parameter() -> (String, Expr)
         = name:identifier() _ ":" _ val:expression() { (name, val) }
-        / name:identifier() { (name.clone(), Expr::Path(Trail { head: name, trail: Vec::new() })) }
+        / start:position!() name:identifier() end:position!() {
+            let span = Span::new(start, end);
+            let path = Trail { head: name.clone(), trail: Vec::new(), span };
+            (name, Expr { kind: ExprKind::Path(path), span })
+        }
 
     rule block() -> Block
-        = "{" _ content:(( _ s:stat() _ {s}) ** _ ) _ "}" { Block{ content, return_value: None } }
+        = start:position!() "{" _ content:(( _ s:stat() _ {s}) ** _ ) _ "}" end:position!() { Block{ content, return_value: None, span: Span::new(start, end) } }
         / exp_block()
 
     rule exp_block() -> Block
-        = "{" _ content:(( _ s:stat() _ {s}) ** _ ) ret:expression() _ "}" { Block{content, return_value: Some(ret)} }
+        = start:position!() "{" _ content:(( _ s:stat() _ {s}) ** _ ) ret:expression() _ "}" end:position!() { Block{content, return_value: Some(ret), span: Span::new(start, end)} }
 
     pub rule fn_block() -> Block
-        = _ "{" _ content:(( _ s:stat_noret() _ {s}) ** _) ret:expression() _ "}" _ { Block{content, return_value: Some(ret)} }
-        / _ "{" _ content:(( _ s:stat_noret() _ {s}) ** _) ret:return_val() _ "}" _ { Block{content, return_value: Some(ret.val)} }
-        / _ "{" _ content:(( _ s:stat_noret() _ {s}) ** _) _ "}" _ { Block{content, return_value: None} }
+        = _ start:position!() "{" _ content:(( _ s:stat_noret() _ {s}) ** _) ret:expression() _ "}" end:position!() __ { Block{content, return_value: Some(ret), span: Span::new(start, end)} }
+        / _ start:position!() "{" _ content:(( _ s:stat_noret() _ {s}) ** _) ret:return_val() _ "}" end:position!() __ { Block{content, return_value: Some(ret.val), span: Span::new(start, end)} }
+        / _ start:position!() "{" _ content:(( _ s:stat_noret() _ {s}) ** _) _ "}" end:position!() __ { Block{content, return_value: None, span: Span::new(start, end)} }
 
-    rule while_stat() -> Stat
-        = "while" _ arg:expression() _ block:block() { Stat::While(Box::new(arg), block) }
+    rule while_stat() -> StatKind
+        = "while" _ arg:expression() _ block:block() { StatKind::While(Box::new(arg), block) }
 
-    rule for_stat() -> Stat
-        = "for" _ arg:((_ i:identifier() _ {i})++ ",") _ "in" _ iter:expression() _ body:block() {Stat::For(arg, Box::new(iter), body)}
+    rule for_stat() -> StatKind
+        = "for" _ arg:((_ i:identifier() _ {i})++ ",") _ "in" _ iter:expression() _ body:block() {StatKind::For(arg, Box::new(iter), body)}
 
     rule if_expr() -> If
-        = "if" _ arg:expression() _ block:exp_block() _ "else" _ el:exp_block() { If::If(arg, block, Some(Box::new(If::Else(el)))) }
-        / "if" _ arg:expression() _ block:exp_block() _ "else" _ el:if_expr() { If::If(arg, block, Some(Box::new(el))) }
+        = start:position!() kind:if_expr_kind() end:position!() { If { kind, span: Span::new(start, end) } }
+
+    rule if_expr_kind() -> IfKind
+        = "if" _ arg:expression() _ block:exp_block() _ "else" _ el:exp_block() { IfKind::If(arg, block, Some(Box::new(If::else_block(el)))) }
+        / "if" _ arg:expression() _ block:exp_block() _ "else" _ el:if_expr() { IfKind::If(arg, block, Some(Box::new(el))) }
 
     rule if_stat() -> If
-        = "if" _ arg:expression() _ block:block() _ "else" _ el:if_stat() { If::If(arg, block, Some(Box::new(el))) }
-        / "if" _ arg:expression() _ block:block() _ "else" _ el:block() { If::If(arg, block, Some(Box::new(If::Else(el)))) }
-        / "if" _ arg:expression() _ block:block() { If::If(arg, block, None) }
+        = start:position!() kind:if_stat_kind() end:position!() { If { kind, span: Span::new(start, end) } }
+
+    rule if_stat_kind() -> IfKind
+        = "if" _ arg:expression() _ block:block() _ "else" _ el:if_stat() { IfKind::If(arg, block, Some(Box::new(el))) }
+        / "if" _ arg:expression() _ block:block() _ "else" _ el:block() { IfKind::If(arg, block, Some(Box::new(If::else_block(el)))) }
+        / "if" _ arg:expression() _ block:block() { IfKind::If(arg, block, None) }
 
     rule stat() -> Stat
-        = stat_noret()
-        / return_stat()
+        = start:position!() kind:(stat_noret_kind() / return_stat()) end:position!() { Stat { kind, span: Span::new(start, end) } }
 
     rule stat_noret() -> Stat
+        = start:position!() kind:stat_noret_kind() end:position!() { Stat { kind, span: Span::new(start, end) } }
+
+    rule stat_noret_kind() -> StatKind
         = define()
         / break_stat()
         / while_stat()
         / for_stat()
-        / e:block() { Stat::Block(Box::new(e)) }
-        / i:if_stat() { Stat::If(Box::new(i)) }
-        / m:match_stat() { Stat::Match(Box::new(m)) }
+        / e:block() { StatKind::Block(Box::new(e)) }
+        / i:if_stat() { StatKind::If(Box::new(i)) }
+        / m:match_stat() { StatKind::Match(Box::new(m)) }
         / expr_stat()
 
-    rule break_stat() -> Stat
-        = "break" _ val:expression() _ ";" { Stat::Break(Some(Box::new(val))) }
-        / "break" _ ";" { Stat::Break(None) }
+    rule break_stat() -> StatKind
+        = "break" _ val:expression() _ ";" { StatKind::Break(Some(Box::new(val))) }
+        / "break" _ ";" { StatKind::Break(None) }
 
-    rule continue_stat() -> Stat
-        = "continue" _ ";" { Stat::Continue }
+    rule continue_stat() -> StatKind
+        = "continue" _ ";" { StatKind::Continue }
 
-    rule define() -> Stat
-        = "let" _ "mut" _ name:identifier() _ "=" _ val:expression() _ ";" { Stat::Define(name, Box::new(val), true) }
-        / "let" _ name:identifier() _ "=" _ val:expression() _ ";" { Stat::Define(name, Box::new(val), false) }
+    rule define() -> StatKind
+        = "let" _ "mut" _ name:identifier() _ "=" _ val:expression() _ ";" { StatKind::Define(name, Box::new(val), true) }
+        / "let" _ name:identifier() _ "=" _ val:expression() _ ";" { StatKind::Define(name, Box::new(val), false) }
 
-    rule expr_stat() -> Stat
-        = ex:expression() _ ";" { Stat::ExprStat(ex) }
+    rule expr_stat() -> StatKind
+        = ex:expression() _ ";" { StatKind::ExprStat(ex) }
 
     rule return_val() -> Return
-        = "return" _ val:expression() _ ";" { Return {val} }
+        = start:position!() "return" _ val:expression() _ ";" end:position!() { Return {val, span: Span::new(start, end)} }
 
-    rule return_stat() -> Stat
-        = val:return_val() { Stat::Return(val) }
+    rule return_stat() -> StatKind
+        = val:return_val() { StatKind::Return(val) }
 
     rule path() -> Trail
-        = first:identifier() _ "::" _ path:((_ i:identifier() _ {i} ) ** "::") { Trail{ head: first, trail: path } }
-        / first:identifier() { Trail{ head: first, trail: Vec::new() } }
+        = start:position!() first:identifier() _ "::" _ path:((_ i:identifier() {i} ) ** (_ "::")) end:position!() { Trail{ head: first, trail: path, span: Span::new(start, end) } }
+        / start:position!() first:identifier() end:position!() { Trail{ head: first, trail: Vec::new(), span: Span::new(start, end) } }
         / expected!("path")
 
     rule ty() -> Type
-        = "()" { Type::None }
-        / "(" _ types:((_ t:ty() _ {t}) ++ ",") _ "," _ ")" { Type::Tuple(types) }
-        / "(" _ types:((_ t:ty() _ {t}) ++ ",") _ ")" { Type::Tuple(types) }
-        / "&" _ "mut" _ t:ty() { Type::MutRef(Box::new(t)) }
-        / "&" _ t:ty() { Type::Ref(Box::new(t)) }
-        / "*" _ "mut" _ t:ty() { Type::MutPtr(Box::new(t)) }
-        / "*" _ t:ty() { Type::Ptr(Box::new(t)) }
-        / p:path() { Type::Plain(p) }
+        = start:position!() kind:ty_kind() end:position!() { Type { kind, span: Span::new(start, end) } }
+
+    rule ty_kind() -> TypeKind
+        = "()" { TypeKind::None }
+        / "(" _ types:((_ t:ty() _ {t}) ++ ",") _ "," _ ")" { TypeKind::Tuple(types) }
+        / "(" _ types:((_ t:ty() _ {t}) ++ ",") _ ")" { TypeKind::Tuple(types) }
+        / "&" _ "mut" _ t:ty() { TypeKind::MutRef(Box::new(t)) }
+        / "&" _ t:ty() { TypeKind::Ref(Box::new(t)) }
+        / "*" _ "mut" _ t:ty() { TypeKind::MutPtr(Box::new(t)) }
+        / "*" _ t:ty() { TypeKind::Ptr(Box::new(t)) }
+        / p:path() { TypeKind::Plain(p) }
 
     rule identifier() -> String
         = quiet!{ n:$(['a'..='z' | 'A'..='Z' | '_']['a'..='z' | 'A'..='Z' | '0'..='9' | '_']*) { n.to_owned() } }
         / expected!("identifier")
 
-    rule str_lit() -> Expr
-        = "\"" s:$(['a'..='z' | 'A'..='Z' | '0'..='9' | ' ' | '_' | '\n' | '\t' | '\r' | '@' |
-        '\'' | '-' | '+' | '*' | '/' | '[' | ']' | '(' | ')' | '{' | '}' | '?' | '!' |
-        '.' | ',' | ';' | ':' | '#' | '=' | '/' | '\\' | '%' | '$' | '§' | '~' | '>' | '<' |
-        '|' | '`' | '^' | '°' | '€' ]*) "\"" { Expr::Literal( s.to_owned() ) }
-
-    rule bool_lit() -> Expr
-        = "true" { Expr::BoolLit(true) }
-        / "false" { Expr::BoolLit(false) }
-
-    rule char_lit() -> Expr
-        = "\'" c:(['!'..='~' ]) "\'" { Expr::CharLit( c.to_owned() ) }
-
-    rule num_type() -> String
-        = ty:$(['a'..='z']['a'..='z' | '0'..='9']*) { ty.to_owned() }
-        / "" { "".to_owned() }
-
-    rule num_lit() -> Expr
-        = n:$(['0'..='9' | '_']*) "." dot:$(['0'..='9' | '_']+) "e-" exp:$(['0'..='9' | '_']+) ty:num_type() { Expr::FloatLit( n.to_owned() + "." + dot + "e-" + exp, NumType::try_from(ty.as_ref()).unwrap() ) }
-        / n:$(['0'..='9' | '_']*) "." dot:$(['0'..='9' | '_']+) "e+" exp:$(['0'..='9' | '_']+) ty:num_type() { Expr::FloatLit( n.to_owned() + "." + dot + "e+" + exp, NumType::try_from(ty.as_ref()).unwrap() ) }
-        / n:$(['0'..='9' | '_']*) "." dot:$(['0'..='9' | '_']+) "e" exp:$(['0'..='9' | '_']+) ty:num_type() { Expr::FloatLit( n.to_owned() + "." + dot + "e" + exp, NumType::try_from(ty.as_ref()).unwrap() ) }
-        / n:$(['0'..='9' | '_']*) "." dot:$(['0'..='9' | '_']+) ty:num_type() { Expr::FloatLit( n.to_owned() + "." + dot, NumType::try_from(ty.as_ref()).unwrap() ) }
-        / "." dot:$(['0'..='9' | '_']+) ty:num_type() { Expr::FloatLit( "0.".to_owned() + dot, NumType::try_from(ty.as_ref()).unwrap() ) }
-        / n:$(['0'..='9' | '_']*) "e" exp:$(['0'..='9' | '_']+) ty:num_type() { Expr::FloatLit( n.to_owned() + "e" + exp, NumType::try_from(ty.as_ref()).unwrap() ) }
-        / n:$(['0'..='9' | '_']*) "e-" exp:$(['0'..='9' | '_']+) ty:num_type() { Expr::FloatLit( n.to_owned() + "e-" + exp, NumType::try_from(ty.as_ref()).unwrap() ) }
-        / n:$(['0'..='9' | '_']*) "e+" exp:$(['0'..='9' | '_']+) ty:num_type() { Expr::FloatLit( n.to_owned() + "e+" + exp, NumType::try_from(ty.as_ref()).unwrap() ) }
-        / n:$(['0'..='9' | '_']+) ty:num_type() { Expr::NumLit( n.to_owned(), NumType::try_from(ty.as_ref()).unwrap() ) }
-
-    rule _() =  quiet!{[' ' | '\t' | '\n' | '\r']*}
+    /// String literals with escapes, raw strings (`r#"..."#`) and their byte string variants.
+    rule str_lit() -> ExprKind
+        = "\"" s:str_char()* "\"" { ExprKind::Literal(s.into_iter().flatten().collect()) }
+        / "r" s:raw_str() { ExprKind::Literal(s.to_owned()) }
+        / "b\"" s:byte_char()* "\"" { ExprKind::ByteStrLit(s.into_iter().flatten().collect()) }
+        / "br" s:raw_str() {? match s.is_ascii() {
+            true => Ok(ExprKind::ByteStrLit(s.as_bytes().to_vec())),
+            false => Err("ASCII character in raw byte string"),
+        } }
+
+    /// Char of a string literal, `None` for a line continuation.
+    rule str_char() -> Option<char>
+        = "\\" "\r"? "\n" [' ' | '\t' | '\n' | '\r']* { None }
+        / c:escape() { Some(c) }
+        / !['"' | '\\'] c:[_] { Some(c) }
+
+    rule byte_char() -> Option<u8>
+        = "\\" "\r"? "\n" [' ' | '\t' | '\n' | '\r']* { None }
+        / b:byte_escape() { Some(b) }
+        / !['"' | '\\'] c:[_] {? match c.is_ascii() {
+            true => Ok(Some(c as u8)),
+            false => Err("ASCII character in byte string"),
+        } }
+
+    /// Body of a raw string, enclosed in the same number of `#` on both sides.
+    rule raw_str() -> &'input str
+        = hashes:$("#"*) "\"" s:$((!raw_str_end(hashes.len()) [_])*) raw_str_end(hashes.len()) { s }
+
+    rule raw_str_end(hashes: usize)
+        = "\"" "#"*<{hashes}>
+
+    rule escape() -> char
+        = "\\" c:(
+            "u{" digits:$(hex_digit() (hex_digit() / "_")*) "}" {?
+                u32::from_str_radix(&digits.replace('_', ""), 16).ok()
+                    .and_then(char::from_u32)
+                    .ok_or("unicode escape of a valid char")
+            }
+            / "x" digits:$(hex_digit()*<2>) {?
+                match u8::from_str_radix(digits, 16) {
+                    Ok(b) if b.is_ascii() => Ok(b as char),
+                    _ => Err("hex escape in range \\x00..=\\x7f"),
+                }
+            }
+            / c:quote_escape() { c }
+            / expected!("escape sequence")
+        ) { c }
+
+    rule byte_escape() -> u8
+        = "\\" b:(
+            "x" digits:$(hex_digit()*<2>) { u8::from_str_radix(digits, 16).unwrap() }
+            / c:quote_escape() { c as u8 }
+            / expected!("byte escape sequence")
+        ) { b }
+
+    /// Escapes that are valid in chars, strings and byte strings alike.
+    rule quote_escape() -> char
+        = "n" { '\n' }
+        / "r" { '\r' }
+        / "t" { '\t' }
+        / "\\" { '\\' }
+        / "0" { '\0' }
+        / "'" { '\'' }
+        / "\"" { '"' }
+
+    rule hex_digit()
+        = ['0'..='9' | 'a'..='f' | 'A'..='F']
+
+    rule bool_lit() -> ExprKind
+        = "true" { ExprKind::BoolLit(true) }
+        / "false" { ExprKind::BoolLit(false) }
+
+    rule char_lit() -> ExprKind
+        = "'" c:(escape() / !['\'' | '\\' | '\n' | '\r' | '\t'] c:[_] { c }) "'" { ExprKind::CharLit(c) }
+        / "b'" b:(byte_escape() / !['\'' | '\\' | '\n' | '\r' | '\t'] c:[_] {?
+            match c.is_ascii() {
+                true => Ok(c as u8),
+                false => Err("ASCII character in byte literal"),
+            }
+        }) "'" { ExprKind::ByteLit(b) }
+
+    /// Type suffix of a number literal. Anything that looks like a suffix but does not name a
+    /// number type is rejected, instead of being left for the following rules.
+    rule num_type() -> NumType
+        = ty:$(['a'..='z' | 'A'..='Z' | '_']['a'..='z' | 'A'..='Z' | '0'..='9' | '_']*) {?
+            NumType::try_from(ty).map_err(|_| "number type suffix")
+        }
+        / "" { NumType::None }
+
+    rule int_type() -> NumType
+        = ty:num_type() {? match ty {
+            NumType::F32 | NumType::F64 => Err("integer type suffix"),
+            ty => Ok(ty),
+        } }
+
+    rule float_type() -> NumType
+        = ty:num_type() {? match ty {
+            NumType::F32 | NumType::F64 | NumType::None => Ok(ty),
+            _ => Err("float type suffix"),
+        } }
+
+    /// Number literals. Decimal literals start with a digit, such that identifiers like `_x` or
+    /// `e5` are not taken for numbers, and prefixed literals contain at least one digit.
+    rule num_lit() -> ExprKind
+        = n:$("0x" "_"* hex_digit() (hex_digit() / "_")*) ty:int_type() { ExprKind::NumLit( n.to_owned(), ty ) }
+        / n:$("0o" "_"* ['0'..='7'] ['0'..='7' | '_']*) ty:int_type() { ExprKind::NumLit( n.to_owned(), ty ) }
+        / n:$("0b" "_"* ['0' | '1'] ['0' | '1' | '_']*) ty:int_type() { ExprKind::NumLit( n.to_owned(), ty ) }
+        / n:dec_digits() "." dot:dec_digits() "e-" exp:exp_digits() ty:float_type() { ExprKind::FloatLit( n.to_owned() + "." + dot + "e-" + exp, ty ) }
+        / n:dec_digits() "." dot:dec_digits() "e+" exp:exp_digits() ty:float_type() { ExprKind::FloatLit( n.to_owned() + "." + dot + "e+" + exp, ty ) }
+        / n:dec_digits() "." dot:dec_digits() "e" exp:exp_digits() ty:float_type() { ExprKind::FloatLit( n.to_owned() + "." + dot + "e" + exp, ty ) }
+        / n:dec_digits() "." dot:dec_digits() ty:float_type() { ExprKind::FloatLit( n.to_owned() + "." + dot, ty ) }
+        / n:dec_digits() "e" exp:exp_digits() ty:float_type() { ExprKind::FloatLit( n.to_owned() + "e" + exp, ty ) }
+        / n:dec_digits() "e-" exp:exp_digits() ty:float_type() { ExprKind::FloatLit( n.to_owned() + "e-" + exp, ty ) }
+        / n:dec_digits() "e+" exp:exp_digits() ty:float_type() { ExprKind::FloatLit( n.to_owned() + "e+" + exp, ty ) }
+        / n:dec_digits() ty:num_type() { ExprKind::NumLit( n.to_owned(), ty ) }
+
+    rule dec_digits() -> &'input str
+        = $(['0'..='9'] ['0'..='9' | '_']*)
+
+    rule exp_digits() -> &'input str
+        = $("_"* ['0'..='9'] ['0'..='9' | '_']*)
+
+    /// Outer doc comment lines (`///`) in front of an item, each without its leading `///` and
+    /// the space after it.
+    rule doc_comments() -> Vec<String>
+        = (d:doc_comment() __ {d})*
+
+    rule doc_comment() -> String
+        = "///" !"/" text:comment_text() { text }
+
+    rule inner_doc_comment() -> String
+        = "//!" text:comment_text() { text }
+
+    rule comment_text() -> String
+        = text:$((!['\n' | '\r'] [_])*) { text.strip_prefix(' ').unwrap_or(text).to_owned() }
+
+    /// Regular comments. Line comments that start like doc comments are left to the items they
+    /// document, `////` is an ordinary comment though. Block comments may be nested.
+    rule comment()
+        = "//" !(("/" !"/") / "!") (!['\n'] [_])*
+        / block_comment()
+
+    rule block_comment()
+        = "/*" (block_comment() / !"*/" [_])* "*/"
+
+    /// Whitespace and comments. Doc comments that are not in front of an item or member, e.g. on
+    /// statements or function arguments, are skipped like regular comments.
+    rule _() =  quiet!{([' ' | '\t' | '\n' | '\r'] / comment() / doc_comment() / inner_doc_comment())*}
+
+    /// Whitespace and regular comments, but no doc comments. Used in front of items and members,
+    /// such that their doc comments are left to them.
+    rule __() =  quiet!{([' ' | '\t' | '\n' | '\r'] / comment())*}
 });
+
+
+#[cfg(test)]
+mod tests {
+    use crate::lang::expr::{int_value, parser, Expr, ExprKind, Item, NumType, StatKind};
+    use crate::lang::span::{LineIndex, Span, Spanned};
+
+    #[test]
+    fn spans() {
+        let source = "fn add(a: i32) -> i32 {\n    let b = a + 1;\n    b\n}";
+        let function = parser::function(source).unwrap();
+        assert_eq!(function.span(), Span::new(0, 50));
+        assert_eq!(function.header.span(), Span::new(0, 21));
+        assert_eq!(function.header.args[0].span(), Span::new(7, 13));
+        assert_eq!(function.body.span(), Span::new(22, 50));
+
+        let define = &function.body.content[0];
+        assert_eq!(define.span(), Span::new(28, 42));
+        let StatKind::Define(_, value, _) = define.kind() else {
+            panic!("expected a definition, got {:?}", define);
+        };
+        assert_eq!(value.span(), Span::new(36, 41));
+        let ExprKind::Add(a, b) = value.kind() else {
+            panic!("expected an addition, got {:?}", value);
+        };
+        assert_eq!((a.span(), b.span()), (Span::new(36, 37), Span::new(40, 41)));
+
+        let ret = function.body.return_value.as_ref().unwrap();
+        assert_eq!(ret.span(), Span::new(47, 48));
+
+        let lines = LineIndex::new(source);
+        assert_eq!(lines.span_pos(value.span()), (2, 12));
+        assert_eq!(lines.span_pos(ret.span()), (3, 4));
+    }
+
+    #[test]
+    fn comments() {
+        let source = "\
+//! Geometry helpers.
+//!
+//! Nothing fancy.
+
+/// A point in the plane.
+struct Point {
+    /// horizontal coordinate
+    x: i32, // pixels
+    //// not documentation
+    y: i32,
+}
+
+/* block comments /* may be */ nested */
+/// Adds one.
+///
+fn inc(a: i32) -> i32 {
+    // the result
+    a /* plus */ + 1
+}
+";
+        let module = parser::module(source).unwrap();
+        assert_eq!(module.docs(), ["Geometry helpers.", "", "Nothing fancy."]);
+        assert_eq!(module.items().len(), 2);
+
+        let Item::Struct(point) = &module.items()[0] else {
+            panic!("expected a struct, got {:?}", module.items()[0]);
+        };
+        assert_eq!(module.items()[0].docs(), ["A point in the plane."]);
+        assert_eq!(point.m[0].docs, ["horizontal coordinate"]);
+        assert!(point.m[1].docs.is_empty());
+
+        let Item::Function(inc) = &module.items()[1] else {
+            panic!("expected a function, got {:?}", module.items()[1]);
+        };
+        assert_eq!(module.items()[1].docs(), ["Adds one.", ""]);
+        assert!(matches!(inc.body.return_value.as_ref().unwrap().kind(), ExprKind::Add(_, _)));
+
+        assert!(parser::function("fn f() { /* unterminated }").is_err());
+
+        // doc comments that are not attached to an item are skipped
+        let function = parser::function("\
+fn f(
+    /// the argument
+    a: i32
+) -> i32 {
+    /// explanation
+    let x = 1;
+    //! inner
+    x
+    /// dangling
+}").unwrap();
+        assert_eq!(function.header.args.len(), 1);
+        assert_eq!(function.body.content.len(), 1);
+        let module = parser::module("\
+trait Shape {
+    /// Returns the area.
+    fn area() -> i32;
+    /// dangling
+}
+/// dangling at the end
+").unwrap();
+        let Item::Trait(shape) = &module.items()[0] else {
+            panic!("expected a trait, got {:?}", module.items()[0]);
+        };
+        assert_eq!(shape.fns[0].docs, ["Returns the area."]);
+    }
+
+    /// Parses `expr` as the value of a function body.
+    fn literal(expr: &str) -> ExprKind {
+        let function = parser::function(&format!("fn f() {{ {} }}", expr)).unwrap();
+        function.body.return_value.unwrap().kind
+    }
+
+    #[test]
+    fn literals() {
+        let ExprKind::Literal(s) = literal(r#""tab\t \"quoted\" \\ \x41 \u{1F980} 🦀 §\
+            continued""#) else { panic!() };
+        assert_eq!(s, "tab\t \"quoted\" \\ A \u{1F980} 🦀 §continued");
+        let ExprKind::Literal(s) = literal(r###"r#"raw "\n" string"#"###) else { panic!() };
+        assert_eq!(s, r#"raw "\n" string"#);
+        let ExprKind::ByteStrLit(b) = literal(r#"b"\xff\0a""#) else { panic!() };
+        assert_eq!(b, [0xff, 0, b'a']);
+        assert!(matches!(literal("' '"), ExprKind::CharLit(' ')));
+        assert!(matches!(literal(r"'\''"), ExprKind::CharLit('\'')));
+        assert!(matches!(literal("'€'"), ExprKind::CharLit('€')));
+        assert!(matches!(literal(r"b'\n'"), ExprKind::ByteLit(b'\n')));
+
+        // errors point at the offending escape
+        let err = parser::function(r#"fn f() { "ok \q" }"#).unwrap_err();
+        assert_eq!(err.location.offset, 14);
+        assert!(err.expected.tokens().any(|t| t == "escape sequence"));
+        let err = parser::function(r#"fn f() { "\x80" }"#).unwrap_err();
+        assert_eq!(err.location.offset, 14);
+        assert!(parser::function(r#"fn f() { "\u{d800}" }"#).is_err());
+        assert!(parser::function(r#"fn f() { b"€" }"#).is_err());
+    }
+
+    #[test]
+    fn number_literals() {
+        let ExprKind::NumLit(n, NumType::U8) = literal("0xFFu8") else { panic!() };
+        assert_eq!(int_value(&n), Some(255));
+        let ExprKind::NumLit(n, NumType::None) = literal("0o7_7") else { panic!() };
+        assert_eq!(int_value(&n), Some(63));
+        let ExprKind::NumLit(n, NumType::I64) = literal("0b1010_1010i64") else { panic!() };
+        assert_eq!(int_value(&n), Some(170));
+        assert!(matches!(literal("1_000"), ExprKind::NumLit(_, NumType::None)));
+        assert!(matches!(literal("2.5f32"), ExprKind::FloatLit(_, NumType::F32)));
+
+        // malformed suffixes are parse errors
+        let err = parser::function("fn f() { 3xyz }").unwrap_err();
+        assert!(err.expected.tokens().any(|t| t == "number type suffix"));
+        assert!(parser::function("fn f() { 2.5u8 }").is_err());
+        assert!(parser::function("fn f() { 0o17f32 }").is_err());
+        assert!(parser::function("fn f() { 0b102 }").is_err());
+        assert!(parser::function("fn f() { 0x_ }").is_err());
+
+        // identifiers that start like numbers
+        for name in ["_x", "_", "__", "e5"] {
+            match literal(name) {
+                ExprKind::Path(p) => assert_eq!(p.path(), name),
+                e => panic!("expected {} to be a path, got {:?}", name, e),
+            }
+        }
+    }
+
+    #[test]
+    fn left_associative_operators() {
+        let path = |e: &Expr| match e.kind() {
+            ExprKind::Path(p) => p.path(),
+            e => panic!("expected a path, got {:?}", e),
+        };
+        let ExprKind::Add(ab, c) = literal("a - b + c") else { panic!() };
+        let ExprKind::Sub(a, b) = ab.kind() else { panic!() };
+        assert_eq!((path(a), path(b), path(&c)), ("a".into(), "b".into(), "c".into()));
+
+        let ExprKind::Mul(ab, _) = literal("a / b * c") else { panic!() };
+        assert!(matches!(ab.kind(), ExprKind::Div(_, _)));
+        let ExprKind::RShift(ab, _) = literal("a << b >> c") else { panic!() };
+        assert!(matches!(ab.kind(), ExprKind::LShift(_, _)));
+    }
+}
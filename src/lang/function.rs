@@ -19,11 +19,31 @@ struct FerrumFunctionBody {
     // TODO
 }
 
+/// Identifies a single function in a compiled program. Functions generated from the same generic
+/// template share their `function_id` and are told apart by the fingerprint of their generics.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct FerrumFunctionPtr {
     function_id: Namespace,
     generic_fingerprint: u64,
 }
 
+impl FerrumFunctionPtr {
+    pub fn new(function_id: Namespace, generic_fingerprint: u64) -> Self {
+        FerrumFunctionPtr {
+            function_id,
+            generic_fingerprint,
+        }
+    }
+
+    pub fn function_id(&self) -> &str {
+        &self.function_id
+    }
+
+    pub fn generic_fingerprint(&self) -> u64 {
+        self.generic_fingerprint
+    }
+}
+
 impl FerrumFunctionHeader {
     pub fn matches_generics(&self, generics: &FerrumGenericsTable) -> bool {
         self.generic_fingerprint == *generics.fingerprint()
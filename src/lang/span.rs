@@ -0,0 +1,57 @@
+use crate::bytecode::chunk::CodePos;
+
+/// Range of bytes of the source code an AST node has been parsed from. `start` is inclusive and
+/// `end` is exclusive.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Span { start, end }
+    }
+
+    /// Returns the smallest span that covers both spans.
+    pub fn join(self, other: Span) -> Span {
+        Span::new(usize::min(self.start, other.start), usize::max(self.end, other.end))
+    }
+}
+
+/// AST nodes that know which part of the source code they have been parsed from.
+pub trait Spanned {
+    fn span(&self) -> Span;
+}
+
+/// Maps byte offsets of a source file to the line and char positions used by the line tables
+/// of chunks.
+#[derive(Clone, Debug)]
+pub struct LineIndex {
+    /// byte offset of the first char of every line
+    starts: Vec<usize>,
+}
+
+impl LineIndex {
+    pub fn new(source: &str) -> Self {
+        let mut starts = vec![0];
+        starts.extend(source.match_indices('\n').map(|(i, _)| i + 1));
+        LineIndex { starts }
+    }
+
+    /// Returns the 1-based line and the 0-based byte column of the byte offset `offset`.
+    /// Positions that do not fit into a `CodePos` saturate at `u16::MAX`.
+    pub fn pos(&self, offset: usize) -> CodePos {
+        let line = self.starts.partition_point(|s| *s <= offset);
+        let column = offset - self.starts[line - 1];
+        (
+            u16::try_from(line).unwrap_or(u16::MAX),
+            u16::try_from(column).unwrap_or(u16::MAX),
+        )
+    }
+
+    /// Returns the position of the first char of the span.
+    pub fn span_pos(&self, span: Span) -> CodePos {
+        self.pos(span.start)
+    }
+}
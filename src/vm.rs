@@ -1,20 +1,63 @@
+pub mod coverage;
+pub mod debug;
+pub mod native;
+pub mod sandbox;
+pub mod trace;
+
 use std::error::Error;
 use std::fmt::{Debug, Display, Formatter};
 use std::num::NonZeroUsize;
 use std::ops::{Index, IndexMut, Range};
 use crate::bytecode::chunk::{Chunk, Value};
 use crate::bytecode::opcode::{NumeralType, OpCode, OpCodeError};
+use crate::bytecode::program::Program;
+use crate::bytecode::verify::{verify_program, VerifyError};
 use crate::bytecode::values::*;
+use crate::vm::native::{LinkError, NativeRegistry};
+use crate::vm::sandbox::SandboxPolicy;
+use crate::vm::trace::Tracer;
 use crate::vm::VMError::UnknownOpCode;
 
 
 #[derive(Clone, Debug)]
 pub enum VMError {
-    CompileError((u16, u16), String),
-    RuntimeError(String),
+    /// The source code could not be compiled. The position of the offending source code is
+    /// known unless the error is not attributed to a span.
+    CompileError(Option<(u16, u16)>, String),
+    RuntimeError((u16, u16), RuntimeError),
     UnexpectedEoF,
     JITError((u16, u16)),
     UnknownOpCode(OpCodeError),
+    /// The program does not contain an entry point to start execution at
+    NoEntryPoint,
+    /// The program did not pass bytecode verification
+    VerifyError(VerifyError),
+    /// The native imports of the program could not be linked
+    LinkError(LinkError),
+}
+
+/// Traps raised by the VM while executing an instruction. The VM attaches the source position of
+/// the offending instruction when it reports them as `VMError::RuntimeError`.
+#[derive(Clone, Debug)]
+pub enum RuntimeError {
+    /// The instruction cannot be executed with the given operands, e.g. negating an unsigned value
+    IllegalOperation(String),
+    /// An integer operation overflowed the value range of its numeral type
+    IntegerOverflow(NumeralType),
+    /// Integer division or remainder with a zero divisor
+    DivisionByZero,
+    /// A shift amount was negative or not smaller than the bit width of the shifted type
+    ShiftOverflow(NumeralType),
+    /// A call referenced a function index that does not exist
+    UnknownFunction(usize),
+    /// The stack would grow beyond its limit of the specified number of bytes
+    StackOverflow(usize),
+    /// The instruction requires more bytes than the stack or current frame holds
+    StackUnderflow,
+    /// A native function failed
+    NativeError(String),
+    /// The script exceeded the fuel limit of its sandbox policy
+    FuelLimitExceeded(u64),
 }
 
 impl Display for VMError {
@@ -25,64 +68,238 @@ impl Display for VMError {
 
 impl Error for VMError {}
 
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::IllegalOperation(msg) => f.write_str(msg),
+            Self::IntegerOverflow(n) => f.write_str(&format!("Arithmetic overflow for type {:?}", n)),
+            Self::DivisionByZero => f.write_str("Attempted to divide by zero"),
+            Self::ShiftOverflow(n) => f.write_str(&format!("Shift amount out of range for type {:?}", n)),
+            Self::UnknownFunction(i) => f.write_str(&format!("There is no function with index {}", i)),
+            Self::StackOverflow(limit) => f.write_str(&format!("Stack overflow, the stack is limited to {} bytes", limit)),
+            Self::StackUnderflow => f.write_str("Stack underflow"),
+            Self::NativeError(msg) => f.write_str(&format!("Native function failed: {}", msg)),
+            Self::FuelLimitExceeded(limit) => f.write_str(&format!("Fuel limit of {} exceeded", limit)),
+        }
+    }
+}
 
+impl Error for RuntimeError {}
 
-pub struct Stack<const SIZE: usize> {
-    stack: [u8; SIZE],
-    sp: usize
+/// Value returned by the entry point of a program once it finishes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExitStatus {
+    value: Vec<u8>,
+    ty: Option<NumeralType>,
+}
+
+impl ExitStatus {
+    /// Returns the raw bytes of the returned value.
+    pub fn value(&self) -> &[u8] {
+        &self.value
+    }
+
+    /// Returns the declared return type of the entry point, if any.
+    pub fn return_type(&self) -> Option<NumeralType> {
+        self.ty
+    }
+
+    /// Interprets the returned value as a value of type `Val`. Returns `None` if the size of the
+    /// returned value does not match.
+    pub fn get<const N: usize, Val: Value<N>>(&self) -> Option<Val> {
+        let bits: [u8; N] = self.value.as_slice().try_into().ok()?;
+        Some(Val::from_bits(bits))
+    }
+
+    /// Returns the exit code of the program. Values of a declared numeral type are converted
+    /// into an `i32` using Rust's `as` semantics. Otherwise, the first four bytes of the returned
+    /// value are used, zero-extended if the value is shorter.
+    pub fn code(&self) -> i32 {
+        let code = match self.ty {
+            Some(NumeralType::I8) => self.get::<1, i8>().map(|v| v as i32),
+            Some(NumeralType::I16) => self.get::<2, i16>().map(|v| v as i32),
+            Some(NumeralType::I32) => self.get::<4, i32>(),
+            Some(NumeralType::I64) => self.get::<8, i64>().map(|v| v as i32),
+            Some(NumeralType::I128) => self.get::<16, i128>().map(|v| v as i32),
+            Some(NumeralType::U8) => self.get::<1, u8>().map(|v| v as i32),
+            Some(NumeralType::U16) => self.get::<2, u16>().map(|v| v as i32),
+            Some(NumeralType::U32) => self.get::<4, u32>().map(|v| v as i32),
+            Some(NumeralType::U64) => self.get::<8, u64>().map(|v| v as i32),
+            Some(NumeralType::U128) => self.get::<16, u128>().map(|v| v as i32),
+            Some(NumeralType::F32) => self.get::<4, f32>().map(|v| v as i32),
+            Some(NumeralType::F64) => self.get::<8, f64>().map(|v| v as i32),
+            None => None,
+        };
+        code.unwrap_or_else(|| {
+            let mut bits = [0u8; 4];
+            let n = usize::min(self.value.len(), bits.len());
+            bits[..n].copy_from_slice(&self.value[..n]);
+            i32::from_le_bytes(bits)
+        })
+    }
 }
 
-impl<const SIZE: usize> Stack<SIZE> {
-    pub fn new() -> Self {
+/// Outcome of `VM::run_with_fuel`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FuelStatus {
+    /// The program returned from its entry point
+    Finished(ExitStatus),
+    /// The fuel ran out before the next instruction could be executed. Execution resumes at
+    /// that instruction once more fuel is supplied.
+    OutOfFuel,
+}
+
+
+
+/// Default limit of the VM stack in bytes.
+pub const DEFAULT_STACK_LIMIT: usize = 1 << 20;
+
+/// Byte stack of the VM. The stack grows on demand until it reaches its size limit, after which
+/// pushing traps with `StackOverflow`. The bookkeeping of call frames counts against the limit as
+/// well, such that deep recursion overflows the stack instead of exhausting host memory.
+pub struct Stack {
+    stack: Vec<u8>,
+    limit: usize,
+    /// bytes of the limit taken by call frames
+    frames: usize,
+}
+
+impl Stack {
+    pub fn new(limit: usize) -> Self {
         Stack {
-            stack: [0; SIZE],
-            sp: 0
+            stack: Vec::with_capacity(usize::min(limit, 256)),
+            limit,
+            frames: 0,
         }
     }
 
     pub fn is_empty(&self) -> bool {
-        self.sp == 0
+        self.stack.is_empty()
+    }
+
+    /// Checks that `size` more bytes fit onto the stack.
+    fn reserve(&self, size: usize) -> Result<(), RuntimeError> {
+        if size > self.limit - self.stack.len() - self.frames {
+            return Err(RuntimeError::StackOverflow(self.limit));
+        }
+        Ok(())
+    }
+
+    /// Checks that the stack holds at least `size` bytes and returns the position of the topmost
+    /// `size` bytes.
+    fn top(&self, size: usize) -> Result<usize, RuntimeError> {
+        self.stack.len()
+            .checked_sub(size)
+            .ok_or(RuntimeError::StackUnderflow)
     }
 
-    pub fn push_value<const N: usize, Val: Value<N>>(&mut self, val: Val) {
-        self.push(&val.to_bits());
+    pub fn push_value<const N: usize, Val: Value<N>>(&mut self, val: Val) -> Result<(), RuntimeError> {
+        self.push(&val.to_bits())
     }
 
-    pub fn push(&mut self, src: &[u8]) {
-        self.stack[self.sp..(self.sp + src.len())].copy_from_slice(src);
-        self.sp += src.len();
+    pub fn push(&mut self, src: &[u8]) -> Result<(), RuntimeError> {
+        self.reserve(src.len())?;
+        self.stack.extend_from_slice(src);
+        Ok(())
     }
 
-    pub fn pop_to(&mut self, dst: &mut [u8]) {
-        dst.copy_from_slice(&self.stack[(self.sp - dst.len())..self.sp]);
-        self.sp -= dst.len();
+    pub fn pop_to(&mut self, dst: &mut [u8]) -> Result<(), RuntimeError> {
+        let top = self.top(dst.len())?;
+        dst.copy_from_slice(&self.stack[top..]);
+        self.stack.truncate(top);
+        Ok(())
     }
 
-    pub fn pop_value<const N: usize, Val: Value<N>>(&mut self) -> Val {
+    pub fn pop_value<const N: usize, Val: Value<N>>(&mut self) -> Result<Val, RuntimeError> {
         let mut bits = [0u8; N];
-        self.pop_to(&mut bits);
-        Val::from_bits(bits)
+        self.pop_to(&mut bits)?;
+        Ok(Val::from_bits(bits))
+    }
+
+    pub fn pop(&mut self, size: usize) -> Result<(), RuntimeError> {
+        let top = self.top(size)?;
+        self.stack.truncate(top);
+        Ok(())
     }
 
-    pub fn pop(&mut self, size: usize) {
-        self.sp -= size;
+    /// Returns the topmost `size` bytes without popping them.
+    pub fn peek(&self, size: usize) -> Result<&[u8], RuntimeError> {
+        let top = self.top(size)?;
+        Ok(&self.stack[top..])
+    }
+
+    /// Pushes a copy of the `size` bytes starting at stack position `pos`.
+    pub fn load(&mut self, pos: usize, size: usize) -> Result<(), RuntimeError> {
+        if pos + size > self.stack.len() {
+            return Err(RuntimeError::StackUnderflow);
+        }
+        self.reserve(size)?;
+        self.stack.extend_from_within(pos..(pos + size));
+        Ok(())
+    }
+
+    /// Pops `size` bytes off the stack and writes them to stack position `pos`, which has to lie
+    /// below the popped bytes.
+    pub fn store(&mut self, pos: usize, size: usize) -> Result<(), RuntimeError> {
+        let top = self.top(size)?;
+        if pos + size > top {
+            return Err(RuntimeError::StackUnderflow);
+        }
+        self.stack.copy_within(top.., pos);
+        self.stack.truncate(top);
+        Ok(())
+    }
+
+    /// Moves the topmost `size` bytes to stack position `pos` and drops everything above them.
+    pub fn collapse(&mut self, pos: usize, size: usize) -> Result<(), RuntimeError> {
+        let top = self.top(size)?;
+        if pos > top {
+            return Err(RuntimeError::StackUnderflow);
+        }
+        self.stack.copy_within(top.., pos);
+        self.stack.truncate(pos + size);
+        Ok(())
+    }
+
+    /// Takes `size` bytes of the limit for a call frame.
+    pub fn push_frame(&mut self, size: usize) -> Result<(), RuntimeError> {
+        self.reserve(size)?;
+        self.frames += size;
+        Ok(())
+    }
+
+    /// Returns the `size` bytes of a call frame to the limit.
+    pub fn pop_frame(&mut self, size: usize) {
+        self.frames = self.frames.saturating_sub(size);
+    }
+
+    /// Removes all values and call frames from the stack.
+    pub fn clear(&mut self) {
+        self.stack.clear();
+        self.frames = 0;
+    }
+
+    /// Shortens the stack to `len` bytes.
+    pub fn truncate(&mut self, len: usize) {
+        self.stack.truncate(len);
     }
 
     pub fn len(&self) -> usize {
-        self.sp
+        self.stack.len()
     }
 
-    pub fn cap(&self) -> usize {
-        SIZE
+    /// Returns the maximum size of the stack in bytes.
+    pub fn limit(&self) -> usize {
+        self.limit
     }
 }
 
-impl<const SIZE: usize> Debug for Stack<SIZE> {
+impl Debug for Stack {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.write_str("[ ")?;
-        for i in 0..self.sp {
-            f.write_str(&format!("{:02x}", self.stack[i]))?;
-            if i + 1 < self.sp {
+        for (i, b) in self.stack.iter().enumerate() {
+            f.write_str(&format!("{:02x}", b))?;
+            if i + 1 < self.stack.len() {
                 f.write_str(", ")?;
             }
         }
@@ -90,7 +307,7 @@ impl<const SIZE: usize> Debug for Stack<SIZE> {
     }
 }
 
-impl<const SIZE: usize> Index<usize> for Stack<SIZE> {
+impl Index<usize> for Stack {
     type Output = u8;
 
     fn index(&self, index: usize) -> &Self::Output {
@@ -98,13 +315,13 @@ impl<const SIZE: usize> Index<usize> for Stack<SIZE> {
     }
 }
 
-impl<const SIZE: usize> IndexMut<usize> for Stack<SIZE> {
+impl IndexMut<usize> for Stack {
     fn index_mut(&mut self, index: usize) -> &mut Self::Output {
         &mut self.stack[index]
     }
 }
 
-impl<const SIZE: usize> Index<Range<usize>> for Stack<SIZE> {
+impl Index<Range<usize>> for Stack {
     type Output = [u8];
 
     fn index(&self, index: Range<usize>) -> &Self::Output {
@@ -112,7 +329,7 @@ impl<const SIZE: usize> Index<Range<usize>> for Stack<SIZE> {
     }
 }
 
-impl<const SIZE: usize> IndexMut<Range<usize>> for Stack<SIZE> {
+impl IndexMut<Range<usize>> for Stack {
     fn index_mut(&mut self, index: Range<usize>) -> &mut Self::Output {
         &mut self.stack[index]
     }
@@ -123,93 +340,621 @@ impl<const SIZE: usize> IndexMut<Range<usize>> for Stack<SIZE> {
 macro_rules! impl_binop(
     ($self:expr, $T:ty, $op:expr) => {
         {
-            let b: $T = $self.stack.pop_value();
-            let a: $T = $self.stack.pop_value();
-            $self.stack.push_value($op(a, b));
+            let b: $T = $self.stack.pop_value()?;
+            let a: $T = $self.stack.pop_value()?;
+            $self.stack.push_value($op(a, b))?;
+        }
+    };
+);
+
+macro_rules! impl_unop(
+    ($self:expr, $T:ty, $op:expr) => {
+        {
+            let a: $T = $self.stack.pop_value()?;
+            $self.stack.push_value($op(a))?;
         }
     };
 );
 
+/// Expands a binary operation for every numeral type.
+macro_rules! impl_numeral_binop(
+    ($self:expr, $n:expr, $op:expr) => {
+        match $n {
+            NumeralType::I8 => impl_binop!($self, i8, $op),
+            NumeralType::I16 => impl_binop!($self, i16, $op),
+            NumeralType::I32 => impl_binop!($self, i32, $op),
+            NumeralType::I64 => impl_binop!($self, i64, $op),
+            NumeralType::I128 => impl_binop!($self, i128, $op),
+            NumeralType::U8 => impl_binop!($self, u8, $op),
+            NumeralType::U16 => impl_binop!($self, u16, $op),
+            NumeralType::U32 => impl_binop!($self, u32, $op),
+            NumeralType::U64 => impl_binop!($self, u64, $op),
+            NumeralType::U128 => impl_binop!($self, u128, $op),
+            NumeralType::F32 => impl_binop!($self, f32, $op),
+            NumeralType::F64 => impl_binop!($self, f64, $op),
+        }
+    };
+);
+
+/// Calls one of the `checked_*` methods on an integer operand pair. A failed operation traps with
+/// `DivisionByZero` if the divisor is zero and with `IntegerOverflow` otherwise.
+macro_rules! impl_checked_binop(
+    ($self:expr, $T:ty, $n:expr, $method:ident) => {
+        {
+            let b: $T = $self.stack.pop_value()?;
+            let a: $T = $self.stack.pop_value()?;
+            match a.$method(b) {
+                Some(v) => $self.stack.push_value(v)?,
+                None if b == 0 => return Err(RuntimeError::DivisionByZero),
+                None => return Err(RuntimeError::IntegerOverflow($n)),
+            }
+        }
+    };
+);
+
+/// Calls one of the infallible `wrapping_*` or `saturating_*` methods on an integer operand pair.
+/// Since these still panic on a zero divisor, division-like operations have to set `$div` to
+/// trap beforehand.
+macro_rules! impl_total_binop(
+    ($self:expr, $T:ty, $method:ident, $div:expr) => {
+        {
+            let b: $T = $self.stack.pop_value()?;
+            let a: $T = $self.stack.pop_value()?;
+            if $div && b == 0 {
+                return Err(RuntimeError::DivisionByZero);
+            }
+            $self.stack.push_value(a.$method(b))?;
+        }
+    };
+);
+
+/// Expands a checked arithmetic operation for every numeral type. Integer types trap on overflow
+/// and division by zero, floating point types use the IEEE semantics of `$float_op`.
+macro_rules! impl_arith_binop(
+    ($self:expr, $n:expr, $method:ident, $float_op:expr) => {
+        match $n {
+            NumeralType::I8 => impl_checked_binop!($self, i8, $n, $method),
+            NumeralType::I16 => impl_checked_binop!($self, i16, $n, $method),
+            NumeralType::I32 => impl_checked_binop!($self, i32, $n, $method),
+            NumeralType::I64 => impl_checked_binop!($self, i64, $n, $method),
+            NumeralType::I128 => impl_checked_binop!($self, i128, $n, $method),
+            NumeralType::U8 => impl_checked_binop!($self, u8, $n, $method),
+            NumeralType::U16 => impl_checked_binop!($self, u16, $n, $method),
+            NumeralType::U32 => impl_checked_binop!($self, u32, $n, $method),
+            NumeralType::U64 => impl_checked_binop!($self, u64, $n, $method),
+            NumeralType::U128 => impl_checked_binop!($self, u128, $n, $method),
+            NumeralType::F32 => impl_binop!($self, f32, $float_op),
+            NumeralType::F64 => impl_binop!($self, f64, $float_op),
+        }
+    };
+);
+
+/// Expands a wrapping or saturating operation for every integer type. These modes do not exist
+/// for floating point types.
+macro_rules! impl_int_binop(
+    ($self:expr, $n:expr, $method:ident, $div:expr) => {
+        match $n {
+            NumeralType::I8 => impl_total_binop!($self, i8, $method, $div),
+            NumeralType::I16 => impl_total_binop!($self, i16, $method, $div),
+            NumeralType::I32 => impl_total_binop!($self, i32, $method, $div),
+            NumeralType::I64 => impl_total_binop!($self, i64, $method, $div),
+            NumeralType::I128 => impl_total_binop!($self, i128, $method, $div),
+            NumeralType::U8 => impl_total_binop!($self, u8, $method, $div),
+            NumeralType::U16 => impl_total_binop!($self, u16, $method, $div),
+            NumeralType::U32 => impl_total_binop!($self, u32, $method, $div),
+            NumeralType::U64 => impl_total_binop!($self, u64, $method, $div),
+            NumeralType::U128 => impl_total_binop!($self, u128, $method, $div),
+            n => return Err(RuntimeError::IllegalOperation(
+                format!("Operation `{}` is not defined for type {:?}", stringify!($method), n)
+            )),
+        }
+    };
+);
+
+/// Expands a bitwise operation for every integer type.
+macro_rules! impl_bitwise_binop(
+    ($self:expr, $n:expr, $op:expr) => {
+        match $n {
+            NumeralType::I8 => impl_binop!($self, i8, $op),
+            NumeralType::I16 => impl_binop!($self, i16, $op),
+            NumeralType::I32 => impl_binop!($self, i32, $op),
+            NumeralType::I64 => impl_binop!($self, i64, $op),
+            NumeralType::I128 => impl_binop!($self, i128, $op),
+            NumeralType::U8 => impl_binop!($self, u8, $op),
+            NumeralType::U16 => impl_binop!($self, u16, $op),
+            NumeralType::U32 => impl_binop!($self, u32, $op),
+            NumeralType::U64 => impl_binop!($self, u64, $op),
+            NumeralType::U128 => impl_binop!($self, u128, $op),
+            n => return Err(RuntimeError::IllegalOperation(
+                format!("Bitwise operations are not defined for type {:?}", n)
+            )),
+        }
+    };
+);
+
+/// Shifts an integer by an amount of the same type. Amounts that are negative or not smaller
+/// than the bit width trap instead of being masked.
+macro_rules! impl_shift(
+    ($self:expr, $T:ty, $n:expr, $method:ident) => {
+        {
+            let b: $T = $self.stack.pop_value()?;
+            let a: $T = $self.stack.pop_value()?;
+            match u32::try_from(b).ok().and_then(|b| a.$method(b)) {
+                Some(v) => $self.stack.push_value(v)?,
+                None => return Err(RuntimeError::ShiftOverflow($n)),
+            }
+        }
+    };
+);
+
+/// Expands a shift operation for every integer type.
+macro_rules! impl_int_shift(
+    ($self:expr, $n:expr, $method:ident) => {
+        match $n {
+            NumeralType::I8 => impl_shift!($self, i8, $n, $method),
+            NumeralType::I16 => impl_shift!($self, i16, $n, $method),
+            NumeralType::I32 => impl_shift!($self, i32, $n, $method),
+            NumeralType::I64 => impl_shift!($self, i64, $n, $method),
+            NumeralType::I128 => impl_shift!($self, i128, $n, $method),
+            NumeralType::U8 => impl_shift!($self, u8, $n, $method),
+            NumeralType::U16 => impl_shift!($self, u16, $n, $method),
+            NumeralType::U32 => impl_shift!($self, u32, $n, $method),
+            NumeralType::U64 => impl_shift!($self, u64, $n, $method),
+            NumeralType::U128 => impl_shift!($self, u128, $n, $method),
+            n => return Err(RuntimeError::IllegalOperation(
+                format!("Shift operations are not defined for type {:?}", n)
+            )),
+        }
+    };
+);
+
+/// Pushes `$a` converted into the numeral type `$to` using Rust's `as` semantics.
+macro_rules! impl_cast_to(
+    ($self:expr, $a:expr, $to:expr) => {
+        match $to {
+            NumeralType::I8 => $self.stack.push_value($a as i8)?,
+            NumeralType::I16 => $self.stack.push_value($a as i16)?,
+            NumeralType::I32 => $self.stack.push_value($a as i32)?,
+            NumeralType::I64 => $self.stack.push_value($a as i64)?,
+            NumeralType::I128 => $self.stack.push_value($a as i128)?,
+            NumeralType::U8 => $self.stack.push_value($a as u8)?,
+            NumeralType::U16 => $self.stack.push_value($a as u16)?,
+            NumeralType::U32 => $self.stack.push_value($a as u32)?,
+            NumeralType::U64 => $self.stack.push_value($a as u64)?,
+            NumeralType::U128 => $self.stack.push_value($a as u128)?,
+            NumeralType::F32 => $self.stack.push_value($a as f32)?,
+            NumeralType::F64 => $self.stack.push_value($a as f64)?,
+        }
+    };
+);
+
+/// Pops a value of numeral type `$from` and pushes it converted into numeral type `$to`.
+macro_rules! impl_cast(
+    ($self:expr, $from:expr, $to:expr) => {
+        match $from {
+            NumeralType::I8 => { let a: i8 = $self.stack.pop_value()?; impl_cast_to!($self, a, $to) },
+            NumeralType::I16 => { let a: i16 = $self.stack.pop_value()?; impl_cast_to!($self, a, $to) },
+            NumeralType::I32 => { let a: i32 = $self.stack.pop_value()?; impl_cast_to!($self, a, $to) },
+            NumeralType::I64 => { let a: i64 = $self.stack.pop_value()?; impl_cast_to!($self, a, $to) },
+            NumeralType::I128 => { let a: i128 = $self.stack.pop_value()?; impl_cast_to!($self, a, $to) },
+            NumeralType::U8 => { let a: u8 = $self.stack.pop_value()?; impl_cast_to!($self, a, $to) },
+            NumeralType::U16 => { let a: u16 = $self.stack.pop_value()?; impl_cast_to!($self, a, $to) },
+            NumeralType::U32 => { let a: u32 = $self.stack.pop_value()?; impl_cast_to!($self, a, $to) },
+            NumeralType::U64 => { let a: u64 = $self.stack.pop_value()?; impl_cast_to!($self, a, $to) },
+            NumeralType::U128 => { let a: u128 = $self.stack.pop_value()?; impl_cast_to!($self, a, $to) },
+            NumeralType::F32 => { let a: f32 = $self.stack.pop_value()?; impl_cast_to!($self, a, $to) },
+            NumeralType::F64 => { let a: f64 = $self.stack.pop_value()?; impl_cast_to!($self, a, $to) },
+        }
+    };
+);
+
+/// Negates a signed integer and traps if the result does not fit the type (e.g. `-i8::MIN`).
+macro_rules! impl_checked_neg(
+    ($self:expr, $T:ty, $n:expr) => {
+        {
+            let a: $T = $self.stack.pop_value()?;
+            match a.checked_neg() {
+                Some(v) => $self.stack.push_value(v)?,
+                None => return Err(RuntimeError::IntegerOverflow($n)),
+            }
+        }
+    };
+);
+
+
+/// Bookkeeping for an active function call. The frame is pushed by `Call` and restores the state
+/// of the caller when it is popped by `Ret`.
+struct CallFrame {
+    /// instruction pointer to continue at in the calling chunk
+    ret_ip: usize,
+    /// frame pointer of the caller
+    fp: usize,
+    /// index of the calling chunk
+    chunk: usize,
+}
+
+/// Bytes of the stack limit taken by each active call frame.
+const FRAME_SIZE: usize = std::mem::size_of::<CallFrame>();
 
 pub struct VM {
     /// stack
-    stack: Stack<256>, // limit stack for now
+    stack: Stack,
     /// instruction pointer
     ip: usize,
+    /// position of the instruction that is currently being executed
+    op_ip: usize,
     /// frame pointer
     fp: usize,
+    /// call frames of all functions that have not returned yet, excluding the current one
+    frames: Vec<CallFrame>,
+
+    /// value returned by the entry point, once the program has finished
+    exit: Option<ExitStatus>,
 
-    pub exit_code: i32,
-    pub is_active: bool,
+    /// fuel that is left for metered execution
+    fuel: u64,
+    /// total fuel consumed by all instructions executed so far
+    fuel_consumed: u64,
 
-    /// main chunk of program memory
-    chunk: Chunk,
+    /// program memory
+    program: Program,
+    /// index of the chunk that is currently being executed
+    chunk: usize,
+    /// index of the chunk execution starts at
+    entry: usize,
+    /// native functions provided by the host
+    natives: NativeRegistry,
+    /// index of the registered native for each native import of the program
+    links: Vec<usize>,
+    /// restrictions that apply to the program
+    policy: SandboxPolicy,
 
     use_jit: bool,
 }
 
 impl VM {
-    pub fn new(chunk: Chunk) -> Self {
-        VM {
-            stack: Stack::new(),
+    /// Creates a VM that executes the specified program, starting at its entry point. The
+    /// program is verified beforehand, such that untrusted bytecode can be loaded safely.
+    pub fn new(program: Program) -> Result<Self, VMError> {
+        Self::with_stack_limit(program, DEFAULT_STACK_LIMIT)
+    }
+
+    /// Creates a VM like `VM::new`, with a stack that grows up to `stack_limit` bytes.
+    pub fn with_stack_limit(program: Program, stack_limit: usize) -> Result<Self, VMError> {
+        let policy = SandboxPolicy::permissive().with_memory_limit(stack_limit);
+        Self::sandboxed(program, NativeRegistry::new(), policy)
+    }
+
+    /// Creates a VM like `VM::new`, which provides the specified native functions to the
+    /// program. Every native imported by the program has to be part of the registry.
+    pub fn with_natives(program: Program, natives: NativeRegistry) -> Result<Self, VMError> {
+        Self::sandboxed(program, natives, SandboxPolicy::permissive())
+    }
+
+    /// Creates a VM that runs the program within the restrictions of the sandbox policy. The
+    /// native imports of the program are linked against the registry, and programs that import
+    /// natives the policy does not allow are rejected.
+    pub fn sandboxed(program: Program, natives: NativeRegistry, policy: SandboxPolicy) -> Result<Self, VMError> {
+        let entry = program.entry_point()
+            .ok_or(VMError::NoEntryPoint)?;
+        verify_program(&program)
+            .map_err(VMError::VerifyError)?;
+        let links = natives.link(program.natives(), &policy)
+            .map_err(VMError::LinkError)?;
+        let stack_limit = policy.memory_limit().unwrap_or(DEFAULT_STACK_LIMIT);
+
+        Ok(VM {
+            stack: Stack::new(stack_limit),
             ip: 0,
+            op_ip: 0,
             fp: 0,
+            frames: Vec::new(),
 
-            exit_code: 0,
-            is_active: true,
-            chunk,
+            exit: None,
+            fuel: 0,
+            fuel_consumed: 0,
+            program,
+            chunk: entry,
+            entry,
+            natives,
+            links,
+            policy,
 
             use_jit: false,
-        }
+        })
+    }
+
+    /// Resets the VM to the state it was in after construction, such that the program can be
+    /// executed again from the start. The consumed fuel is kept, as the fuel limit of the sandbox
+    /// policy applies to the lifetime of the VM.
+    pub fn reset(&mut self) {
+        self.stack.clear();
+        self.ip = 0;
+        self.op_ip = 0;
+        self.fp = 0;
+        self.frames.clear();
+        self.exit = None;
+        self.chunk = self.entry;
+        self.fuel = 0;
+    }
+
+    /// Returns the program executed by the VM.
+    pub fn program(&self) -> &Program {
+        &self.program
     }
 
-    /// Returns the next byte in program memory and increments the instruction pointer.
-    fn fetch(&mut self) -> Result<OpCode, VMError> {
-        let out = OpCode::try_from((self.ip, &self.chunk))
-            .map_err(|e| UnknownOpCode(e))?;
-        self.ip += out.size();
-        Ok(out)
+    /// Returns the chunk index of the function that is currently being executed.
+    pub fn function(&self) -> usize {
+        self.chunk
+    }
+
+    /// Returns the position of the next instruction within the chunk of the current function.
+    pub fn ip(&self) -> usize {
+        self.ip
+    }
+
+    /// Returns the chunk indices of all functions that have not returned yet, starting with the
+    /// entry point and ending with the current function.
+    pub fn call_stack(&self) -> impl Iterator<Item = usize> + '_ {
+        self.frames.iter()
+            .map(|f| f.chunk)
+            .chain(std::iter::once(self.chunk))
+    }
+
+    /// Returns the sandbox policy the program runs under.
+    pub fn policy(&self) -> &SandboxPolicy {
+        &self.policy
+    }
+
+    /// Returns `true` as long as the program has not finished.
+    pub fn is_active(&self) -> bool {
+        self.exit.is_none()
+    }
+
+    /// Returns the value returned by the entry point, once the program has finished.
+    pub fn exit_status(&self) -> Option<&ExitStatus> {
+        self.exit.as_ref()
+    }
+
+    /// Returns the chunk that is currently being executed.
+    fn current_chunk(&self) -> &Chunk {
+        &self.program.chunks()[self.chunk]
+    }
+
+    /// Decodes the instruction at the instruction pointer without executing it.
+    fn decode(&self) -> Result<OpCode, VMError> {
+        OpCode::try_from((self.ip, self.current_chunk()))
+            .map_err(UnknownOpCode)
     }
 
     /// Returns the next byte in program memory without incrementing the instruction counter.
     fn peak(&mut self) -> u8 {
-        self.chunk.code()[self.ip]
+        self.current_chunk().code()[self.ip]
+    }
+
+    /// Returns the source position of the instruction that is currently being executed.
+    fn code_pos(&self) -> (u16, u16) {
+        self.current_chunk().lines()[self.op_ip]
     }
 
     /// Executes a single CPU cycle
     pub fn cycle(&mut self) -> Result<(), VMError> {
-        match self.fetch()? {
+        let op = self.decode()?;
+        self.step(op)
+    }
+
+    /// Runs the program until it returns from its entry point. If the program has already
+    /// finished, its exit status is returned again.
+    pub fn run(&mut self) -> Result<ExitStatus, VMError> {
+        while self.exit.is_none() {
+            self.cycle()?;
+        }
+        Ok(self.exit.clone().unwrap())
+    }
+
+    /// Executes a single CPU cycle and reports the instruction to the tracer before and after it
+    /// is executed. Instructions that trap are only reported before their execution.
+    pub fn cycle_traced(&mut self, tracer: &mut dyn Tracer) -> Result<(), VMError> {
+        let op = self.decode()?;
+        tracer.before(self, &op);
+        self.step(op)?;
+        tracer.after(self, &op);
+        Ok(())
+    }
+
+    /// Runs the program like `run`, reporting every instruction to the tracer.
+    pub fn run_traced(&mut self, tracer: &mut dyn Tracer) -> Result<ExitStatus, VMError> {
+        while self.exit.is_none() {
+            self.cycle_traced(tracer)?;
+        }
+        Ok(self.exit.clone().unwrap())
+    }
+
+    /// Runs the program until it finishes or until the fuel runs out. `fuel` is added to the
+    /// fuel left over from previous calls and every instruction consumes `OpCode::cost` units
+    /// before it is executed. Running out of fuel is not an error; calling this function again
+    /// resumes execution where it stopped.
+    pub fn run_with_fuel(&mut self, fuel: u64) -> Result<FuelStatus, VMError> {
+        self.fuel = self.fuel.saturating_add(fuel);
+        while self.exit.is_none() {
+            let op = self.decode()?;
+            let cost = op.cost();
+            if cost > self.fuel {
+                return Ok(FuelStatus::OutOfFuel);
+            }
+            self.fuel -= cost;
+            self.step(op)?;
+        }
+        Ok(FuelStatus::Finished(self.exit.clone().unwrap()))
+    }
+
+    /// Returns the fuel that is left for `run_with_fuel`.
+    pub fn fuel(&self) -> u64 {
+        self.fuel
+    }
+
+    /// Returns the total fuel consumed by all instructions executed since the VM was created,
+    /// whether they were metered or not.
+    pub fn fuel_consumed(&self) -> u64 {
+        self.fuel_consumed
+    }
+
+    /// Executes the decoded instruction at the instruction pointer.
+    fn step(&mut self, op: OpCode) -> Result<(), VMError> {
+        self.op_ip = self.ip;
+        if let Some(limit) = self.policy.fuel_limit() {
+            if self.fuel_consumed + op.cost() > limit {
+                return Err(VMError::RuntimeError(self.code_pos(), RuntimeError::FuelLimitExceeded(limit)));
+            }
+        }
+        self.ip += op.size();
+        self.fuel_consumed += op.cost();
+        self.execute(op)
+            .map_err(|e| VMError::RuntimeError(self.code_pos(), e))
+    }
+
+    /// Executes a single, already decoded instruction.
+    fn execute(&mut self, op: OpCode) -> Result<(), RuntimeError> {
+        match op {
             OpCode::Const(i, s) => {
-                self.stack.push(&self.chunk.vals()[(i as usize)..(i as usize + s as usize)])
+                let vals = self.program.consts(self.chunk);
+                self.stack.push(&vals[(i as usize)..(i as usize + s as usize)])?
+            },
+            OpCode::ConstLong(i, s) => {
+                let vals = self.program.consts(self.chunk);
+                self.stack.push(&vals[i..(i + s)])?
+            },
+            OpCode::Ret(s) => {
+                // move the return value to the start of the frame and drop everything else
+                self.stack.collapse(self.fp, s)?;
+
+                match self.frames.pop() {
+                    Some(frame) => {
+                        self.stack.pop_frame(FRAME_SIZE);
+                        self.ip = frame.ret_ip;
+                        self.fp = frame.fp;
+                        self.chunk = frame.chunk;
+                    },
+                    None => {
+                        // returning from the entry function terminates the program
+                        self.exit = Some(ExitStatus {
+                            value: self.stack[self.fp..(self.fp + s)].to_vec(),
+                            ty: self.program.return_type(self.chunk),
+                        });
+                    },
+                }
             },
-            OpCode::Ret => {
-                // TODO temporary
-                if self.stack.len() <= 8 {
-                    let test: f64 = self.stack.pop_value();
-                    println!("stack as f64: {test}");
-
-                    self.exit_code = self.stack[0] as i32;
-                    self.is_active = false;
-                    return Ok(())
+            OpCode::Call(f, a) => {
+                if self.program.chunk(f).is_none() {
+                    return Err(RuntimeError::UnknownFunction(f));
                 }
+                let fp = self.stack.len()
+                    .checked_sub(a)
+                    .ok_or(RuntimeError::StackUnderflow)?;
+                self.stack.push_frame(FRAME_SIZE)?;
+                self.frames.push(CallFrame {
+                    ret_ip: self.ip,
+                    fp: self.fp,
+                    chunk: self.chunk,
+                });
+                self.fp = fp;
+                self.chunk = f;
+                self.ip = 0;
             },
+            OpCode::CallNative(i) => self.natives.call(self.links[i], &mut self.stack)?,
 
 
-            OpCode::Neg(NumeralType::I8) => {
-                let val: i8 = self.stack.pop_value();
-                self.stack.push_value(-val);
+            OpCode::Neg(n) => match n {
+                NumeralType::I8 => impl_checked_neg!(self, i8, n),
+                NumeralType::I16 => impl_checked_neg!(self, i16, n),
+                NumeralType::I32 => impl_checked_neg!(self, i32, n),
+                NumeralType::I64 => impl_checked_neg!(self, i64, n),
+                NumeralType::I128 => impl_checked_neg!(self, i128, n),
+                NumeralType::F32 => impl_unop!(self, f32, |a: f32| -a),
+                NumeralType::F64 => impl_unop!(self, f64, |a: f64| -a),
+                n => return Err(RuntimeError::IllegalOperation(
+                    format!("Cannot negate value of unsigned type {:?}", n)
+                )),
             },
-            OpCode::Neg(NumeralType::F64) => {
-                let val: f64 = self.stack.pop_value();
-                self.stack.push_value(-val);
-            }
 
-            OpCode::Add(NumeralType::F64) => impl_binop!(self, f64, |a, b| a + b),
-            OpCode::Sub(NumeralType::F64) => impl_binop!(self, f64, |a, b| a - b),
-            OpCode::Mul(NumeralType::F64) => impl_binop!(self, f64, |a, b| a * b),
-            OpCode::Div(NumeralType::F64) => impl_binop!(self, f64, |a, b| a / b),
+            OpCode::Add(n) => impl_arith_binop!(self, n, checked_add, |a, b| a + b),
+            OpCode::Sub(n) => impl_arith_binop!(self, n, checked_sub, |a, b| a - b),
+            OpCode::Mul(n) => impl_arith_binop!(self, n, checked_mul, |a, b| a * b),
+            OpCode::Div(n) => impl_arith_binop!(self, n, checked_div, |a, b| a / b),
+            OpCode::Rem(n) => impl_arith_binop!(self, n, checked_rem, |a, b| a % b),
+
+            OpCode::WrappingAdd(n) => impl_int_binop!(self, n, wrapping_add, false),
+            OpCode::WrappingSub(n) => impl_int_binop!(self, n, wrapping_sub, false),
+            OpCode::WrappingMul(n) => impl_int_binop!(self, n, wrapping_mul, false),
+            OpCode::WrappingDiv(n) => impl_int_binop!(self, n, wrapping_div, true),
+            OpCode::SaturatingAdd(n) => impl_int_binop!(self, n, saturating_add, false),
+            OpCode::SaturatingSub(n) => impl_int_binop!(self, n, saturating_sub, false),
+            OpCode::SaturatingMul(n) => impl_int_binop!(self, n, saturating_mul, false),
+            OpCode::SaturatingDiv(n) => impl_int_binop!(self, n, saturating_div, true),
+
+            OpCode::Eq(n) => impl_numeral_binop!(self, n, |a, b| (a == b) as u8),
+            OpCode::Ne(n) => impl_numeral_binop!(self, n, |a, b| (a != b) as u8),
+            OpCode::Lt(n) => impl_numeral_binop!(self, n, |a, b| (a < b) as u8),
+            OpCode::Le(n) => impl_numeral_binop!(self, n, |a, b| (a <= b) as u8),
+            OpCode::Gt(n) => impl_numeral_binop!(self, n, |a, b| (a > b) as u8),
+            OpCode::Ge(n) => impl_numeral_binop!(self, n, |a, b| (a >= b) as u8),
+            OpCode::Not => impl_unop!(self, u8, |a: u8| (a == 0) as u8),
+
+            OpCode::Jump(j) => self.ip += j as usize,
+            OpCode::JumpIfFalse(j) => {
+                let cond: u8 = self.stack.pop_value()?;
+                if cond == 0 {
+                    self.ip += j as usize;
+                }
+            },
+            OpCode::JumpIfFalseOrPop(j) => {
+                if self.stack.peek(1)?[0] == 0 {
+                    self.ip += j as usize;
+                } else {
+                    self.stack.pop(1)?;
+                }
+            },
+            OpCode::JumpIfTrueOrPop(j) => {
+                if self.stack.peek(1)?[0] != 0 {
+                    self.ip += j as usize;
+                } else {
+                    self.stack.pop(1)?;
+                }
+            },
+            OpCode::Loop(j) => self.ip -= j as usize,
+
+            OpCode::BitAnd(n) => impl_bitwise_binop!(self, n, |a, b| a & b),
+            OpCode::BitOr(n) => impl_bitwise_binop!(self, n, |a, b| a | b),
+            OpCode::BitXor(n) => impl_bitwise_binop!(self, n, |a, b| a ^ b),
+            OpCode::BitNot(n) => match n {
+                NumeralType::I8 => impl_unop!(self, i8, |a: i8| !a),
+                NumeralType::I16 => impl_unop!(self, i16, |a: i16| !a),
+                NumeralType::I32 => impl_unop!(self, i32, |a: i32| !a),
+                NumeralType::I64 => impl_unop!(self, i64, |a: i64| !a),
+                NumeralType::I128 => impl_unop!(self, i128, |a: i128| !a),
+                NumeralType::U8 => impl_unop!(self, u8, |a: u8| !a),
+                NumeralType::U16 => impl_unop!(self, u16, |a: u16| !a),
+                NumeralType::U32 => impl_unop!(self, u32, |a: u32| !a),
+                NumeralType::U64 => impl_unop!(self, u64, |a: u64| !a),
+                NumeralType::U128 => impl_unop!(self, u128, |a: u128| !a),
+                n => return Err(RuntimeError::IllegalOperation(
+                    format!("Bitwise operations are not defined for type {:?}", n)
+                )),
+            },
+            OpCode::Shl(n) => impl_int_shift!(self, n, checked_shl),
+            OpCode::Shr(n) => impl_int_shift!(self, n, checked_shr),
+
+            OpCode::Cast(from, to) => impl_cast!(self, from, to),
 
-            _ => {}
+            OpCode::LoadLocal(o, s) => self.stack.load(self.fp + o, s)?,
+            OpCode::StoreLocal(o, s) => self.stack.store(self.fp + o, s)?,
+            OpCode::Pop(s) => self.stack.pop(s)?,
+            OpCode::Dup(s) => {
+                let top = self.stack.len()
+                    .checked_sub(s)
+                    .ok_or(RuntimeError::StackUnderflow)?;
+                self.stack.load(top, s)?
+            },
         }
 
         Ok(())
@@ -218,7 +963,491 @@ impl VM {
 
 impl Debug for VM {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        self.chunk.disassemble(self.ip, 1, f)?;
+        self.current_chunk().disassemble(self.ip, 1, f)?;
         f.write_str(&format!("      {:?}", self.stack))
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use crate::bytecode::chunk::{Chunk, Value};
+    use crate::bytecode::opcode::{NumeralType, OpCode};
+    use crate::bytecode::asm::assemble;
+    use crate::bytecode::program::Program;
+    use crate::lang::function::FerrumFunctionPtr;
+    use crate::vm::{FuelStatus, RuntimeError, Stack, VM, VMError, DEFAULT_STACK_LIMIT, FRAME_SIZE};
+
+    /// Runs `Cast(from, to)` on the value `a` in the VM and returns the resulting value.
+    fn run_cast<const N: usize, const M: usize, A: Value<N>, B: Value<M>>(
+        a: A, from: NumeralType, to: NumeralType
+    ) -> B {
+        let mut chunk = Chunk::new(String::from("cast"));
+        let i = chunk.write_value(a);
+        chunk.write(OpCode::Const(i as u8, N as u8), 0, 0);
+        chunk.write(OpCode::Cast(from, to), 0, 0);
+        chunk.write(OpCode::Ret(M), 0, 0);
+
+        let mut vm = VM::new(Program::from(chunk)).unwrap();
+        vm.cycle().unwrap();
+        vm.cycle().unwrap();
+        assert_eq!(vm.stack.len(), M);
+        vm.stack.pop_value().unwrap()
+    }
+
+    macro_rules! assert_cast(
+        ($a:expr, $from:expr, $T:ty, $to:expr) => {
+            {
+                let got: $T = run_cast($a, $from, $to);
+                assert_eq!(
+                    got.to_le_bytes(), ($a as $T).to_le_bytes(),
+                    "{:?} as {}", $a, stringify!($T)
+                );
+            }
+        };
+    );
+
+    /// Checks the cast of `$a` into every numeral type against Rust's `as` operator.
+    macro_rules! assert_casts(
+        ($from:expr, [$($a:expr),+]) => {
+            $(
+                assert_cast!($a, $from, i8, NumeralType::I8);
+                assert_cast!($a, $from, i16, NumeralType::I16);
+                assert_cast!($a, $from, i32, NumeralType::I32);
+                assert_cast!($a, $from, i64, NumeralType::I64);
+                assert_cast!($a, $from, i128, NumeralType::I128);
+                assert_cast!($a, $from, u8, NumeralType::U8);
+                assert_cast!($a, $from, u16, NumeralType::U16);
+                assert_cast!($a, $from, u32, NumeralType::U32);
+                assert_cast!($a, $from, u64, NumeralType::U64);
+                assert_cast!($a, $from, u128, NumeralType::U128);
+                assert_cast!($a, $from, f32, NumeralType::F32);
+                assert_cast!($a, $from, f64, NumeralType::F64);
+            )+
+        };
+    );
+
+    #[test]
+    fn cast_signed() {
+        assert_casts!(NumeralType::I8, [0i8, 1i8, -1i8, i8::MIN, i8::MAX]);
+        assert_casts!(NumeralType::I16, [0i16, -1i16, 300i16, -300i16, i16::MIN, i16::MAX]);
+        assert_casts!(NumeralType::I32, [0i32, -1i32, 70_000i32, -70_000i32, i32::MIN, i32::MAX, 16_777_217i32]);
+        assert_casts!(NumeralType::I64, [0i64, -1i64, 5_000_000_000i64, i64::MIN, i64::MAX, 9_007_199_254_740_993i64]);
+        assert_casts!(NumeralType::I128, [0i128, -1i128, i128::MIN, i128::MAX, (u64::MAX as i128) + 1]);
+    }
+
+    #[test]
+    fn cast_unsigned() {
+        assert_casts!(NumeralType::U8, [0u8, 1u8, 128u8, u8::MAX]);
+        assert_casts!(NumeralType::U16, [0u16, 255u16, 256u16, 40_000u16, u16::MAX]);
+        assert_casts!(NumeralType::U32, [0u32, 65_536u32, 3_000_000_000u32, u32::MAX]);
+        assert_casts!(NumeralType::U64, [0u64, 1u64 << 40, 1u64 << 63, u64::MAX]);
+        assert_casts!(NumeralType::U128, [0u128, 1u128 << 64, 1u128 << 127, u128::MAX]);
+    }
+
+    #[test]
+    #[allow(clippy::cast_nan_to_int)]
+    fn cast_float() {
+        assert_casts!(NumeralType::F32, [
+            0f32, -0f32, 1.5f32, -1.5f32, 2.5f32, -2.5f32, 300.7f32, -129.9f32, 1e20f32, -1e20f32,
+            f32::MAX, f32::MIN, f32::MIN_POSITIVE, f32::INFINITY, f32::NEG_INFINITY, f32::NAN
+        ]);
+        assert_casts!(NumeralType::F64, [
+            0f64, -0f64, 1.5f64, -1.5f64, 2.5f64, 65_535.99f64, -32_768.5f64, 4e9f64, 1e40f64,
+            -1e40f64, 3.4e38f64, 1e300f64, f64::MAX, f64::MIN, f64::MIN_POSITIVE, f64::INFINITY,
+            f64::NEG_INFINITY, f64::NAN
+        ]);
+    }
+
+    /// Runs the instruction `op` on the operands `a` and `b` in the VM and returns the result. The
+    /// operands are pushed at line 1 and 2, `op` is located at line 3, char 7.
+    fn run_binop<const N: usize, A: Value<N>>(a: A, b: A, op: OpCode) -> Result<A, VMError> {
+        let mut chunk = Chunk::new(String::from("binop"));
+        chunk.write_const(a, 1, 0);
+        chunk.write_const(b, 2, 0);
+        chunk.write(op, 3, 7);
+        chunk.write(OpCode::Ret(N), 4, 0);
+
+        let mut vm = VM::new(Program::from(chunk))?;
+        Ok(vm.run()?.get().unwrap())
+    }
+
+    /// Runs the instruction `op` on the operand `a` in the VM and returns the result.
+    fn run_unop<const N: usize, A: Value<N>>(a: A, op: OpCode) -> Result<A, VMError> {
+        let mut chunk = Chunk::new(String::from("unop"));
+        chunk.write_const(a, 1, 0);
+        chunk.write(op, 2, 7);
+        chunk.write(OpCode::Ret(N), 3, 0);
+
+        let mut vm = VM::new(Program::from(chunk))?;
+        Ok(vm.run()?.get().unwrap())
+    }
+
+    /// Checks `Add`, `Sub`, `Mul`, `Div` and `Rem` of every operand pair against Rust's operators.
+    macro_rules! assert_arith(
+        ($n:expr, $T:ty, [$(($a:expr, $b:expr)),+]) => {
+            $(
+                {
+                    let (a, b): ($T, $T) = ($a, $b);
+                    assert_eq!(run_binop(a, b, OpCode::Add($n)).unwrap(), a + b, "{:?} + {:?}", a, b);
+                    assert_eq!(run_binop(a, b, OpCode::Sub($n)).unwrap(), a - b, "{:?} - {:?}", a, b);
+                    assert_eq!(run_binop(a, b, OpCode::Mul($n)).unwrap(), a * b, "{:?} * {:?}", a, b);
+                    assert_eq!(run_binop(a, b, OpCode::Div($n)).unwrap(), a / b, "{:?} / {:?}", a, b);
+                    assert_eq!(run_binop(a, b, OpCode::Rem($n)).unwrap(), a % b, "{:?} % {:?}", a, b);
+                }
+            )+
+        };
+    );
+
+    #[test]
+    fn arithmetic() {
+        assert_arith!(NumeralType::I8, i8, [(7, 3), (-7, 2), (60, -2)]);
+        assert_arith!(NumeralType::I16, i16, [(7, 3), (-7, 2), (-300, -7)]);
+        assert_arith!(NumeralType::I32, i32, [(7, 3), (-7, 2), (70_000, -13)]);
+        assert_arith!(NumeralType::I64, i64, [(7, 3), (-7, 2), (5_000_000_000, -3)]);
+        assert_arith!(NumeralType::I128, i128, [(7, 3), (-7, 2), (i128::MAX / 2, -2)]);
+        assert_arith!(NumeralType::U8, u8, [(7, 3), (20, 6), (100, 2)]);
+        assert_arith!(NumeralType::U16, u16, [(7, 3), (20, 6), (300, 200)]);
+        assert_arith!(NumeralType::U32, u32, [(7, 3), (20, 6), (70_000, 13)]);
+        assert_arith!(NumeralType::U64, u64, [(7, 3), (20, 6), (5_000_000_000, 3)]);
+        assert_arith!(NumeralType::U128, u128, [(7, 3), (20, 6), (u128::MAX / 2, 2)]);
+        assert_arith!(NumeralType::F32, f32, [(7.5, 2.0), (-1.25, 0.5), (1e30, -3.0)]);
+        assert_arith!(NumeralType::F64, f64, [(7.5, 2.0), (-1.25, 0.5), (1e300, -3.0)]);
+
+        assert_eq!(run_unop(-7i8, OpCode::Neg(NumeralType::I8)).unwrap(), 7);
+        assert_eq!(run_unop(7i16, OpCode::Neg(NumeralType::I16)).unwrap(), -7);
+        assert_eq!(run_unop(i32::MAX, OpCode::Neg(NumeralType::I32)).unwrap(), -i32::MAX);
+        assert_eq!(run_unop(7i64, OpCode::Neg(NumeralType::I64)).unwrap(), -7);
+        assert_eq!(run_unop(i128::MIN + 1, OpCode::Neg(NumeralType::I128)).unwrap(), i128::MAX);
+        assert_eq!(run_unop(1.5f32, OpCode::Neg(NumeralType::F32)).unwrap(), -1.5);
+        assert_eq!(run_unop(-1.5f64, OpCode::Neg(NumeralType::F64)).unwrap(), 1.5);
+        match run_unop(7u8, OpCode::Neg(NumeralType::U8)) {
+            Err(VMError::RuntimeError(pos, RuntimeError::IllegalOperation(_))) => assert_eq!(pos, (2, 7)),
+            r => panic!("expected negating an u8 to fail, got {:?}", r),
+        }
+    }
+
+    /// Checks the wrapping and saturating variants of an operation against Rust's methods.
+    macro_rules! assert_modes(
+        ($n:expr, $T:ty, [$(($a:expr, $b:expr)),+]) => {
+            $(
+                {
+                    let (a, b): ($T, $T) = ($a, $b);
+                    assert_eq!(run_binop(a, b, OpCode::WrappingAdd($n)).unwrap(), a.wrapping_add(b));
+                    assert_eq!(run_binop(a, b, OpCode::WrappingSub($n)).unwrap(), a.wrapping_sub(b));
+                    assert_eq!(run_binop(a, b, OpCode::WrappingMul($n)).unwrap(), a.wrapping_mul(b));
+                    assert_eq!(run_binop(a, b, OpCode::WrappingDiv($n)).unwrap(), a.wrapping_div(b));
+                    assert_eq!(run_binop(a, b, OpCode::SaturatingAdd($n)).unwrap(), a.saturating_add(b));
+                    assert_eq!(run_binop(a, b, OpCode::SaturatingSub($n)).unwrap(), a.saturating_sub(b));
+                    assert_eq!(run_binop(a, b, OpCode::SaturatingMul($n)).unwrap(), a.saturating_mul(b));
+                    assert_eq!(run_binop(a, b, OpCode::SaturatingDiv($n)).unwrap(), a.saturating_div(b));
+                }
+            )+
+        };
+    );
+
+    #[test]
+    fn arithmetic_traps() {
+        let overflows = [
+            run_binop(i8::MAX, 1, OpCode::Add(NumeralType::I8)).err(),
+            run_binop(i16::MIN, 1, OpCode::Sub(NumeralType::I16)).err(),
+            run_binop(i32::MIN, -1, OpCode::Div(NumeralType::I32)).err(),
+            run_binop(i32::MIN, -1, OpCode::Rem(NumeralType::I32)).err(),
+            run_binop(0u8, 1, OpCode::Sub(NumeralType::U8)).err(),
+            run_binop(u64::MAX, 2, OpCode::Mul(NumeralType::U64)).err(),
+            run_binop(u128::MAX, 1, OpCode::Add(NumeralType::U128)).err(),
+            run_unop(i64::MIN, OpCode::Neg(NumeralType::I64)).err(),
+        ];
+        for err in overflows {
+            match err {
+                Some(VMError::RuntimeError(_, RuntimeError::IntegerOverflow(_))) => (),
+                e => panic!("expected an overflow, got {:?}", e),
+            }
+        }
+
+        let divisions = [
+            run_binop(7i32, 0, OpCode::Div(NumeralType::I32)).err(),
+            run_binop(7u8, 0, OpCode::Rem(NumeralType::U8)).err(),
+            run_binop(i128::MIN, 0, OpCode::Rem(NumeralType::I128)).err(),
+            run_binop(7u16, 0, OpCode::WrappingDiv(NumeralType::U16)).err(),
+            run_binop(7i64, 0, OpCode::SaturatingDiv(NumeralType::I64)).err(),
+        ];
+        for err in divisions {
+            match err {
+                Some(VMError::RuntimeError(pos, RuntimeError::DivisionByZero)) => assert_eq!(pos, (3, 7)),
+                e => panic!("expected a division by zero, got {:?}", e),
+            }
+        }
+
+        // floats follow IEEE semantics instead of trapping
+        assert_eq!(run_binop(1.0f64, 0.0, OpCode::Div(NumeralType::F64)).unwrap(), f64::INFINITY);
+        assert!(run_binop(f32::MAX, 2.0, OpCode::WrappingAdd(NumeralType::F32)).is_err());
+    }
+
+    #[test]
+    fn wrapping_and_saturating() {
+        assert_modes!(NumeralType::I8, i8, [(i8::MAX, 1), (i8::MIN, -1), (100, 3)]);
+        assert_modes!(NumeralType::I16, i16, [(i16::MAX, 2), (i16::MIN, -1), (-300, 7)]);
+        assert_modes!(NumeralType::I32, i32, [(i32::MAX, 1), (i32::MIN, -1), (-70_000, 3)]);
+        assert_modes!(NumeralType::I64, i64, [(i64::MAX, 3), (i64::MIN, -1), (5, -2)]);
+        assert_modes!(NumeralType::I128, i128, [(i128::MAX, 1), (i128::MIN, -1), (5, -2)]);
+        assert_modes!(NumeralType::U8, u8, [(u8::MAX, 1), (0, 1), (100, 3)]);
+        assert_modes!(NumeralType::U16, u16, [(u16::MAX, 2), (0, 1), (300, 7)]);
+        assert_modes!(NumeralType::U32, u32, [(u32::MAX, 1), (0, 1), (70_000, 3)]);
+        assert_modes!(NumeralType::U64, u64, [(u64::MAX, 3), (0, 1), (5, 2)]);
+        assert_modes!(NumeralType::U128, u128, [(u128::MAX, 1), (0, 1), (5, 2)]);
+    }
+
+    #[test]
+    fn jumps() {
+        // sums up the numbers from 4 down to 1, the store behind the forward jump never executes
+        let src = "
+            .const n i32 4
+            .const zero i32 0
+            .const one i32 1
+            .const bad i32 1000
+                CONST zero
+                CONST n
+            top:
+                LOAD 4 4
+                CONST zero
+                GT I32
+                JMPF end
+                LOAD 0 4
+                LOAD 4 4
+                ADD I32
+                STORE 0 4
+                LOAD 4 4
+                CONST one
+                SUB I32
+                STORE 4 4
+                JMP skip
+                CONST bad
+                STORE 0 4
+            skip:
+                LOOP top
+            end:
+                POP 4
+                RET 4
+        ";
+        let mut vm = VM::new(Program::from(assemble(src).unwrap())).unwrap();
+        assert_eq!(vm.run().unwrap().get::<4, i32>(), Some(10));
+
+        // the condition is kept if it decides the result and popped otherwise
+        for (jump, cond, result) in [("JFOP", 0, 0), ("JFOP", 1, 7), ("JTOP", 1, 1), ("JTOP", 0, 7)] {
+            let src = format!("
+                .const cond u8 {cond}
+                .const seven u8 7
+                    CONST cond
+                    {jump} end
+                    CONST seven
+                end:
+                    RET 1
+            ");
+            let mut vm = VM::new(Program::from(assemble(&src).unwrap())).unwrap();
+            let status = vm.run().unwrap();
+            assert_eq!(status.value(), &[result], "{} with {}", jump, cond);
+        }
+    }
+
+    #[test]
+    fn shifts() {
+        // right shifts are arithmetic for signed and logical for unsigned types
+        assert_eq!(run_binop(-16i8, 2, OpCode::Shr(NumeralType::I8)).unwrap(), -4);
+        assert_eq!(run_binop(0xf0u8, 2, OpCode::Shr(NumeralType::U8)).unwrap(), 0x3c);
+        assert_eq!(run_binop(-1i32, 31, OpCode::Shr(NumeralType::I32)).unwrap(), -1);
+        assert_eq!(run_binop(u32::MAX, 31, OpCode::Shr(NumeralType::U32)).unwrap(), 1);
+        assert_eq!(run_binop(i128::MIN, 127, OpCode::Shr(NumeralType::I128)).unwrap(), -1);
+        assert_eq!(run_binop(u128::MAX, 127, OpCode::Shr(NumeralType::U128)).unwrap(), 1);
+        assert_eq!(run_binop(1i16, 15, OpCode::Shl(NumeralType::I16)).unwrap(), i16::MIN);
+        assert_eq!(run_binop(0x81u8, 1, OpCode::Shl(NumeralType::U8)).unwrap(), 0x02);
+
+        let overflows = [
+            run_binop(1u8, 8, OpCode::Shl(NumeralType::U8)).err(),
+            run_binop(1i32, 32, OpCode::Shr(NumeralType::I32)).err(),
+            run_binop(1i32, -1, OpCode::Shl(NumeralType::I32)).err(),
+            run_binop(1u64, 64, OpCode::Shr(NumeralType::U64)).err(),
+            run_binop(1i128, 128, OpCode::Shl(NumeralType::I128)).err(),
+        ];
+        for err in overflows {
+            match err {
+                Some(VMError::RuntimeError(pos, RuntimeError::ShiftOverflow(_))) => assert_eq!(pos, (3, 7)),
+                e => panic!("expected a shift overflow, got {:?}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn stack_overflow() {
+        let chunk = assemble("
+            .const one i32 1
+                CONST one
+                CONST one @ 7:3
+                RET 4
+        ").unwrap();
+        let mut vm = VM::with_stack_limit(Program::from(chunk), 6).unwrap();
+        vm.cycle().unwrap();
+        match vm.cycle() {
+            Err(VMError::RuntimeError(pos, RuntimeError::StackOverflow(6))) => assert_eq!(pos, (7, 3)),
+            r => panic!("expected a stack overflow, got {:?}", r),
+        }
+
+        let mut stack = Stack::new(4);
+        assert!(matches!(stack.pop(1), Err(RuntimeError::StackUnderflow)));
+        assert!(matches!(stack.push(&[0; 5]), Err(RuntimeError::StackOverflow(4))));
+        stack.push(&[1, 2]).unwrap();
+        assert!(matches!(stack.load(1, 2), Err(RuntimeError::StackUnderflow)));
+        assert!(matches!(stack.store(1, 1), Err(RuntimeError::StackUnderflow)));
+    }
+
+    #[test]
+    fn recursion_overflow() {
+        let endless = "
+                CALL 0 0
+                RET 0
+        ";
+        let mut vm = VM::with_stack_limit(Program::from(assemble(endless).unwrap()), 1024).unwrap();
+        match vm.run() {
+            Err(VMError::RuntimeError(_, RuntimeError::StackOverflow(1024))) => (),
+            r => panic!("expected a stack overflow, got {:?}", r),
+        }
+        assert_eq!(vm.call_stack().count(), 1024 / FRAME_SIZE + 1);
+
+        // frames and values share the limit
+        vm.reset();
+        assert!(vm.stack.push(&[0; 1024]).is_ok());
+        vm.stack.pop(1024).unwrap();
+
+        let mut vm = VM::new(Program::from(assemble(endless).unwrap())).unwrap();
+        assert!(matches!(vm.run(), Err(VMError::RuntimeError(_, RuntimeError::StackOverflow(DEFAULT_STACK_LIMIT)))));
+    }
+
+    #[test]
+    fn return_in_place() {
+        let mut program = Program::new();
+        let main = assemble("
+            .const seven i32 7
+                CONST seven
+                CALL 1 4
+                RET 4
+        ").unwrap();
+        let id = assemble("RET 4").unwrap();
+        program.add_function(FerrumFunctionPtr::new(String::from("main"), 0), main);
+        program.add_function(FerrumFunctionPtr::new(String::from("id"), 0), id);
+
+        let mut vm = VM::new(program).unwrap();
+        assert_eq!(vm.run().unwrap().get::<4, i32>(), Some(7));
+    }
+
+    #[test]
+    fn fuel() {
+        let endless = assemble("
+            top:
+                LOOP top
+        ").unwrap();
+        let mut vm = VM::new(Program::from(endless)).unwrap();
+        assert_eq!(vm.run_with_fuel(10).unwrap(), FuelStatus::OutOfFuel);
+        assert_eq!(vm.run_with_fuel(10).unwrap(), FuelStatus::OutOfFuel);
+        assert_eq!(vm.fuel_consumed(), 20);
+
+        let src = "
+            .const n i32 100
+            .const one i32 1
+            .const zero i32 0
+                CONST n
+            top:
+                DUP 4
+                CONST zero
+                GT I32
+                JMPF end
+                CONST one
+                SUB I32
+                LOOP top
+            end:
+                RET 4
+        ";
+        let mut vm = VM::new(Program::from(assemble(src).unwrap())).unwrap();
+        assert!(matches!(vm.run_with_fuel(u64::MAX).unwrap(), FuelStatus::Finished(_)));
+        let total = vm.fuel_consumed();
+
+        // running in small slices has to consume exactly the same amount of fuel
+        let mut vm = VM::new(Program::from(assemble(src).unwrap())).unwrap();
+        let mut slices = 0;
+        while vm.run_with_fuel(7).unwrap() == FuelStatus::OutOfFuel {
+            slices += 1;
+        }
+        assert_eq!(vm.fuel_consumed(), total);
+        assert_eq!(slices, total / 7);
+    }
+
+    #[test]
+    fn run_and_reset() {
+        let src = "
+            .const minus_one i8 -1
+                CONST minus_one
+                RET 1
+        ";
+        let mut vm = VM::new(Program::from(assemble(src).unwrap())).unwrap();
+        let status = vm.run().unwrap();
+        assert_eq!(status.value(), &[0xff]);
+        assert_eq!(status.return_type(), None);
+        assert_eq!(status.code(), 255);
+
+        let mut program = Program::from(assemble(src).unwrap());
+        program.set_return_type(&FerrumFunctionPtr::new(String::from("main"), 0), Some(NumeralType::I8));
+        let mut vm = VM::new(program).unwrap();
+        let status = vm.run().unwrap();
+        assert_eq!(status.get::<1, i8>(), Some(-1));
+        assert_eq!(status.code(), -1);
+        assert!(!vm.is_active());
+
+        vm.reset();
+        assert!(vm.is_active());
+        assert_eq!(vm.run().unwrap(), status);
+    }
+
+    #[test]
+    fn locals() {
+        let mut program = Program::new();
+        let main = assemble("
+            .const hundred i32 100
+            .const three i32 3
+            .const four i32 4
+                CONST hundred
+                CONST three
+                CONST four
+                CALL 1 8
+                LOAD 0 4
+                ADD I32
+                RET 4
+        ").unwrap();
+        // locals are addressed relative to the frame, which starts at the arguments
+        let callee = assemble("
+            .const one i32 1
+                LOAD 0 4
+                LOAD 4 4
+                MUL I32
+                STORE 0 4
+                LOAD 0 4
+                DUP 4
+                ADD I32
+                STORE 4 4
+                CONST one
+                POP 4
+                LOAD 4 4
+                RET 4
+        ").unwrap();
+        program.add_function(FerrumFunctionPtr::new(String::from("main"), 0), main);
+        program.add_function(FerrumFunctionPtr::new(String::from("f"), 0), callee);
+
+        let mut vm = VM::new(program).unwrap();
+        assert_eq!(vm.run().unwrap().get::<4, i32>(), Some(124));
+
+        let underflow = assemble("
+            .const one i32 1
+                CONST one
+                DUP 8
+        ").unwrap();
+        assert!(matches!(VM::new(Program::from(underflow)), Err(VMError::VerifyError(_))));
+    }
+}
@@ -0,0 +1,252 @@
+//! Code coverage of programs. `Coverage` is a tracer that counts how often every instruction is
+//! executed, and how often every conditional jump is taken. The counts are mapped back to source
+//! lines with the line table of each chunk and can be written as an LCOV tracefile.
+//!
+//! Branches are derived from conditional jumps, which is how both arms of an `if` and every case
+//! of a `match` are selected in bytecode. Each conditional jump yields one LCOV branch pair:
+//! branch 0 is the fall-through path, i.e. the arm that runs if the condition holds, and branch 1
+//! is the path of the jump.
+
+use std::collections::{BTreeMap, HashMap};
+use crate::bytecode::chunk::Chunk;
+use crate::bytecode::opcode::OpCode;
+use crate::bytecode::program::Program;
+use crate::vm::trace::Tracer;
+use crate::vm::VM;
+
+/// Coverage of a single conditional jump.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BranchCoverage {
+    /// source line of the jump
+    pub line: u16,
+    /// number of times execution fell through the jump
+    pub fallthrough: u64,
+    /// number of times the jump was taken
+    pub taken: u64,
+}
+
+#[derive(Default)]
+pub struct Coverage {
+    /// execution count of every instruction, indexed by chunk and code position
+    hits: Vec<Vec<u64>>,
+    /// number of calls of every function, indexed by chunk
+    calls: Vec<u64>,
+    /// (fall-through, taken) counts of every executed conditional jump by chunk and position
+    branches: HashMap<(usize, usize), (u64, u64)>,
+    /// conditional jump that is currently being executed
+    jump: Option<(usize, usize)>,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns how often the instruction at code position `pos` of chunk `chunk` has been
+    /// executed.
+    pub fn hits(&self, chunk: usize, pos: usize) -> u64 {
+        self.hits.get(chunk)
+            .and_then(|h| h.get(pos))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Returns how often the function with chunk index `chunk` has been called.
+    pub fn calls(&self, chunk: usize) -> u64 {
+        self.calls.get(chunk).copied().unwrap_or(0)
+    }
+
+    /// Returns the execution count of every source line with code. The count of a line is the
+    /// highest count of the instructions compiled from it. Lines are merged by their number
+    /// alone, which assumes that all chunks of the program are compiled from one source file.
+    pub fn lines(&self, program: &Program) -> BTreeMap<u16, u64> {
+        let mut lines = BTreeMap::new();
+        for (c, chunk) in program.chunks().iter().enumerate() {
+            for (pos, _) in instructions(chunk) {
+                let count = lines.entry(chunk.lines()[pos].0).or_insert(0);
+                *count = u64::max(*count, self.hits(c, pos));
+            }
+        }
+        lines
+    }
+
+    /// Returns the coverage of every conditional jump of the program, in the order of the source
+    /// lines. Jumps that were never executed have counts of zero. Like `lines`, this assumes
+    /// that all chunks are compiled from one source file.
+    pub fn branches(&self, program: &Program) -> Vec<BranchCoverage> {
+        let mut branches = Vec::new();
+        for (c, chunk) in program.chunks().iter().enumerate() {
+            for (pos, op) in instructions(chunk) {
+                if is_conditional(&op) {
+                    let (fallthrough, taken) = self.branches.get(&(c, pos)).copied().unwrap_or((0, 0));
+                    branches.push(BranchCoverage { line: chunk.lines()[pos].0, fallthrough, taken });
+                }
+            }
+        }
+        branches.sort_by_key(|b| b.line);
+        branches
+    }
+
+    /// Writes the coverage as an LCOV tracefile. All functions of the program are attributed to
+    /// the source file at `source`.
+    pub fn lcov(&self, program: &Program, source: &str) -> String {
+        let mut out = String::new();
+        out.push_str("TN:\n");
+        out.push_str(&format!("SF:{}\n", source));
+
+        let functions: Vec<_> = program.chunks().iter()
+            .enumerate()
+            .map(|(c, chunk)| (chunk.lines().first().map(|l| l.0).unwrap_or(0), chunk.name(), self.calls(c)))
+            .collect();
+        for (line, name, _) in functions.iter() {
+            out.push_str(&format!("FN:{},{}\n", line, name));
+        }
+        for (_, name, calls) in functions.iter() {
+            out.push_str(&format!("FNDA:{},{}\n", calls, name));
+        }
+        out.push_str(&format!("FNF:{}\n", functions.len()));
+        out.push_str(&format!("FNH:{}\n", functions.iter().filter(|f| f.2 > 0).count()));
+
+        let branches = self.branches(program);
+        let mut block = 0;
+        for (i, branch) in branches.iter().enumerate() {
+            // jumps on the same line are numbered consecutively
+            block = match i {
+                0 => 0,
+                _ if branches[i - 1].line == branch.line => block + 1,
+                _ => 0,
+            };
+            let count = |n: u64| match branch.fallthrough + branch.taken {
+                0 => String::from("-"),
+                _ => n.to_string(),
+            };
+            out.push_str(&format!("BRDA:{},{},0,{}\n", branch.line, block, count(branch.fallthrough)));
+            out.push_str(&format!("BRDA:{},{},1,{}\n", branch.line, block, count(branch.taken)));
+        }
+        let hit = branches.iter()
+            .map(|b| (b.fallthrough > 0) as usize + (b.taken > 0) as usize)
+            .sum::<usize>();
+        out.push_str(&format!("BRF:{}\n", 2 * branches.len()));
+        out.push_str(&format!("BRH:{}\n", hit));
+
+        let lines = self.lines(program);
+        for (line, count) in lines.iter() {
+            out.push_str(&format!("DA:{},{}\n", line, count));
+        }
+        out.push_str(&format!("LF:{}\n", lines.len()));
+        out.push_str(&format!("LH:{}\n", lines.values().filter(|c| **c > 0).count()));
+        out.push_str("end_of_record\n");
+        out
+    }
+}
+
+impl Tracer for Coverage {
+    fn before(&mut self, vm: &VM, op: &OpCode) {
+        if self.hits.is_empty() {
+            let chunks = vm.program().chunks();
+            self.hits = chunks.iter().map(|c| vec![0; c.code().len()]).collect();
+            self.calls = vec![0; chunks.len()];
+            // the entry point is not entered by a call instruction
+            self.calls[vm.function()] += 1;
+        }
+        self.hits[vm.function()][vm.ip()] += 1;
+        self.jump = is_conditional(op).then_some((vm.function(), vm.ip()));
+    }
+
+    fn after(&mut self, vm: &VM, op: &OpCode) {
+        if let Some((chunk, pos)) = self.jump.take() {
+            let counts = self.branches.entry((chunk, pos)).or_insert((0, 0));
+            if vm.ip() == pos + op.size() {
+                counts.0 += 1;
+            } else {
+                counts.1 += 1;
+            }
+        }
+        if let OpCode::Call(_, _) = op {
+            self.calls[vm.function()] += 1;
+        }
+    }
+}
+
+/// Returns `true` for jumps that depend on a condition.
+fn is_conditional(op: &OpCode) -> bool {
+    matches!(op, OpCode::JumpIfFalse(_) | OpCode::JumpIfFalseOrPop(_) | OpCode::JumpIfTrueOrPop(_))
+}
+
+/// Decodes all instructions of the chunk together with their code positions.
+fn instructions(chunk: &Chunk) -> Vec<(usize, OpCode)> {
+    let mut ops = Vec::new();
+    let mut pos = 0;
+    while let Ok(op) = OpCode::try_from((pos, chunk)) {
+        ops.push((pos, op));
+        pos += op.size();
+    }
+    ops
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::bytecode::asm::assemble;
+    use crate::bytecode::program::Program;
+    use crate::lang::function::FerrumFunctionPtr;
+    use crate::vm::coverage::Coverage;
+    use crate::vm::VM;
+
+    #[test]
+    fn lcov() {
+        let main = assemble("
+            .chunk main
+            .const a i32 5
+            .const b i32 3
+            .const one i32 1
+                CONST a      @ 1:0
+                LOAD 0 4     @ 2:3
+                CONST b      @ 2:7
+                LT I32       @ 2:5
+                JMPF other   @ 2:0
+                CONST one    @ 3:4
+                JMP end      @ 3:0
+            other:
+                CONST b      @ 5:4
+            end:
+                ADD I32      @ 6:0
+                RET 4        @ 7:0
+        ").unwrap();
+        let unused = assemble("
+            .chunk unused
+                RET 0        @ 10:0
+        ").unwrap();
+        let mut program = Program::new();
+        program.add_function(FerrumFunctionPtr::new(String::from("main"), 0), main);
+        program.add_function(FerrumFunctionPtr::new(String::from("unused"), 0), unused);
+
+        let mut coverage = Coverage::new();
+        let mut vm = VM::new(program).unwrap();
+        assert_eq!(vm.run_traced(&mut coverage).unwrap().get::<4, i32>(), Some(8));
+        assert_eq!(coverage.lcov(vm.program(), "main.fe"), "\
+TN:
+SF:main.fe
+FN:1,main
+FN:10,unused
+FNDA:1,main
+FNDA:0,unused
+FNF:2
+FNH:1
+BRDA:2,0,0,0
+BRDA:2,0,1,1
+BRF:2
+BRH:1
+DA:1,1
+DA:2,1
+DA:3,0
+DA:5,1
+DA:6,1
+DA:7,1
+DA:10,0
+LF:7
+LH:5
+end_of_record
+");
+    }
+}
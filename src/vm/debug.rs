@@ -0,0 +1,433 @@
+//! Source level debugging of programs running in a VM.
+//!
+//! A `Debugger` drives a VM one instruction at a time and maps instructions back to the source
+//! lines they were compiled from, using the line table of each chunk. Breakpoints are set on
+//! source lines and resolve to the first instruction of every run of instructions attributed to
+//! that line. Stepping works on whole lines and uses the call frames of the VM to decide whether
+//! a call is entered, skipped or left. Local variables are looked up by name in the debug
+//! information the compiler records in each chunk.
+
+pub mod dap;
+
+use std::collections::{BTreeSet, HashSet};
+use crate::bytecode::asm::assemble;
+use crate::bytecode::chunk::{CodePos, Value};
+use crate::bytecode::opcode::{NumeralType, OpCode};
+use crate::bytecode::program::Program;
+use crate::vm::native::NativeValue;
+use crate::vm::{ExitStatus, VM, VMError};
+
+/// Reason the debugger handed control back to its caller.
+#[derive(Clone, Debug, PartialEq)]
+pub enum StopReason {
+    /// The next instruction is the first instruction of a breakpoint on the specified line
+    Breakpoint(u16),
+    /// The requested step has been completed
+    Step,
+    /// The program returned from its entry point
+    Finished(ExitStatus),
+}
+
+/// A function call that has not returned yet.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frame {
+    /// name of the chunk of the function
+    function: String,
+    /// source position of the next instruction, or of the pending call for callers
+    pos: CodePos,
+}
+
+impl Frame {
+    pub fn function(&self) -> &str {
+        &self.function
+    }
+
+    pub fn pos(&self) -> CodePos {
+        self.pos
+    }
+}
+
+/// The value of a local variable, copied from the stack.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Local {
+    name: String,
+    value: Vec<u8>,
+}
+
+impl Local {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the raw bytes of the variable.
+    pub fn value(&self) -> &[u8] {
+        &self.value
+    }
+
+    /// Decodes the variable as a value of type `Val`. Returns `None` if the size of the variable
+    /// does not match.
+    pub fn get<const N: usize, Val: Value<N>>(&self) -> Option<Val> {
+        let bits: [u8; N] = self.value.as_slice().try_into().ok()?;
+        Some(Val::from_bits(bits))
+    }
+
+    /// Decodes the variable as a value of the specified numeral type. Returns `None` if the size
+    /// of the variable does not match.
+    pub fn as_numeral(&self, ty: NumeralType) -> Option<NativeValue> {
+        (self.value.len() == ty.size()).then(|| NativeValue::from_bits(ty, &self.value))
+    }
+}
+
+/// Loads a program to debug from a `.fbc` file, or from assembly source for any other extension.
+pub fn load_program(path: &str) -> Result<Program, String> {
+    if path.ends_with(".fbc") {
+        let bytes = std::fs::read(path).map_err(|e| e.to_string())?;
+        Program::deserialize(&bytes).map_err(|e| e.to_string())
+    } else {
+        let src = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        assemble(&src).map(Program::from).map_err(|e| e.to_string())
+    }
+}
+
+/// State of a single call frame, as needed to inspect it.
+struct FrameState {
+    chunk: usize,
+    /// code position used to look up the source position and the live locals of the frame
+    ip: usize,
+    fp: usize,
+}
+
+pub struct Debugger {
+    vm: VM,
+    /// source lines with a breakpoint
+    lines: BTreeSet<u16>,
+    /// resolved breakpoint locations as (chunk, code position)
+    locations: HashSet<(usize, usize)>,
+    /// location the debugger last handed control back at, whose breakpoint has been reported
+    stopped: Option<(usize, usize)>,
+}
+
+impl Debugger {
+    pub fn new(vm: VM) -> Self {
+        Debugger {
+            vm,
+            lines: BTreeSet::new(),
+            locations: HashSet::new(),
+            stopped: None,
+        }
+    }
+
+    pub fn vm(&self) -> &VM {
+        &self.vm
+    }
+
+    pub fn into_vm(self) -> VM {
+        self.vm
+    }
+
+    /// Sets a breakpoint on a source line. Returns `false` if no instruction of the program was
+    /// compiled from that line.
+    pub fn set_breakpoint(&mut self, line: u16) -> bool {
+        let mut found = false;
+        for (c, chunk) in self.vm.program.chunks().iter().enumerate() {
+            let mut ip = 0;
+            let mut prev = None;
+            while ip < chunk.code().len() {
+                let l = chunk.lines()[ip].0;
+                if l == line && prev != Some(line) {
+                    self.locations.insert((c, ip));
+                    found = true;
+                }
+                prev = Some(l);
+                ip += match OpCode::try_from((ip, chunk)) {
+                    Ok(op) => op.size(),
+                    Err(_) => break,
+                };
+            }
+        }
+        if found {
+            self.lines.insert(line);
+        }
+        found
+    }
+
+    /// Removes the breakpoint from a source line. Returns `false` if the line had none.
+    pub fn clear_breakpoint(&mut self, line: u16) -> bool {
+        if !self.lines.remove(&line) {
+            return false;
+        }
+        let chunks = self.vm.program.chunks();
+        self.locations.retain(|(c, ip)| chunks[*c].lines()[*ip].0 != line);
+        true
+    }
+
+    /// Returns the lines that have a breakpoint, in ascending order.
+    pub fn breakpoints(&self) -> impl Iterator<Item = u16> + '_ {
+        self.lines.iter().copied()
+    }
+
+    /// Returns the source position of the next instruction, or `None` once the program has
+    /// finished.
+    pub fn position(&self) -> Option<CodePos> {
+        if !self.vm.is_active() {
+            return None;
+        }
+        self.vm.current_chunk().lines().get(self.vm.ip).copied()
+    }
+
+    /// Returns the number of active calls, including the entry point.
+    pub fn depth(&self) -> usize {
+        self.vm.frames.len() + 1
+    }
+
+    /// Returns the active calls, starting with the innermost one.
+    pub fn backtrace(&self) -> Vec<Frame> {
+        self.frames().into_iter()
+            .map(|frame| {
+                let chunk = &self.vm.program.chunks()[frame.chunk];
+                Frame {
+                    function: chunk.name().to_owned(),
+                    pos: chunk.lines().get(frame.ip).copied().unwrap_or_default(),
+                }
+            })
+            .collect()
+    }
+
+    /// Returns the local variables that are in scope in the specified frame, where frame 0 is
+    /// the innermost call. Variables that have not been pushed onto the stack yet are omitted.
+    pub fn locals(&self, frame: usize) -> Vec<Local> {
+        let frames = self.frames();
+        let Some(state) = frames.get(frame) else {
+            return Vec::new();
+        };
+        let chunk = &self.vm.program.chunks()[state.chunk];
+        chunk.locals().iter()
+            .filter(|l| l.is_live(state.ip))
+            .filter(|l| chunk.find_local(l.name(), state.ip) == Some(*l))
+            .filter_map(|l| self.read_local(state, l.name(), l.offset(), l.size()))
+            .collect()
+    }
+
+    /// Looks up the local variable `name` in the specified frame, where frame 0 is the innermost
+    /// call.
+    pub fn local(&self, frame: usize, name: &str) -> Option<Local> {
+        let frames = self.frames();
+        let state = frames.get(frame)?;
+        let local = self.vm.program.chunks()[state.chunk].find_local(name, state.ip)?;
+        self.read_local(state, name, local.offset(), local.size())
+    }
+
+    fn read_local(&self, state: &FrameState, name: &str, offset: usize, size: usize) -> Option<Local> {
+        let start = state.fp + offset;
+        if start + size > self.vm.stack.len() {
+            return None;
+        }
+        Some(Local {
+            name: name.to_owned(),
+            value: self.vm.stack[start..(start + size)].to_vec(),
+        })
+    }
+
+    /// Collects the state of all active calls, starting with the innermost one. Callers are
+    /// inspected at their pending `Call` instruction.
+    fn frames(&self) -> Vec<FrameState> {
+        if !self.vm.is_active() {
+            return Vec::new();
+        }
+        let mut frames = vec![FrameState {
+            chunk: self.vm.chunk,
+            ip: self.vm.ip,
+            fp: self.vm.fp,
+        }];
+        frames.extend(self.vm.frames.iter().rev().map(|f| FrameState {
+            chunk: f.chunk,
+            ip: f.ret_ip.saturating_sub(1),
+            fp: f.fp,
+        }));
+        frames
+    }
+
+    /// Returns the line of the next instruction and the depth it executes at.
+    fn line(&self) -> (usize, usize, u16) {
+        (self.depth(), self.vm.chunk, self.position().map(|p| p.0).unwrap_or_default())
+    }
+
+    /// Returns `true` if the next instruction has a breakpoint.
+    fn at_breakpoint(&self) -> bool {
+        self.vm.is_active() && self.locations.contains(&(self.vm.chunk, self.vm.ip))
+    }
+
+    /// Executes a single instruction and reports whether the program finished.
+    fn cycle(&mut self) -> Result<Option<StopReason>, VMError> {
+        self.vm.cycle()?;
+        Ok(self.vm.exit_status().cloned().map(StopReason::Finished))
+    }
+
+    /// Runs instructions until `stop` returns `true` for the state before the next instruction,
+    /// a breakpoint is reached or the program finishes. A breakpoint on the next instruction is
+    /// only reported if the debugger is not already stopped there, such that stepping away from a
+    /// breakpoint works.
+    fn run_until<F: Fn(&Self) -> bool>(&mut self, stop: F) -> Result<StopReason, VMError> {
+        if let Some(exit) = self.vm.exit_status() {
+            return Ok(StopReason::Finished(exit.clone()));
+        }
+        let here = Some((self.vm.chunk, self.vm.ip));
+        let reason = if self.at_breakpoint() && self.stopped != here {
+            StopReason::Breakpoint(self.line().2)
+        } else {
+            self.run(stop)?
+        };
+        self.stopped = Some((self.vm.chunk, self.vm.ip));
+        Ok(reason)
+    }
+
+    fn run<F: Fn(&Self) -> bool>(&mut self, stop: F) -> Result<StopReason, VMError> {
+        loop {
+            if let Some(finished) = self.cycle()? {
+                return Ok(finished);
+            }
+            if stop(self) {
+                return Ok(StopReason::Step);
+            }
+            if self.at_breakpoint() {
+                return Ok(StopReason::Breakpoint(self.line().2));
+            }
+        }
+    }
+
+    /// Executes a single instruction.
+    pub fn step_instruction(&mut self) -> Result<StopReason, VMError> {
+        self.run_until(|_| true)
+    }
+
+    /// Runs until execution reaches another source line, entering called functions.
+    pub fn step_into(&mut self) -> Result<StopReason, VMError> {
+        let start = self.line();
+        self.run_until(|d| d.line() != start)
+    }
+
+    /// Runs until execution reaches another source line of the current function or returns from
+    /// it. Called functions run to completion, unless they hit a breakpoint.
+    pub fn step_over(&mut self) -> Result<StopReason, VMError> {
+        let start = self.line();
+        self.run_until(|d| {
+            let line = d.line();
+            line.0 < start.0 || (line.0 == start.0 && line != start)
+        })
+    }
+
+    /// Runs until the current function returns to its caller.
+    pub fn step_out(&mut self) -> Result<StopReason, VMError> {
+        let depth = self.depth();
+        self.run_until(|d| d.depth() < depth)
+    }
+
+    /// Runs until a breakpoint is reached or the program finishes.
+    pub fn resume(&mut self) -> Result<StopReason, VMError> {
+        self.run_until(|_| false)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::bytecode::asm::assemble;
+    use crate::bytecode::opcode::NumeralType;
+    use crate::bytecode::program::Program;
+    use crate::lang::function::FerrumFunctionPtr;
+    use crate::vm::debug::{Debugger, StopReason};
+    use crate::vm::native::NativeValue;
+    use crate::vm::VM;
+
+    fn debugger() -> Debugger {
+        let main = assemble("
+            .chunk main
+            .const a i32 20
+            .local x 0 4 3
+            .local y 4 4 9
+                CONST a      @ 1:0
+                LOAD 0 4     @ 2:4
+                CALL 1 4     @ 2:0
+                LOAD 0 4     @ 3:4
+                LOAD 4 4     @ 3:8
+                ADD I32      @ 3:6
+                RET 4        @ 4:0
+        ").unwrap();
+        let double = assemble("
+            .chunk double
+            .const two i32 2
+            .local n 0 4 0
+                LOAD 0 4     @ 7:4
+                CONST two    @ 7:8
+                MUL I32      @ 7:6
+                RET 4        @ 8:0
+        ").unwrap();
+
+        let mut program = Program::new();
+        program.add_function(FerrumFunctionPtr::new(String::from("main"), 0), main);
+        program.add_function(FerrumFunctionPtr::new(String::from("double"), 0), double);
+        Debugger::new(VM::new(program).unwrap())
+    }
+
+    #[test]
+    fn step() {
+        let mut dbg = debugger();
+        assert_eq!(dbg.position(), Some((1, 0)));
+        assert!(dbg.local(0, "x").is_none());
+
+        assert_eq!(dbg.step_into().unwrap(), StopReason::Step);
+        assert_eq!(dbg.position(), Some((2, 4)));
+        assert_eq!(dbg.local(0, "x").unwrap().get::<4, i32>(), Some(20));
+
+        assert_eq!(dbg.step_into().unwrap(), StopReason::Step);
+        assert_eq!(dbg.position(), Some((7, 4)));
+        assert_eq!(dbg.depth(), 2);
+        let trace = dbg.backtrace();
+        assert_eq!((trace[0].function(), trace[1].function()), ("double", "main"));
+        assert_eq!(trace[1].pos(), (2, 0));
+        assert_eq!(dbg.local(0, "n").unwrap().as_numeral(NumeralType::I32), Some(NativeValue::I32(20)));
+        assert_eq!(dbg.locals(1).len(), 1);
+
+        assert_eq!(dbg.step_out().unwrap(), StopReason::Step);
+        assert_eq!(dbg.depth(), 1);
+        assert_eq!(dbg.position(), Some((3, 4)));
+        assert_eq!(dbg.local(0, "y").unwrap().get::<4, i32>(), Some(40));
+
+        assert_eq!(dbg.step_over().unwrap(), StopReason::Step);
+        assert_eq!(dbg.position(), Some((4, 0)));
+        match dbg.step_over().unwrap() {
+            StopReason::Finished(exit) => assert_eq!(exit.get::<4, i32>(), Some(60)),
+            r => panic!("expected the program to finish, got {:?}", r),
+        }
+        assert_eq!(dbg.position(), None);
+    }
+
+    #[test]
+    fn breakpoints() {
+        let mut dbg = debugger();
+        assert!(dbg.set_breakpoint(7));
+        assert!(dbg.set_breakpoint(3));
+        assert!(!dbg.set_breakpoint(5));
+        assert_eq!(dbg.breakpoints().collect::<Vec<_>>(), vec![3, 7]);
+
+        // stepping over the call still stops at the breakpoint inside of it
+        dbg.step_into().unwrap();
+        assert_eq!(dbg.step_over().unwrap(), StopReason::Breakpoint(7));
+        assert_eq!(dbg.resume().unwrap(), StopReason::Breakpoint(3));
+
+        assert!(dbg.clear_breakpoint(3));
+        assert!(!dbg.clear_breakpoint(3));
+        assert!(matches!(dbg.resume().unwrap(), StopReason::Finished(_)));
+    }
+
+    #[test]
+    fn breakpoint_on_entry() {
+        let mut dbg = debugger();
+        assert!(dbg.set_breakpoint(1));
+        assert_eq!(dbg.resume().unwrap(), StopReason::Breakpoint(1));
+        assert_eq!(dbg.position(), Some((1, 0)));
+
+        // resuming from the breakpoint runs past it
+        assert!(matches!(dbg.resume().unwrap(), StopReason::Finished(_)));
+    }
+}
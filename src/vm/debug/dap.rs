@@ -0,0 +1,405 @@
+//! Debug Adapter Protocol (DAP) server on top of the `Debugger`.
+//!
+//! Messages are JSON objects framed by a `Content-Length` header, as specified by the protocol.
+//! `DapServer` handles one request at a time and returns the response along with any events it
+//! causes, such that a session can be scripted without any I/O. `serve` runs a session over a
+//! pair of streams, e.g. stdin and stdout.
+//!
+//! The program is executed synchronously on a single thread with id 1. Stack frames are taken
+//! from the call frames of the VM and mapped to source lines with the line table of each chunk.
+//! Every frame has a single scope with the locals recorded in the debug information of its chunk.
+//! Since the debug information does not carry types, variables are shown as raw bytes.
+
+use std::io::{BufRead, Read, Write};
+use serde_json::{json, Value};
+use crate::vm::debug::{load_program, Debugger, StopReason};
+use crate::vm::{VM, VMError};
+
+/// Id of the only thread of a Ferrum program.
+pub const THREAD_ID: u64 = 1;
+
+pub struct DapServer {
+    /// sequence number of the last message sent
+    seq: u64,
+    debugger: Option<Debugger>,
+    /// path of the source file that is reported for every stack frame
+    source: Option<String>,
+    stop_on_entry: bool,
+    /// set once the client disconnected
+    done: bool,
+}
+
+impl DapServer {
+    pub fn new() -> Self {
+        DapServer {
+            seq: 0,
+            debugger: None,
+            source: None,
+            stop_on_entry: false,
+            done: false,
+        }
+    }
+
+    /// Returns `true` once the client has disconnected.
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    fn message(&mut self, mut msg: Value) -> Value {
+        self.seq += 1;
+        msg["seq"] = json!(self.seq);
+        msg
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> Value {
+        self.message(json!({ "type": "event", "event": event, "body": body }))
+    }
+
+    fn response(&mut self, request: &Value, result: Result<Value, String>) -> Value {
+        let mut msg = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": result.is_ok(),
+        });
+        match result {
+            Ok(body) => msg["body"] = body,
+            Err(e) => msg["message"] = json!(e),
+        }
+        self.message(msg)
+    }
+
+    /// Handles a single request and returns the messages to send to the client, starting with
+    /// the response to the request.
+    pub fn handle(&mut self, request: &Value) -> Vec<Value> {
+        let args = &request["arguments"];
+        let command = request["command"].as_str().unwrap_or_default();
+        let mut events = Vec::new();
+        let result = match command {
+            "initialize" => {
+                events.push(self.event("initialized", json!({})));
+                Ok(json!({
+                    "supportsConfigurationDoneRequest": true,
+                }))
+            },
+            "launch" => self.launch(args),
+            "setBreakpoints" => self.set_breakpoints(args),
+            "configurationDone" => {
+                if self.stop_on_entry {
+                    events.push(self.stopped("entry"));
+                    Ok(json!({}))
+                } else {
+                    self.run(&mut events, |dbg| dbg.resume())
+                }
+            },
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "main" }] })),
+            "stackTrace" => self.stack_trace(),
+            "scopes" => self.scopes(args),
+            "variables" => self.variables(args),
+            "continue" => self.run(&mut events, |dbg| dbg.resume())
+                .map(|_| json!({ "allThreadsContinued": true })),
+            "next" => self.run(&mut events, |dbg| dbg.step_over()),
+            "stepIn" => self.run(&mut events, |dbg| dbg.step_into()),
+            "stepOut" => self.run(&mut events, |dbg| dbg.step_out()),
+            "disconnect" => {
+                self.done = true;
+                self.debugger = None;
+                Ok(json!({}))
+            },
+            c => Err(format!("Unsupported command `{}`", c)),
+        };
+
+        let mut out = vec![self.response(request, result)];
+        out.append(&mut events);
+        out
+    }
+
+    fn debugger(&mut self) -> Result<&mut Debugger, String> {
+        self.debugger.as_mut()
+            .ok_or_else(|| String::from("No program is running"))
+    }
+
+    fn launch(&mut self, args: &Value) -> Result<Value, String> {
+        let path = args["program"].as_str()
+            .ok_or_else(|| String::from("The launch configuration does not specify a `program`"))?;
+        let program = load_program(path)?;
+        let vm = VM::new(program).map_err(|e| e.to_string())?;
+        self.debugger = Some(Debugger::new(vm));
+        self.source = Some(args["source"].as_str().unwrap_or(path).to_owned());
+        self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+        Ok(json!({}))
+    }
+
+    /// Replaces all breakpoints with the requested ones. Breakpoints on lines without code are
+    /// reported as unverified.
+    fn set_breakpoints(&mut self, args: &Value) -> Result<Value, String> {
+        let dbg = self.debugger()?;
+        let lines: Vec<u16> = dbg.breakpoints().collect();
+        for line in lines {
+            dbg.clear_breakpoint(line);
+        }
+        let breakpoints: Vec<Value> = args["breakpoints"].as_array()
+            .map(|b| b.as_slice())
+            .unwrap_or_default()
+            .iter()
+            .map(|b| {
+                let line = b["line"].as_u64().unwrap_or_default();
+                let verified = u16::try_from(line).is_ok_and(|l| dbg.set_breakpoint(l));
+                json!({ "verified": verified, "line": line })
+            })
+            .collect();
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    fn stack_trace(&mut self) -> Result<Value, String> {
+        let source = self.source.clone();
+        let frames: Vec<Value> = self.debugger()?
+            .backtrace()
+            .iter()
+            .enumerate()
+            .map(|(i, frame)| json!({
+                "id": i,
+                "name": frame.function(),
+                "source": { "path": source },
+                "line": frame.pos().0,
+                "column": frame.pos().1 as u64 + 1,
+            }))
+            .collect();
+        Ok(json!({ "stackFrames": frames, "totalFrames": frames.len() }))
+    }
+
+    /// Every frame has a single scope for its locals. The variables reference of the scope is
+    /// the frame id plus one, as zero means that there are no variables.
+    fn scopes(&mut self, args: &Value) -> Result<Value, String> {
+        let frame = args["frameId"].as_u64().unwrap_or_default();
+        Ok(json!({
+            "scopes": [{ "name": "Locals", "variablesReference": frame + 1, "expensive": false }],
+        }))
+    }
+
+    fn variables(&mut self, args: &Value) -> Result<Value, String> {
+        let frame = args["variablesReference"].as_u64()
+            .and_then(|r| r.checked_sub(1))
+            .ok_or_else(|| String::from("Invalid variables reference"))?;
+        let variables: Vec<Value> = self.debugger()?
+            .locals(frame as usize)
+            .iter()
+            .map(|local| {
+                let bytes: Vec<String> = local.value().iter().map(|b| format!("{:02x}", b)).collect();
+                json!({
+                    "name": local.name(),
+                    "value": format!("[{}]", bytes.join(" ")),
+                    "type": format!("{} bytes", local.value().len()),
+                    "variablesReference": 0,
+                })
+            })
+            .collect();
+        Ok(json!({ "variables": variables }))
+    }
+
+    fn stopped(&mut self, reason: &str) -> Value {
+        self.event("stopped", json!({
+            "reason": reason,
+            "threadId": THREAD_ID,
+            "allThreadsStopped": true,
+        }))
+    }
+
+    /// Executes the program with `f` and appends the events that report where it stopped.
+    fn run<F>(&mut self, events: &mut Vec<Value>, f: F) -> Result<Value, String>
+    where F: FnOnce(&mut Debugger) -> Result<StopReason, VMError> {
+        let stop = f(self.debugger()?);
+        match stop {
+            Ok(StopReason::Breakpoint(_)) => events.push(self.stopped("breakpoint")),
+            Ok(StopReason::Step) => events.push(self.stopped("step")),
+            Ok(StopReason::Finished(exit)) => {
+                events.push(self.event("exited", json!({ "exitCode": exit.code() })));
+                events.push(self.event("terminated", json!({})));
+            },
+            Err(e) => {
+                // the VM cannot continue after a runtime error
+                events.push(self.event("output", json!({ "category": "stderr", "output": format!("{}\n", e) })));
+                events.push(self.event("terminated", json!({})));
+                self.debugger = None;
+            },
+        }
+        Ok(json!({}))
+    }
+}
+
+impl Default for DapServer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Reads a single message. Returns `None` once the input ends. The body is read as it arrives
+/// instead of being allocated up front, such that a bogus `Content-Length` cannot exhaust memory.
+pub fn read_message<R: BufRead>(input: &mut R) -> std::io::Result<Option<Value>> {
+    let mut len = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("Content-Length") {
+                len = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let len = len.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "missing Content-Length header"))?;
+    let mut body = Vec::new();
+    input.take(len as u64).read_to_end(&mut body)?;
+    if body.len() < len {
+        return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "message body ends early"));
+    }
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+/// Writes a single message with its `Content-Length` header.
+pub fn write_message<W: Write>(output: &mut W, msg: &Value) -> std::io::Result<()> {
+    let body = msg.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+/// Runs a debug session until the client disconnects or the input ends.
+pub fn serve<R: BufRead, W: Write>(mut input: R, mut output: W) -> std::io::Result<()> {
+    let mut server = DapServer::new();
+    while let Some(request) = read_message(&mut input)? {
+        for msg in server.handle(&request) {
+            write_message(&mut output, &msg)?;
+        }
+        if server.is_done() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use serde_json::{json, Value};
+    use crate::vm::debug::dap::{read_message, serve, write_message, DapServer};
+
+    const PROGRAM: &str = "
+        .chunk main
+        .const a i32 20
+        .local x 0 4 3
+            CONST a      @ 1:0
+            LOAD 0 4     @ 2:4
+            LOAD 0 4     @ 2:8
+            ADD I32      @ 2:6
+            RET 4        @ 3:0
+    ";
+
+    fn program_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("ferrum-dap-{}-{}.fasm", std::process::id(), name));
+        std::fs::write(&path, PROGRAM).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn request(seq: u64, command: &str, arguments: Value) -> Value {
+        json!({ "seq": seq, "type": "request", "command": command, "arguments": arguments })
+    }
+
+    /// Returns the events of the messages, in order.
+    fn events(msgs: &[Value]) -> Vec<&str> {
+        msgs.iter()
+            .filter(|m| m["type"] == "event")
+            .map(|m| m["event"].as_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn session() {
+        let path = program_path("session");
+        let mut server = DapServer::new();
+
+        let out = server.handle(&request(1, "initialize", json!({ "adapterID": "ferrum" })));
+        assert_eq!(out[0]["success"], true);
+        assert_eq!(events(&out), vec!["initialized"]);
+
+        let out = server.handle(&request(2, "launch", json!({ "program": path, "source": "main.fe" })));
+        assert_eq!(out[0]["success"], true);
+        let out = server.handle(&request(3, "setBreakpoints", json!({
+            "source": { "path": "main.fe" },
+            "breakpoints": [{ "line": 2 }, { "line": 9 }],
+        })));
+        assert_eq!(out[0]["body"]["breakpoints"], json!([
+            { "verified": true, "line": 2 },
+            { "verified": false, "line": 9 },
+        ]));
+
+        let out = server.handle(&request(4, "configurationDone", json!({})));
+        assert_eq!(out[1]["body"]["reason"], "breakpoint");
+
+        let out = server.handle(&request(5, "stackTrace", json!({ "threadId": 1 })));
+        assert_eq!(out[0]["body"]["stackFrames"], json!([{
+            "id": 0, "name": "main", "source": { "path": "main.fe" }, "line": 2, "column": 5,
+        }]));
+        let out = server.handle(&request(6, "scopes", json!({ "frameId": 0 })));
+        let reference = out[0]["body"]["scopes"][0]["variablesReference"].clone();
+        let out = server.handle(&request(7, "variables", json!({ "variablesReference": reference })));
+        assert_eq!(out[0]["body"]["variables"][0]["name"], "x");
+        assert_eq!(out[0]["body"]["variables"][0]["value"], "[14 00 00 00]");
+
+        let out = server.handle(&request(8, "next", json!({ "threadId": 1 })));
+        assert_eq!(events(&out), vec!["stopped"]);
+        let out = server.handle(&request(9, "continue", json!({ "threadId": 1 })));
+        assert_eq!(events(&out), vec!["exited", "terminated"]);
+        assert_eq!(out[1]["body"]["exitCode"], 40);
+
+        let out = server.handle(&request(10, "evaluate", json!({ "expression": "x" })));
+        assert_eq!(out[0]["success"], false);
+        server.handle(&request(11, "disconnect", json!({})));
+        assert!(server.is_done());
+
+        // sequence numbers of the server are strictly increasing
+        assert_eq!(out[0]["seq"].as_u64().unwrap() + 1, server.seq);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn framing() {
+        let path = program_path("framing");
+        let mut input = Vec::new();
+        write_message(&mut input, &request(1, "initialize", json!({}))).unwrap();
+        write_message(&mut input, &request(2, "launch", json!({ "program": path, "stopOnEntry": true }))).unwrap();
+        write_message(&mut input, &request(3, "configurationDone", json!({}))).unwrap();
+        write_message(&mut input, &request(4, "disconnect", json!({}))).unwrap();
+
+        let mut output = Vec::new();
+        serve(Cursor::new(input), &mut output).unwrap();
+
+        let mut output = Cursor::new(output);
+        let mut msgs = Vec::new();
+        while let Some(msg) = read_message(&mut output).unwrap() {
+            msgs.push(msg);
+        }
+        let commands: Vec<_> = msgs.iter()
+            .filter(|m| m["type"] == "response")
+            .map(|m| m["command"].as_str().unwrap())
+            .collect();
+        assert_eq!(commands, vec!["initialize", "launch", "configurationDone", "disconnect"]);
+        assert_eq!(events(&msgs), vec!["initialized", "stopped"]);
+        assert!(msgs.iter().any(|m| m["body"]["reason"] == "entry"));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn huge_content_length() {
+        let mut input = Cursor::new(format!("Content-Length: {}\r\n\r\n{{}}", usize::MAX));
+        let err = read_message(&mut input).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,372 @@
+//! Native functions allow scripts to call into the host. Native functions are Rust closures that
+//! are registered in a `NativeRegistry` under a Ferrum path, e.g. `std::print`.
+//!
+//! Programs do not reference natives of a registry directly. Instead, every program contains an
+//! import table with the paths and signatures of the natives it calls, and `CallNative`
+//! operands are indices into that table. When a VM is created, the imports are linked against
+//! the natives of its registry, such that a compiled program can run on any host that provides
+//! matching natives.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use crate::bytecode::opcode::NumeralType;
+use crate::bytecode::program::NativeImport;
+use crate::vm::sandbox::{Capability, SandboxPolicy};
+use crate::vm::{RuntimeError, Stack};
+
+/// A value passed to or returned from a native function.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NativeValue {
+    I8(i8),
+    I16(i16),
+    I32(i32),
+    I64(i64),
+    I128(i128),
+    U8(u8),
+    U16(u16),
+    U32(u32),
+    U64(u64),
+    U128(u128),
+    F32(f32),
+    F64(f64),
+}
+
+impl NativeValue {
+    /// Returns the numeral type of the value.
+    pub fn ty(&self) -> NumeralType {
+        match self {
+            NativeValue::I8(_) => NumeralType::I8,
+            NativeValue::I16(_) => NumeralType::I16,
+            NativeValue::I32(_) => NumeralType::I32,
+            NativeValue::I64(_) => NumeralType::I64,
+            NativeValue::I128(_) => NumeralType::I128,
+            NativeValue::U8(_) => NumeralType::U8,
+            NativeValue::U16(_) => NumeralType::U16,
+            NativeValue::U32(_) => NumeralType::U32,
+            NativeValue::U64(_) => NumeralType::U64,
+            NativeValue::U128(_) => NumeralType::U128,
+            NativeValue::F32(_) => NumeralType::F32,
+            NativeValue::F64(_) => NumeralType::F64,
+        }
+    }
+
+    /// Decodes a value of numeral type `ty` from its little endian representation. `bits` must
+    /// be exactly as long as the type.
+    pub(crate) fn from_bits(ty: NumeralType, bits: &[u8]) -> Self {
+        match ty {
+            NumeralType::I8 => NativeValue::I8(i8::from_le_bytes(bits.try_into().unwrap())),
+            NumeralType::I16 => NativeValue::I16(i16::from_le_bytes(bits.try_into().unwrap())),
+            NumeralType::I32 => NativeValue::I32(i32::from_le_bytes(bits.try_into().unwrap())),
+            NumeralType::I64 => NativeValue::I64(i64::from_le_bytes(bits.try_into().unwrap())),
+            NumeralType::I128 => NativeValue::I128(i128::from_le_bytes(bits.try_into().unwrap())),
+            NumeralType::U8 => NativeValue::U8(u8::from_le_bytes(bits.try_into().unwrap())),
+            NumeralType::U16 => NativeValue::U16(u16::from_le_bytes(bits.try_into().unwrap())),
+            NumeralType::U32 => NativeValue::U32(u32::from_le_bytes(bits.try_into().unwrap())),
+            NumeralType::U64 => NativeValue::U64(u64::from_le_bytes(bits.try_into().unwrap())),
+            NumeralType::U128 => NativeValue::U128(u128::from_le_bytes(bits.try_into().unwrap())),
+            NumeralType::F32 => NativeValue::F32(f32::from_le_bytes(bits.try_into().unwrap())),
+            NumeralType::F64 => NativeValue::F64(f64::from_le_bytes(bits.try_into().unwrap())),
+        }
+    }
+
+    /// Pushes the little endian representation of the value onto the stack.
+    fn push(&self, stack: &mut Stack) -> Result<(), RuntimeError> {
+        match self {
+            NativeValue::I8(v) => stack.push_value(*v),
+            NativeValue::I16(v) => stack.push_value(*v),
+            NativeValue::I32(v) => stack.push_value(*v),
+            NativeValue::I64(v) => stack.push_value(*v),
+            NativeValue::I128(v) => stack.push_value(*v),
+            NativeValue::U8(v) => stack.push_value(*v),
+            NativeValue::U16(v) => stack.push_value(*v),
+            NativeValue::U32(v) => stack.push_value(*v),
+            NativeValue::U64(v) => stack.push_value(*v),
+            NativeValue::U128(v) => stack.push_value(*v),
+            NativeValue::F32(v) => stack.push_value(*v),
+            NativeValue::F64(v) => stack.push_value(*v),
+        }
+    }
+}
+
+impl Display for NativeValue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NativeValue::I8(v) => Display::fmt(v, f),
+            NativeValue::I16(v) => Display::fmt(v, f),
+            NativeValue::I32(v) => Display::fmt(v, f),
+            NativeValue::I64(v) => Display::fmt(v, f),
+            NativeValue::I128(v) => Display::fmt(v, f),
+            NativeValue::U8(v) => Display::fmt(v, f),
+            NativeValue::U16(v) => Display::fmt(v, f),
+            NativeValue::U32(v) => Display::fmt(v, f),
+            NativeValue::U64(v) => Display::fmt(v, f),
+            NativeValue::U128(v) => Display::fmt(v, f),
+            NativeValue::F32(v) => Display::fmt(v, f),
+            NativeValue::F64(v) => Display::fmt(v, f),
+        }
+    }
+}
+
+/// Parameter and return types of a native function.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NativeSignature {
+    params: Vec<NumeralType>,
+    ret: Option<NumeralType>,
+}
+
+impl NativeSignature {
+    pub fn new(params: Vec<NumeralType>, ret: Option<NumeralType>) -> Self {
+        NativeSignature { params, ret }
+    }
+
+    pub fn params(&self) -> &[NumeralType] {
+        &self.params
+    }
+
+    pub fn ret(&self) -> Option<NumeralType> {
+        self.ret
+    }
+
+    /// Returns the number of argument bytes the function pops off the stack.
+    pub fn arg_size(&self) -> usize {
+        self.params.iter().map(|p| p.size()).sum()
+    }
+
+    /// Returns the number of bytes the function pushes onto the stack.
+    pub fn ret_size(&self) -> usize {
+        self.ret.map(|r| r.size()).unwrap_or(0)
+    }
+}
+
+/// Result of a native function. Errors are reported to the script as
+/// `RuntimeError::NativeError`.
+pub type NativeResult = Result<Option<NativeValue>, String>;
+
+/// Host function that implements a native.
+pub type NativeFn = dyn FnMut(&[NativeValue]) -> NativeResult;
+
+struct NativeFunction {
+    signature: NativeSignature,
+    /// capability the sandbox policy has to allow for the native to be linked
+    capability: Option<Capability>,
+    func: Box<NativeFn>,
+}
+
+/// Error raised while linking the native imports of a program against a registry.
+#[derive(Clone, Debug, PartialEq)]
+pub enum LinkError {
+    /// The registry does not contain a native with the imported path
+    UnknownNative(String),
+    /// The registry contains the imported native, but with a different signature
+    SignatureMismatch(String),
+    /// The native requires a capability that the sandbox policy does not allow
+    Forbidden(String, Capability),
+}
+
+impl Display for LinkError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnknownNative(p) => f.write_str(&format!("Native function `{}` is not available", p)),
+            Self::SignatureMismatch(p) => f.write_str(&format!("Native function `{}` is imported with a different signature", p)),
+            Self::Forbidden(p, c) => f.write_str(&format!("Native function `{}` requires the `{}` capability, which is disabled by the sandbox policy", p, c)),
+        }
+    }
+}
+
+impl Error for LinkError {}
+
+/// Collection of the native functions a host provides to scripts.
+#[derive(Default)]
+pub struct NativeRegistry {
+    functions: Vec<NativeFunction>,
+    /// Maps the path of each native to its index in `functions`
+    paths: HashMap<String, usize>,
+}
+
+impl NativeRegistry {
+    pub fn new() -> Self {
+        NativeRegistry {
+            functions: Vec::new(),
+            paths: HashMap::new(),
+        }
+    }
+
+    /// Registers a native function under the specified path and returns its index. The VM only
+    /// ever calls the function with arguments that match the parameter types of the signature
+    /// and checks that the returned value matches the return type. If a native with the same
+    /// path already exists, it is replaced.
+    pub fn register<F>(&mut self, path: &str, signature: NativeSignature, func: F) -> usize
+    where F: FnMut(&[NativeValue]) -> NativeResult + 'static {
+        self.insert(path, NativeFunction {
+            signature,
+            capability: None,
+            func: Box::new(func),
+        })
+    }
+
+    /// Registers a native function like `register`, which is only available to scripts if the
+    /// sandbox policy of the VM allows the specified capability.
+    pub fn register_with<F>(&mut self, capability: Capability, path: &str, signature: NativeSignature, func: F) -> usize
+    where F: FnMut(&[NativeValue]) -> NativeResult + 'static {
+        self.insert(path, NativeFunction {
+            signature,
+            capability: Some(capability),
+            func: Box::new(func),
+        })
+    }
+
+    fn insert(&mut self, path: &str, native: NativeFunction) -> usize {
+        match self.paths.get(path) {
+            Some(&i) => {
+                self.functions[i] = native;
+                i
+            },
+            None => {
+                let i = self.functions.len();
+                self.functions.push(native);
+                self.paths.insert(path.to_owned(), i);
+                i
+            },
+        }
+    }
+
+    /// Returns the index of the native with the specified path.
+    pub fn find(&self, path: &str) -> Option<usize> {
+        self.paths.get(path).copied()
+    }
+
+    /// Returns the signature of the native with the specified path.
+    pub fn signature(&self, path: &str) -> Option<&NativeSignature> {
+        self.find(path).map(|i| &self.functions[i].signature)
+    }
+
+    /// Returns the capability required by the native with the specified path.
+    pub fn capability(&self, path: &str) -> Option<Capability> {
+        self.find(path).and_then(|i| self.functions[i].capability)
+    }
+
+    /// Resolves every import to the index of the matching native in the registry. Natives that
+    /// require a capability which the policy does not allow cannot be linked.
+    pub(crate) fn link(&self, imports: &[NativeImport], policy: &SandboxPolicy) -> Result<Vec<usize>, LinkError> {
+        imports.iter()
+            .map(|import| {
+                let i = self.find(import.path())
+                    .ok_or_else(|| LinkError::UnknownNative(import.path().to_owned()))?;
+                let native = &self.functions[i];
+                if native.signature != *import.signature() {
+                    return Err(LinkError::SignatureMismatch(import.path().to_owned()));
+                }
+                match native.capability {
+                    Some(c) if !policy.allows(c) => Err(LinkError::Forbidden(import.path().to_owned(), c)),
+                    _ => Ok(i),
+                }
+            })
+            .collect()
+    }
+
+    /// Calls the native with index `i`. The arguments are popped off the stack, where the first
+    /// argument lies lowest, and the returned value is pushed.
+    pub(crate) fn call(&mut self, i: usize, stack: &mut Stack) -> Result<(), RuntimeError> {
+        let native = &mut self.functions[i];
+        let base = stack.len()
+            .checked_sub(native.signature.arg_size())
+            .ok_or(RuntimeError::StackUnderflow)?;
+
+        let mut args = Vec::with_capacity(native.signature.params.len());
+        let mut pos = base;
+        for ty in native.signature.params.iter() {
+            args.push(NativeValue::from_bits(*ty, &stack[pos..(pos + ty.size())]));
+            pos += ty.size();
+        }
+        stack.truncate(base);
+
+        let ret = (native.func)(&args)
+            .map_err(RuntimeError::NativeError)?;
+        match (ret, native.signature.ret) {
+            (Some(v), Some(ty)) if v.ty() == ty => v.push(stack),
+            (None, None) => Ok(()),
+            _ => Err(RuntimeError::NativeError(
+                String::from("Native function returned a value that does not match its signature")
+            )),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::bytecode::asm::assemble;
+    use crate::bytecode::opcode::NumeralType;
+    use crate::bytecode::program::Program;
+    use crate::vm::native::{LinkError, NativeRegistry, NativeSignature, NativeValue};
+    use crate::vm::{RuntimeError, VM, VMError};
+
+    fn program() -> Program {
+        let mut program = Program::from(assemble("
+            .const a i32 20
+            .const b f64 2.5
+                CONST a
+                CONST b
+                CALLN 0
+                DUP 4
+                CALLN 1
+                RET 4
+        ").unwrap());
+        program.import_native("math::scale", NativeSignature::new(
+            vec![NumeralType::I32, NumeralType::F64], Some(NumeralType::I32),
+        ));
+        program.import_native("std::print", NativeSignature::new(vec![NumeralType::I32], None));
+        program
+    }
+
+    #[test]
+    fn call_natives() {
+        let printed = Rc::new(RefCell::new(Vec::new()));
+        let out = printed.clone();
+
+        let mut natives = NativeRegistry::new();
+        natives.register("std::print", NativeSignature::new(vec![NumeralType::I32], None), move |args| {
+            out.borrow_mut().push(args[0]);
+            Ok(None)
+        });
+        natives.register("math::scale", NativeSignature::new(
+            vec![NumeralType::I32, NumeralType::F64], Some(NumeralType::I32),
+        ), |args| match args {
+            [NativeValue::I32(a), NativeValue::F64(b)] => Ok(Some(NativeValue::I32((*a as f64 * b) as i32))),
+            _ => Err(String::from("unexpected arguments")),
+        });
+
+        let mut vm = VM::with_natives(program(), natives).unwrap();
+        assert_eq!(vm.run().unwrap().get::<4, i32>(), Some(50));
+        assert_eq!(*printed.borrow(), vec![NativeValue::I32(50)]);
+    }
+
+    #[test]
+    fn reject_unlinked() {
+        let mut natives = NativeRegistry::new();
+        natives.register("std::print", NativeSignature::new(vec![NumeralType::I32], None), |_| Ok(None));
+        match VM::with_natives(program(), natives) {
+            Err(VMError::LinkError(e)) => assert_eq!(e, LinkError::UnknownNative(String::from("math::scale"))),
+            _ => panic!("expected a link error"),
+        }
+
+        let mut natives = NativeRegistry::new();
+        natives.register("std::print", NativeSignature::new(vec![NumeralType::I32], None), |_| Ok(None));
+        natives.register("math::scale", NativeSignature::new(vec![NumeralType::I32], None), |_| Ok(None));
+        match VM::with_natives(program(), natives) {
+            Err(VMError::LinkError(e)) => assert_eq!(e, LinkError::SignatureMismatch(String::from("math::scale"))),
+            _ => panic!("expected a link error"),
+        }
+
+        let mut natives = NativeRegistry::new();
+        natives.register("std::print", NativeSignature::new(vec![NumeralType::I32], None), |_| Ok(None));
+        natives.register("math::scale", NativeSignature::new(
+            vec![NumeralType::I32, NumeralType::F64], Some(NumeralType::I32),
+        ), |_| Err(String::from("failed")));
+        let mut vm = VM::with_natives(program(), natives).unwrap();
+        assert!(matches!(vm.run(), Err(VMError::RuntimeError(_, RuntimeError::NativeError(_)))));
+    }
+}
@@ -0,0 +1,210 @@
+//! Sandbox policies restrict what a script may do when it runs inside a VM.
+//!
+//! Natives that interact with the world outside the VM are registered with the capability they
+//! require. A policy enables or disables whole groups of such natives, and a program that
+//! imports a native of a disabled group is rejected when the VM links it, i.e. before any of its
+//! code runs. In addition, a policy can limit the stack memory and the total fuel the script may
+//! consume.
+
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+
+/// Groups of native functions that require access to the host.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Capability {
+    /// Standard input and output
+    Io,
+    /// File system access
+    Fs,
+    /// Clocks and timers
+    Time,
+    /// Environment variables and program arguments
+    Env,
+    /// Spawning and terminating processes
+    Process,
+}
+
+impl Capability {
+    pub const ALL: [Capability; 5] = [
+        Capability::Io,
+        Capability::Fs,
+        Capability::Time,
+        Capability::Env,
+        Capability::Process,
+    ];
+}
+
+impl Display for Capability {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Capability::Io => "io",
+            Capability::Fs => "fs",
+            Capability::Time => "time",
+            Capability::Env => "env",
+            Capability::Process => "process",
+        })
+    }
+}
+
+/// Restrictions that apply to a script running in a VM.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SandboxPolicy {
+    allowed: HashSet<Capability>,
+    /// Maximum size of the stack and the call frames in bytes
+    memory_limit: Option<usize>,
+    /// Maximum fuel the script may consume over the lifetime of the VM
+    fuel_limit: Option<u64>,
+}
+
+impl SandboxPolicy {
+    /// Creates a policy that allows every capability and does not limit memory or fuel.
+    pub fn permissive() -> Self {
+        SandboxPolicy {
+            allowed: Capability::ALL.into_iter().collect(),
+            memory_limit: None,
+            fuel_limit: None,
+        }
+    }
+
+    /// Creates a policy that denies every capability. Natives that do not require a capability
+    /// remain available.
+    pub fn restrictive() -> Self {
+        SandboxPolicy {
+            allowed: HashSet::new(),
+            memory_limit: None,
+            fuel_limit: None,
+        }
+    }
+
+    pub fn allow(mut self, capability: Capability) -> Self {
+        self.allowed.insert(capability);
+        self
+    }
+
+    pub fn deny(mut self, capability: Capability) -> Self {
+        self.allowed.remove(&capability);
+        self
+    }
+
+    /// Limits the stack of the VM, including its call frames, to the specified number of bytes.
+    pub fn with_memory_limit(mut self, bytes: usize) -> Self {
+        self.memory_limit = Some(bytes);
+        self
+    }
+
+    /// Limits the total fuel consumed by the script. Exceeding the limit traps with
+    /// `RuntimeError::FuelLimitExceeded`, regardless of how the VM is run.
+    pub fn with_fuel_limit(mut self, fuel: u64) -> Self {
+        self.fuel_limit = Some(fuel);
+        self
+    }
+
+    pub fn allows(&self, capability: Capability) -> bool {
+        self.allowed.contains(&capability)
+    }
+
+    pub fn memory_limit(&self) -> Option<usize> {
+        self.memory_limit
+    }
+
+    pub fn fuel_limit(&self) -> Option<u64> {
+        self.fuel_limit
+    }
+}
+
+impl Default for SandboxPolicy {
+    fn default() -> Self {
+        Self::permissive()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::bytecode::asm::assemble;
+    use crate::bytecode::opcode::NumeralType;
+    use crate::bytecode::program::Program;
+    use crate::vm::native::{LinkError, NativeRegistry, NativeSignature, NativeValue};
+    use crate::vm::sandbox::{Capability, SandboxPolicy};
+    use crate::vm::{RuntimeError, VM, VMError};
+
+    fn natives() -> NativeRegistry {
+        let mut natives = NativeRegistry::new();
+        natives.register("math::abs", NativeSignature::new(vec![NumeralType::I32], Some(NumeralType::I32)), |args| {
+            match args {
+                [NativeValue::I32(v)] => Ok(Some(NativeValue::I32(v.abs()))),
+                _ => Err(String::from("unexpected arguments")),
+            }
+        });
+        natives.register_with(Capability::Time, "std::time", NativeSignature::new(Vec::new(), Some(NumeralType::I32)), |_| {
+            Ok(Some(NativeValue::I32(0)))
+        });
+        natives
+    }
+
+    fn program(src: &str, natives: &[&str]) -> Program {
+        let mut program = Program::from(assemble(src).unwrap());
+        let registry = self::natives();
+        for path in natives {
+            program.import_native(path, registry.signature(path).unwrap().clone());
+        }
+        program
+    }
+
+    #[test]
+    fn reject_capabilities() {
+        let src = "
+            .const a i32 -3
+                CONST a
+                CALLN 0
+                CALLN 1
+                ADD I32
+                RET 4
+        ";
+        let policy = SandboxPolicy::restrictive().allow(Capability::Io);
+        match VM::sandboxed(program(src, &["math::abs", "std::time"]), natives(), policy.clone()) {
+            Err(VMError::LinkError(e)) => assert_eq!(e, LinkError::Forbidden(String::from("std::time"), Capability::Time)),
+            _ => panic!("expected std::time to be forbidden"),
+        }
+
+        let mut vm = VM::sandboxed(program(src, &["math::abs", "std::time"]), natives(), policy.allow(Capability::Time)).unwrap();
+        assert_eq!(vm.run().unwrap().get::<4, i32>(), Some(3));
+    }
+
+    #[test]
+    fn enforce_limits() {
+        let endless = "
+            top:
+                LOOP top
+        ";
+        let policy = SandboxPolicy::restrictive().with_fuel_limit(100);
+        let mut vm = VM::sandboxed(program(endless, &[]), natives(), policy).unwrap();
+        assert!(matches!(vm.run(), Err(VMError::RuntimeError(_, RuntimeError::FuelLimitExceeded(100)))));
+        assert_eq!(vm.fuel_consumed(), 100);
+
+        // the limit applies to the lifetime of the VM, resetting it does not refuel
+        let finite = "
+            .const a i32 1
+                CONST a
+                RET 4
+        ";
+        let policy = SandboxPolicy::restrictive().with_fuel_limit(5);
+        let mut vm = VM::sandboxed(program(finite, &[]), natives(), policy).unwrap();
+        vm.run().unwrap();
+        let consumed = vm.fuel_consumed();
+        vm.reset();
+        assert_eq!(vm.fuel_consumed(), consumed);
+        assert!(matches!(vm.run(), Err(VMError::RuntimeError(_, RuntimeError::FuelLimitExceeded(5)))));
+
+        let src = "
+            .const a i64 1
+                CONST a
+                CONST a
+                ADD I64
+                RET 8
+        ";
+        let policy = SandboxPolicy::restrictive().with_memory_limit(12);
+        let mut vm = VM::sandboxed(program(src, &[]), natives(), policy).unwrap();
+        assert!(matches!(vm.run(), Err(VMError::RuntimeError(_, RuntimeError::StackOverflow(12)))));
+    }
+}
@@ -0,0 +1,341 @@
+//! Execution tracing. A `Tracer` is notified before and after the VM executes an instruction,
+//! when the program is run with `VM::run_traced` or `VM::cycle_traced`. Untraced execution is not
+//! affected by tracing in any way.
+//!
+//! Three tracers are built in:
+//!
+//! - `OpcodeHistogram` counts how often each instruction is executed.
+//! - `FunctionProfiler` measures the inclusive and exclusive time spent in each function.
+//! - `FoldedStacks` records the call stack of every executed instruction in the folded format of
+//!   flamegraph tools, e.g. `main;fib;fib 42`.
+//!
+//! Several tracers can be combined into a tuple, which is a tracer as well.
+
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::time::{Duration, Instant};
+use crate::bytecode::opcode::OpCode;
+use crate::vm::VM;
+
+/// Hook that observes the instructions executed by a VM.
+pub trait Tracer {
+    /// Called before the VM executes `op`. The instruction pointer still points at `op`.
+    fn before(&mut self, _vm: &VM, _op: &OpCode) {}
+
+    /// Called after the VM executed `op`. Calls and returns have already switched to the new
+    /// function at this point.
+    fn after(&mut self, _vm: &VM, _op: &OpCode) {}
+}
+
+impl<A: Tracer, B: Tracer> Tracer for (A, B) {
+    fn before(&mut self, vm: &VM, op: &OpCode) {
+        self.0.before(vm, op);
+        self.1.before(vm, op);
+    }
+
+    fn after(&mut self, vm: &VM, op: &OpCode) {
+        self.0.after(vm, op);
+        self.1.after(vm, op);
+    }
+}
+
+/// Returns the names of all functions of the program, indexed by chunk.
+fn function_names(vm: &VM) -> Vec<String> {
+    vm.program().chunks().iter()
+        .map(|c| c.name().to_owned())
+        .collect()
+}
+
+
+/// Counts how often each instruction is executed and how much fuel it consumes in total.
+/// Instructions are distinguished by their mnemonic, regardless of their operands.
+#[derive(Default)]
+pub struct OpcodeHistogram {
+    counts: HashMap<&'static str, (u64, u64)>,
+}
+
+impl OpcodeHistogram {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns how often the instruction with the specified mnemonic has been executed.
+    pub fn count(&self, mnemonic: &str) -> u64 {
+        self.counts.get(mnemonic).map(|c| c.0).unwrap_or(0)
+    }
+
+    /// Returns the total fuel consumed by the instruction with the specified mnemonic.
+    pub fn fuel(&self, mnemonic: &str) -> u64 {
+        self.counts.get(mnemonic).map(|c| c.1).unwrap_or(0)
+    }
+
+    /// Returns the total number of executed instructions.
+    pub fn total(&self) -> u64 {
+        self.counts.values().map(|c| c.0).sum()
+    }
+
+    /// Returns the mnemonic, count and fuel of every executed instruction, the most frequent
+    /// one first.
+    pub fn entries(&self) -> Vec<(&'static str, u64, u64)> {
+        let mut entries: Vec<_> = self.counts.iter()
+            .map(|(m, (count, fuel))| (*m, *count, *fuel))
+            .collect();
+        entries.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        entries
+    }
+}
+
+impl Tracer for OpcodeHistogram {
+    fn before(&mut self, _vm: &VM, op: &OpCode) {
+        let entry = self.counts.entry(op.mnemonic()).or_insert((0, 0));
+        entry.0 += 1;
+        entry.1 += op.cost();
+    }
+}
+
+impl Display for OpcodeHistogram {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let total = self.total().max(1) as f64;
+        f.write_str(&format!("{:<8}{:>12}{:>9}{:>12}\n", "OPCODE", "COUNT", "%", "FUEL"))?;
+        for (mnemonic, count, fuel) in self.entries() {
+            let share = 100.0 * count as f64 / total;
+            f.write_str(&format!("{:<8}{:>12}{:>8.2}%{:>12}\n", mnemonic, count, share, fuel))?;
+        }
+        Ok(())
+    }
+}
+
+
+/// Time spent in a single function.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FunctionProfile {
+    name: String,
+    calls: u64,
+    instructions: u64,
+    inclusive: Duration,
+    exclusive: Duration,
+}
+
+impl FunctionProfile {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns how often the function has been called.
+    pub fn calls(&self) -> u64 {
+        self.calls
+    }
+
+    /// Returns the number of instructions executed by the function itself.
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    /// Returns the time spent in the function, including the functions it called. Recursive
+    /// calls are only counted once.
+    pub fn inclusive(&self) -> Duration {
+        self.inclusive
+    }
+
+    /// Returns the time spent executing instructions of the function itself.
+    pub fn exclusive(&self) -> Duration {
+        self.exclusive
+    }
+}
+
+/// Measures the time spent in each function of the program. Every instruction is timed
+/// individually and attributed to the function that executes it, as well as to all functions on
+/// the call stack for inclusive time.
+#[derive(Default)]
+pub struct FunctionProfiler {
+    /// profile of each function, indexed by chunk
+    profiles: Vec<FunctionProfile>,
+    /// distinct functions on the call stack of the current instruction
+    stack: Vec<usize>,
+    /// function that executes the current instruction
+    current: usize,
+    start: Option<Instant>,
+}
+
+impl FunctionProfiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the profiles of all functions that have been called, the one with the highest
+    /// inclusive time first.
+    pub fn profiles(&self) -> Vec<&FunctionProfile> {
+        let mut profiles: Vec<_> = self.profiles.iter()
+            .filter(|p| p.calls > 0)
+            .collect();
+        profiles.sort_by(|a, b| b.inclusive.cmp(&a.inclusive).then(a.name.cmp(&b.name)));
+        profiles
+    }
+
+    /// Returns the profile of the function with the specified chunk name.
+    pub fn profile(&self, name: &str) -> Option<&FunctionProfile> {
+        self.profiles.iter().find(|p| p.name == name)
+    }
+}
+
+impl Tracer for FunctionProfiler {
+    fn before(&mut self, vm: &VM, _op: &OpCode) {
+        if self.profiles.is_empty() {
+            self.profiles = function_names(vm).into_iter()
+                .map(|name| FunctionProfile { name, ..Default::default() })
+                .collect();
+            // the entry point is not entered by a call instruction
+            self.profiles[vm.function()].calls += 1;
+        }
+        self.stack.clear();
+        for f in vm.call_stack() {
+            if !self.stack.contains(&f) {
+                self.stack.push(f);
+            }
+        }
+        self.current = vm.function();
+        self.start = Some(Instant::now());
+    }
+
+    fn after(&mut self, vm: &VM, op: &OpCode) {
+        let elapsed = self.start.take()
+            .map(|s| s.elapsed())
+            .unwrap_or_default();
+        let current = &mut self.profiles[self.current];
+        current.instructions += 1;
+        current.exclusive += elapsed;
+        for f in self.stack.iter() {
+            self.profiles[*f].inclusive += elapsed;
+        }
+        if let OpCode::Call(_, _) = op {
+            self.profiles[vm.function()].calls += 1;
+        }
+    }
+}
+
+impl Display for FunctionProfiler {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&format!(
+            "{:<24}{:>10}{:>14}{:>14}{:>14}\n", "FUNCTION", "CALLS", "INSTRUCTIONS", "INCLUSIVE", "EXCLUSIVE",
+        ))?;
+        for p in self.profiles() {
+            f.write_str(&format!(
+                "{:<24}{:>10}{:>14}{:>14}{:>14}\n",
+                p.name, p.calls, p.instructions, format!("{:.3?}", p.inclusive), format!("{:.3?}", p.exclusive),
+            ))?;
+        }
+        Ok(())
+    }
+}
+
+
+/// Records the call stack of every executed instruction, weighted by the fuel the instruction
+/// consumes. Since fuel approximates execution time and does not depend on the host, the output
+/// is deterministic. Formatting the tracer produces one line per distinct call stack in the
+/// folded format understood by flamegraph tools.
+#[derive(Default)]
+pub struct FoldedStacks {
+    names: Vec<String>,
+    /// fuel consumed per call stack of chunk indices, outermost function first
+    stacks: HashMap<Vec<usize>, u64>,
+    stack: Vec<usize>,
+}
+
+impl FoldedStacks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns every distinct call stack with its function names joined by `;`, together with
+    /// its weight, sorted by call stack.
+    pub fn stacks(&self) -> Vec<(String, u64)> {
+        let mut stacks: Vec<_> = self.stacks.iter()
+            .map(|(stack, weight)| {
+                let names: Vec<&str> = stack.iter().map(|f| self.names[*f].as_str()).collect();
+                (names.join(";"), *weight)
+            })
+            .collect();
+        stacks.sort();
+        stacks
+    }
+}
+
+impl Tracer for FoldedStacks {
+    fn before(&mut self, vm: &VM, op: &OpCode) {
+        if self.names.is_empty() {
+            self.names = function_names(vm);
+        }
+        self.stack.clear();
+        self.stack.extend(vm.call_stack());
+        match self.stacks.get_mut(&self.stack) {
+            Some(weight) => *weight += op.cost(),
+            None => {
+                self.stacks.insert(self.stack.clone(), op.cost());
+            },
+        }
+    }
+}
+
+impl Display for FoldedStacks {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (stack, weight) in self.stacks() {
+            f.write_str(&format!("{} {}\n", stack, weight))?;
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::bytecode::asm::assemble;
+    use crate::bytecode::program::Program;
+    use crate::lang::function::FerrumFunctionPtr;
+    use crate::vm::trace::{FoldedStacks, FunctionProfiler, OpcodeHistogram};
+    use crate::vm::VM;
+
+    fn vm() -> VM {
+        let main = assemble("
+            .chunk main
+            .const a i32 3
+                CONST a
+                CALL 1 4
+                CALL 1 4
+                RET 4
+        ").unwrap();
+        let square = assemble("
+            .chunk square
+                LOAD 0 4
+                LOAD 0 4
+                MUL I32
+                RET 4
+        ").unwrap();
+        let mut program = Program::new();
+        program.add_function(FerrumFunctionPtr::new(String::from("main"), 0), main);
+        program.add_function(FerrumFunctionPtr::new(String::from("square"), 0), square);
+        VM::new(program).unwrap()
+    }
+
+    #[test]
+    fn trace() {
+        let mut tracer = ((OpcodeHistogram::new(), FunctionProfiler::new()), FoldedStacks::new());
+        let exit = vm().run_traced(&mut tracer).unwrap();
+        assert_eq!(exit.get::<4, i32>(), Some(81));
+        let ((histogram, profiler), folded) = tracer;
+
+        assert_eq!(histogram.total(), 12);
+        assert_eq!(histogram.entries()[0], ("LOAD", 4, 4));
+        assert_eq!(histogram.count("RET"), 3);
+        assert_eq!(histogram.fuel("CALL"), 16);
+
+        let main = profiler.profile("main").unwrap();
+        let square = profiler.profile("square").unwrap();
+        assert_eq!((main.calls(), main.instructions()), (1, 4));
+        assert_eq!((square.calls(), square.instructions()), (2, 8));
+        assert!(main.inclusive() >= square.inclusive());
+        assert!(main.inclusive() >= main.exclusive() + square.exclusive());
+        assert_eq!(profiler.profiles()[0].name(), "main");
+
+        assert_eq!(folded.to_string(), "main 21\nmain;square 16\n");
+    }
+}